@@ -0,0 +1,10 @@
+-- Admin roles: users.role_id says what a user may do through the /admin routes.
+-- 0 is a player (no access), 1 a team captain, 2 a moderator and 3 an admin. Each role may do
+-- everything the roles below it may do.
+UPDATE public.users SET role_id = 0 WHERE role_id IS NULL;
+
+-- Banned users can't log in, join a team or move
+ALTER TABLE public.users ADD COLUMN banned boolean NOT NULL DEFAULT false;
+
+-- Looking up the actions taken through /admin
+CREATE INDEX IF NOT EXISTS audit_log_event ON public.audit_log (event);
@@ -0,0 +1,15 @@
+-- Alt scores: the reasons behind each move's alt_score, kept for moderators.
+-- explanation is a list of {signal, points, detail}, one for each signal that added points.
+CREATE TABLE public.alt_scores (
+    user_id integer NOT NULL REFERENCES public.users(id),
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    score integer NOT NULL,
+    explanation jsonb NOT NULL,
+    scored_at timestamp without time zone NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, turn_id)
+);
+
+ALTER TABLE public.alt_scores OWNER TO risk;
+
+-- Looking up the other accounts seen on a user's IPs
+CREATE INDEX IF NOT EXISTS audit_log_cip ON public.audit_log (cip);
@@ -0,0 +1,29 @@
+-- Personal API tokens: let scripts and bots act for a user by sending
+-- `Authorization: Bearer <token>` instead of the `jwt` cookie.
+-- Only the SHA-256 hash of each token is kept. scopes holds 'read' and/or 'move'.
+CREATE TABLE public.api_tokens (
+    id serial PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES public.users(id),
+    name text NOT NULL,
+    token_hash text NOT NULL UNIQUE,
+    scopes text[] NOT NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now(),
+    last_used_at timestamp without time zone,
+    revoked_at timestamp without time zone
+);
+
+ALTER TABLE public.api_tokens OWNER TO risk;
+
+CREATE INDEX IF NOT EXISTS api_tokens_user_id ON public.api_tokens (user_id);
+
+-- Each request made with a token
+CREATE TABLE public.api_token_uses (
+    id serial PRIMARY KEY,
+    token_id integer NOT NULL REFERENCES public.api_tokens(id),
+    route text NOT NULL,
+    used_at timestamp without time zone NOT NULL DEFAULT now()
+);
+
+ALTER TABLE public.api_token_uses OWNER TO risk;
+
+CREATE INDEX IF NOT EXISTS api_token_uses_token_id ON public.api_token_uses (token_id);
@@ -0,0 +1,15 @@
+-- Cancelling a move: `DELETE /auth/move` withdraws a user's move on the current turn.
+-- Making a move rewrites the user's counters, so each user's counters are kept as they were
+-- before their first move on a turn, to be put back if they cancel it.
+CREATE TABLE public.move_snapshots (
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    user_id integer NOT NULL REFERENCES public.users(id),
+    overall integer,
+    turns integer,
+    game_turns integer,
+    mvps integer,
+    streak integer,
+    PRIMARY KEY (turn_id, user_id)
+);
+
+ALTER TABLE public.move_snapshots OWNER TO risk;
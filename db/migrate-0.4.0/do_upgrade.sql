@@ -0,0 +1,21 @@
+-- Upgrades a 0.3.0 database to 0.4.0, running the migrations in this directory in order:
+--     psql risk -f db/migrate-0.4.0/do_upgrade.sql
+-- Every migration runs in one transaction, so a failure leaves the database as it was.
+\set ON_ERROR_STOP on
+BEGIN;
+-- Columns on turninfo
+\ir roll_state.sql
+\ir seed_commitment.sql
+\ir playoffs.sql
+\ir games.sql
+-- Season rules and their end
+\ir season_rules.sql
+\ir season_winners.sql
+\ir respawn.sql
+-- Users, moves and rolls
+\ir admin.sql
+\ir alt_scores.sql
+\ir api_tokens.sql
+\ir cancel_move.sql
+\ir undo.sql
+COMMIT;
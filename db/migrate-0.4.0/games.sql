@@ -0,0 +1,29 @@
+-- Games: one server and database can host several games at once, each with its own turns.
+-- Seasons are numbered across all games, so a season (and its rules, winners and rolls) belongs
+-- to exactly one game. Every existing turn becomes part of the `default` game.
+CREATE TABLE public.games (
+    id serial PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    description text
+);
+
+ALTER TABLE public.games OWNER TO risk;
+
+INSERT INTO public.games (name, description) VALUES ('default', 'The main game');
+
+ALTER TABLE public.turninfo ADD COLUMN game integer NOT NULL DEFAULT 1 REFERENCES public.games(id);
+
+-- Each season belongs to one game, so a season and day name a single turn. The ringmaster claims
+-- a season for its game when it creates the season's first turn, and refuses to create turns in
+-- a season another game has claimed.
+CREATE TABLE public.seasons (
+    season integer PRIMARY KEY,
+    game integer NOT NULL REFERENCES public.games(id),
+    UNIQUE (season, game)
+);
+
+ALTER TABLE public.seasons OWNER TO risk;
+
+INSERT INTO public.seasons (season, game) SELECT DISTINCT season, game FROM public.turninfo;
+
+ALTER TABLE public.turninfo ADD FOREIGN KEY (season, game) REFERENCES public.seasons (season, game);
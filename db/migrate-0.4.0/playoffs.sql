@@ -0,0 +1,13 @@
+-- Playoffs: a turn flagged with playoffs draws up the next round of brackets instead of rolling.
+-- The first round's brackets come from `risk.playoffs.brackets` in Rocket.toml.
+alter table turninfo add column playoffs boolean default false;
+
+CREATE TABLE public.playoff_brackets (
+    id serial PRIMARY KEY,
+    season integer NOT NULL,
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    bracket integer NOT NULL,
+    team integer NOT NULL REFERENCES public.teams(id)
+);
+
+ALTER TABLE public.playoff_brackets OWNER TO risk;
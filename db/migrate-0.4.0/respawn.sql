@@ -0,0 +1,3 @@
+-- Respawns: an eliminated team with enough committed players is put back on the map at roll time.
+-- A season's respawn_players (see season_rules.sql) is the number of committed players needed.
+ALTER TABLE public.stats ADD COLUMN respawned boolean NOT NULL DEFAULT false;
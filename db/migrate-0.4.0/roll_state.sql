@@ -0,0 +1,6 @@
+-- Roll state: where each turn is in its roll, so that a second ringmaster refuses to roll a turn
+-- that is already being rolled or has been rolled.
+-- pending -> locked (moves closed) -> rolling -> complete, or -> failed (rolled back, reopened).
+alter table turninfo add column roll_state text not null default 'pending'
+    check (roll_state in ('pending', 'locked', 'rolling', 'complete', 'failed'));
+update turninfo set roll_state = 'complete' where complete = true;
@@ -0,0 +1,32 @@
+-- Season rules: the numbers each season is played by, read by both the server and the ringmaster.
+-- A season without a row plays by the defaults below, which are the rules from before this table.
+-- Don't change a season's row once it has started: replays use the rules as they are now.
+-- final_day is the day after whose roll the team with the most territories wins; NULL means the
+-- season only ends by conquest or elimination. Replaces `risk.final_day`.
+-- respawn_players is the number of committed players an eliminated team needs to respawn; NULL
+-- turns respawns off.
+-- strategy decides who wins a territory more than one team moved on. strategy_cap is the
+-- highest chance any team may have under capped_underdog, and is ignored by the other
+-- strategies. Replaces `risk.strategies`.
+CREATE TABLE public.season_rules (
+    season integer PRIMARY KEY,
+    alt_cutoff integer NOT NULL DEFAULT 75,
+    alt_score integer NOT NULL DEFAULT 175,
+    aon_start integer NOT NULL DEFAULT 4,
+    aon_end integer NOT NULL DEFAULT 48,
+    home_multiplier double precision NOT NULL DEFAULT 1.5,
+    region_bonus double precision NOT NULL DEFAULT 0.5,
+    total_turns integer[] NOT NULL DEFAULT '{0,10,25,50,100}',
+    game_turns integer[] NOT NULL DEFAULT '{0,5,10,25,40}',
+    mvps integer[] NOT NULL DEFAULT '{0,1,5,10,25}',
+    streak integer[] NOT NULL DEFAULT '{0,3,5,10,25}',
+    final_day integer,
+    respawn_players integer,
+    strategy text NOT NULL DEFAULT 'lottery'
+        CHECK (strategy IN ('lottery', 'highest_power', 'power_squared', 'capped_underdog')),
+    strategy_cap double precision CHECK (strategy_cap > 0 AND strategy_cap < 1),
+    CHECK (array_length(total_turns, 1) = 5 AND array_length(game_turns, 1) = 5
+        AND array_length(mvps, 1) = 5 AND array_length(streak, 1) = 5)
+);
+
+ALTER TABLE public.season_rules OWNER TO risk;
@@ -0,0 +1,15 @@
+-- Season end: the roll that ends a season is flagged as the finale, and its winners recorded here.
+-- A season ends once one team owns the map, once every other team is eliminated, or after
+-- the season's `final_day` in season_rules (see season_rules.sql). Teams tied on territories at
+-- the end all win.
+CREATE TABLE public.season_winners (
+    id serial PRIMARY KEY,
+    season integer NOT NULL,
+    day integer NOT NULL,
+    team integer NOT NULL REFERENCES public.teams(id),
+    territories integer NOT NULL,
+    reason text NOT NULL,
+    UNIQUE (season, team)
+);
+
+ALTER TABLE public.season_winners OWNER TO risk;
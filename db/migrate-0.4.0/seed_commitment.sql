@@ -0,0 +1,22 @@
+-- Provably fair rolls: each turn commits to the SHA-256 of its roll seed when it is created,
+-- and the seed is revealed (through the API) once the turn is complete.
+alter table turninfo add column seed_commitment text;
+alter table turninfo add column seed text;
+
+drop view rollinfo;
+
+CREATE VIEW public.rollinfo AS
+ SELECT (turninfo.rollstarttime)::text AS rollstarttime,
+    (turninfo.rollendtime)::text AS rollendtime,
+    turninfo.chaosrerolls,
+    turninfo.chaosweight,
+    turninfo.seed_commitment,
+    CASE WHEN turninfo.complete = true THEN turninfo.seed ELSE NULL END AS seed,
+    (territory_ownership_without_neighbors.day - 1) AS day,
+    territory_ownership_without_neighbors.season,
+    json_agg(json_build_object('territory', territory_ownership_without_neighbors.name, 'timestamp', territory_ownership_without_neighbors."timestamp", 'winner', territory_ownership_without_neighbors.owner, 'randomNumber', territory_ownership_without_neighbors.random_number)) AS json_agg
+   FROM (public.territory_ownership_without_neighbors
+     JOIN public.turninfo ON (((turninfo.day = (territory_ownership_without_neighbors.day - 1)) AND (turninfo.season = territory_ownership_without_neighbors.season))))
+  GROUP BY territory_ownership_without_neighbors.day, territory_ownership_without_neighbors.season, turninfo.chaosrerolls, turninfo.rollstarttime, turninfo.rollendtime, turninfo.chaosweight, turninfo.seed_commitment, turninfo.seed, turninfo.complete;
+
+ALTER TABLE public.rollinfo OWNER TO risk;
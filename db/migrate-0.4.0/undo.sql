@@ -0,0 +1,30 @@
+-- Undoing a roll: `rrringmaster undo` puts the last rolled turn of a game back the way it was
+-- before its roll and reopens it for moves.
+-- Each roll keeps the counters of the users it changed, as they were before the roll, so that
+-- they can be put back. Rolls made before this migration have none, and undoing them leaves the
+-- counters alone.
+CREATE TABLE public.roll_user_snapshots (
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    user_id integer NOT NULL REFERENCES public.users(id),
+    turns integer,
+    game_turns integer,
+    mvps integer,
+    streak integer,
+    overall integer,
+    playing_for integer NOT NULL,
+    PRIMARY KEY (turn_id, user_id)
+);
+
+ALTER TABLE public.roll_user_snapshots OWNER TO risk;
+
+-- Who undid which roll, and when
+CREATE TABLE public.roll_undos (
+    id serial PRIMARY KEY,
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    season integer NOT NULL,
+    day integer NOT NULL,
+    undone_by text NOT NULL,
+    undone_at timestamp without time zone NOT NULL DEFAULT now()
+);
+
+ALTER TABLE public.roll_undos OWNER TO risk;
@@ -1297,6 +1297,161 @@ ALTER TABLE ONLY public.users
     ADD CONSTRAINT users_pkey PRIMARY KEY (id);
 
 
+--
+-- Added in 0.4.0: the tables and columns created by db/migrate-0.4.0 (see do_upgrade.sql there)
+--
+
+ALTER TABLE public.turninfo ADD COLUMN roll_state text NOT NULL DEFAULT 'pending'
+    CHECK (roll_state IN ('pending', 'locked', 'rolling', 'complete', 'failed'));
+ALTER TABLE public.turninfo ADD COLUMN seed_commitment text;
+ALTER TABLE public.turninfo ADD COLUMN seed text;
+ALTER TABLE public.turninfo ADD COLUMN playoffs boolean DEFAULT false;
+
+CREATE TABLE public.games (
+    id serial PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    description text
+);
+
+ALTER TABLE public.games OWNER TO risk;
+
+INSERT INTO public.games (name, description) VALUES ('default', 'The main game');
+
+ALTER TABLE public.turninfo ADD COLUMN game integer NOT NULL DEFAULT 1 REFERENCES public.games(id);
+
+CREATE TABLE public.seasons (
+    season integer PRIMARY KEY,
+    game integer NOT NULL REFERENCES public.games(id),
+    UNIQUE (season, game)
+);
+
+ALTER TABLE public.seasons OWNER TO risk;
+
+ALTER TABLE public.turninfo ADD FOREIGN KEY (season, game) REFERENCES public.seasons (season, game);
+
+ALTER TABLE public.stats ADD COLUMN respawned boolean NOT NULL DEFAULT false;
+ALTER TABLE public.users ADD COLUMN banned boolean NOT NULL DEFAULT false;
+
+CREATE TABLE public.playoff_brackets (
+    id serial PRIMARY KEY,
+    season integer NOT NULL,
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    bracket integer NOT NULL,
+    team integer NOT NULL REFERENCES public.teams(id)
+);
+
+ALTER TABLE public.playoff_brackets OWNER TO risk;
+
+CREATE TABLE public.season_rules (
+    season integer PRIMARY KEY,
+    alt_cutoff integer NOT NULL DEFAULT 75,
+    alt_score integer NOT NULL DEFAULT 175,
+    aon_start integer NOT NULL DEFAULT 4,
+    aon_end integer NOT NULL DEFAULT 48,
+    home_multiplier double precision NOT NULL DEFAULT 1.5,
+    region_bonus double precision NOT NULL DEFAULT 0.5,
+    total_turns integer[] NOT NULL DEFAULT '{0,10,25,50,100}',
+    game_turns integer[] NOT NULL DEFAULT '{0,5,10,25,40}',
+    mvps integer[] NOT NULL DEFAULT '{0,1,5,10,25}',
+    streak integer[] NOT NULL DEFAULT '{0,3,5,10,25}',
+    final_day integer,
+    respawn_players integer,
+    strategy text NOT NULL DEFAULT 'lottery'
+        CHECK (strategy IN ('lottery', 'highest_power', 'power_squared', 'capped_underdog')),
+    strategy_cap double precision CHECK (strategy_cap > 0 AND strategy_cap < 1),
+    CHECK (array_length(total_turns, 1) = 5 AND array_length(game_turns, 1) = 5
+        AND array_length(mvps, 1) = 5 AND array_length(streak, 1) = 5)
+);
+
+ALTER TABLE public.season_rules OWNER TO risk;
+
+CREATE TABLE public.season_winners (
+    id serial PRIMARY KEY,
+    season integer NOT NULL,
+    day integer NOT NULL,
+    team integer NOT NULL REFERENCES public.teams(id),
+    territories integer NOT NULL,
+    reason text NOT NULL,
+    UNIQUE (season, team)
+);
+
+ALTER TABLE public.season_winners OWNER TO risk;
+
+CREATE TABLE public.alt_scores (
+    user_id integer NOT NULL REFERENCES public.users(id),
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    score integer NOT NULL,
+    explanation jsonb NOT NULL,
+    scored_at timestamp without time zone NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, turn_id)
+);
+
+ALTER TABLE public.alt_scores OWNER TO risk;
+
+CREATE TABLE public.api_tokens (
+    id serial PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES public.users(id),
+    name text NOT NULL,
+    token_hash text NOT NULL UNIQUE,
+    scopes text[] NOT NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now(),
+    last_used_at timestamp without time zone,
+    revoked_at timestamp without time zone
+);
+
+ALTER TABLE public.api_tokens OWNER TO risk;
+
+CREATE INDEX IF NOT EXISTS api_tokens_user_id ON public.api_tokens (user_id);
+
+CREATE TABLE public.api_token_uses (
+    id serial PRIMARY KEY,
+    token_id integer NOT NULL REFERENCES public.api_tokens(id),
+    route text NOT NULL,
+    used_at timestamp without time zone NOT NULL DEFAULT now()
+);
+
+ALTER TABLE public.api_token_uses OWNER TO risk;
+
+CREATE INDEX IF NOT EXISTS api_token_uses_token_id ON public.api_token_uses (token_id);
+
+CREATE TABLE public.move_snapshots (
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    user_id integer NOT NULL REFERENCES public.users(id),
+    overall integer,
+    turns integer,
+    game_turns integer,
+    mvps integer,
+    streak integer,
+    PRIMARY KEY (turn_id, user_id)
+);
+
+ALTER TABLE public.move_snapshots OWNER TO risk;
+
+CREATE TABLE public.roll_user_snapshots (
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    user_id integer NOT NULL REFERENCES public.users(id),
+    turns integer,
+    game_turns integer,
+    mvps integer,
+    streak integer,
+    overall integer,
+    playing_for integer NOT NULL,
+    PRIMARY KEY (turn_id, user_id)
+);
+
+ALTER TABLE public.roll_user_snapshots OWNER TO risk;
+
+CREATE TABLE public.roll_undos (
+    id serial PRIMARY KEY,
+    turn_id integer NOT NULL REFERENCES public.turninfo(id),
+    season integer NOT NULL,
+    day integer NOT NULL,
+    undone_by text NOT NULL,
+    undone_at timestamp without time zone NOT NULL DEFAULT now()
+);
+
+ALTER TABLE public.roll_undos OWNER TO risk;
+
 --
 -- Name: SCHEMA public; Type: ACL; Schema: -; Owner: postgres
 --
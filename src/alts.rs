@@ -0,0 +1,441 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Heuristic alt scoring: each move's `alt_score` is worked out from signals we already
+//! collect about its player, each worth a configurable number of points.
+//!
+//! The signals are the flag on the user (`users.is_alt`), other accounts that have logged in
+//! from the same IP (`audit_log.cip`), the age and karma of the Reddit account (from the
+//! `/api/v1/me` payload kept in `audit_log.data`), how recently the user joined, and other
+//! accounts that made exactly the same recent moves. The points for each signal are stored in
+//! `alt_scores`, so moderators can see why a move was scored the way it was.
+
+use crate::rules::Rules;
+use crate::schema::{alt_scores, audit_log, users};
+use chrono::{Duration, NaiveDateTime};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Integer;
+use rocket::figment::Figment;
+use serde_json::Value;
+
+/// The `audit_log` event recorded when a user logs in.
+const LOGIN_EVENT: i32 = 1;
+
+/// The points each signal adds to a move's alt score, from `risk.alt_scoring`. A weight of 0
+/// turns its signal off.
+///
+/// Only a moderator's flag should keep a move out of the roll by itself, so the defaults add
+/// up to less than the default `alt_cutoff` however many signals turn up.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct AltWeights {
+    /// For each other account that has logged in from one of the user's IPs in the last
+    /// `shared_ip_days` days, up to `shared_ip_max`
+    pub(crate) shared_ip: i32,
+    pub(crate) shared_ip_max: i32,
+    pub(crate) shared_ip_days: i32,
+    /// For a Reddit account younger than `young_account_days`
+    pub(crate) young_account: i32,
+    pub(crate) young_account_days: i64,
+    /// For a Reddit account with less karma than `low_karma_threshold`
+    pub(crate) low_karma: i32,
+    pub(crate) low_karma_threshold: i64,
+    /// For a user who joined less than `recent_join_days` ago
+    pub(crate) recent_join: i32,
+    pub(crate) recent_join_days: i64,
+    /// For each other account that made the same move as the user on each of the user's last
+    /// `identical_moves_turns` turns, up to `identical_moves_max`
+    pub(crate) identical_moves: i32,
+    pub(crate) identical_moves_max: i32,
+    pub(crate) identical_moves_turns: i32,
+}
+
+impl Default for AltWeights {
+    fn default() -> Self {
+        AltWeights {
+            shared_ip: 10,
+            shared_ip_max: 20,
+            shared_ip_days: 30,
+            young_account: 15,
+            young_account_days: 30,
+            low_karma: 10,
+            low_karma_threshold: 10,
+            recent_join: 5,
+            recent_join_days: 3,
+            identical_moves: 10,
+            identical_moves_max: 20,
+            identical_moves_turns: 5,
+        }
+    }
+}
+
+impl AltWeights {
+    /// Reads the weights from `risk.alt_scoring`, using the defaults for any left out.
+    pub(crate) fn load(settings: &Figment) -> Result<AltWeights, String> {
+        match settings.extract_inner::<AltWeights>("risk.alt_scoring") {
+            Err(e) if e.missing() => Ok(AltWeights::default()),
+            other => other.map_err(|e| format!("Invalid alt scoring weights: {e}")),
+        }
+    }
+}
+
+/// What we know about a user that might give them away as an alt.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct AltSignals {
+    /// Whether a moderator has flagged the user as an alt
+    pub(crate) flagged: bool,
+    /// Other users who have logged in from one of the user's IPs, both recently
+    pub(crate) shared_ip_users: Vec<i32>,
+    /// When the user's Reddit account was created
+    pub(crate) account_created: Option<NaiveDateTime>,
+    pub(crate) karma: Option<i64>,
+    pub(crate) join_date: Option<NaiveDateTime>,
+    /// Other users whose recent moves were the same as the user's
+    pub(crate) identical_move_users: Vec<i32>,
+}
+
+/// One signal's contribution to an alt score.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AltReason {
+    pub(crate) signal: String,
+    pub(crate) points: i32,
+    pub(crate) detail: String,
+}
+
+/// A move's alt score, and the reasons for it.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AltScore {
+    pub(crate) score: i32,
+    pub(crate) reasons: Vec<AltReason>,
+}
+
+#[derive(QueryableByName)]
+struct OtherUser {
+    #[sql_type = "Integer"]
+    user_id: i32,
+}
+
+impl AltSignals {
+    /// Gathers the signals for `user_id`.
+    pub(crate) fn load(
+        user_id: i32,
+        weights: &AltWeights,
+        conn: &PgConnection,
+    ) -> QueryResult<AltSignals> {
+        let (flagged, join_date) = users::table
+            .filter(users::id.eq(user_id))
+            .select((users::is_alt, users::join_date))
+            .first::<(bool, Option<NaiveDateTime>)>(conn)?;
+        // Campuses and mobile networks put many players behind one IP over time, so only
+        // recent logins count
+        let shared_ip_users = sql_query(
+            "SELECT DISTINCT other.user_id FROM audit_log mine \
+            INNER JOIN audit_log other ON other.cip = mine.cip AND other.user_id <> mine.user_id \
+            WHERE mine.user_id = $1 AND mine.cip <> '' \
+            AND mine.timestamp > now() - $2 * interval '1 day' \
+            AND other.timestamp > now() - $2 * interval '1 day' ORDER BY other.user_id",
+        )
+        .bind::<Integer, _>(user_id)
+        .bind::<Integer, _>(weights.shared_ip_days)
+        .load::<OtherUser>(conn)?;
+        let identical_move_users = sql_query(
+            "SELECT other.user_id FROM \
+            (SELECT turn_id, territory FROM past_turns WHERE user_id = $1 \
+            ORDER BY turn_id DESC LIMIT $2) mine \
+            INNER JOIN past_turns other ON other.turn_id = mine.turn_id \
+            AND other.territory = mine.territory AND other.user_id <> $1 \
+            GROUP BY other.user_id HAVING count(*) = $2 ORDER BY other.user_id",
+        )
+        .bind::<Integer, _>(user_id)
+        .bind::<Integer, _>(weights.identical_moves_turns)
+        .load::<OtherUser>(conn)?;
+        let login = audit_log::table
+            .filter(audit_log::user_id.eq(user_id))
+            .filter(audit_log::event.eq(LOGIN_EVENT))
+            .filter(audit_log::data.is_not_null())
+            .select(audit_log::data)
+            .order(audit_log::timestamp.desc())
+            .first::<Option<Value>>(conn)
+            .optional()?
+            .flatten();
+        let (account_created, karma) = login.as_ref().map(reddit_account).unwrap_or_default();
+        Ok(AltSignals {
+            flagged,
+            shared_ip_users: shared_ip_users.into_iter().map(|u| u.user_id).collect(),
+            account_created,
+            karma,
+            join_date,
+            identical_move_users: identical_move_users
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect(),
+        })
+    }
+
+    /// Scores the signals as of `now`, with flagged users scoring the season's `alt_score`.
+    pub(crate) fn score(
+        &self,
+        weights: &AltWeights,
+        rules: &Rules,
+        now: NaiveDateTime,
+    ) -> AltScore {
+        let mut reasons = Vec::new();
+        let mut reason = |signal: &str, points: i32, detail: String| {
+            if points > 0 {
+                reasons.push(AltReason {
+                    signal: String::from(signal),
+                    points,
+                    detail,
+                });
+            }
+        };
+        if self.flagged {
+            reason(
+                "flagged",
+                rules.alt_score,
+                String::from("Flagged as an alt by a moderator"),
+            );
+        }
+        if !self.shared_ip_users.is_empty() {
+            reason(
+                "shared_ip",
+                per_user(
+                    weights.shared_ip,
+                    &self.shared_ip_users,
+                    weights.shared_ip_max,
+                ),
+                format!("Shares an IP with users {:?}", self.shared_ip_users),
+            );
+        }
+        if let Some(created) = self.account_created {
+            let age = now - created;
+            if age < Duration::days(weights.young_account_days) {
+                reason(
+                    "young_account",
+                    weights.young_account,
+                    format!("Reddit account is {} days old", age.num_days()),
+                );
+            }
+        }
+        if let Some(karma) = self.karma {
+            if karma < weights.low_karma_threshold {
+                reason(
+                    "low_karma",
+                    weights.low_karma,
+                    format!("Reddit account has {karma} karma"),
+                );
+            }
+        }
+        if let Some(joined) = self.join_date {
+            let since = now - joined;
+            if since < Duration::days(weights.recent_join_days) {
+                reason(
+                    "recent_join",
+                    weights.recent_join,
+                    format!("Joined {} hours ago", since.num_hours()),
+                );
+            }
+        }
+        if !self.identical_move_users.is_empty() {
+            reason(
+                "identical_moves",
+                per_user(
+                    weights.identical_moves,
+                    &self.identical_move_users,
+                    weights.identical_moves_max,
+                ),
+                format!(
+                    "Made the same last {} moves as users {:?}",
+                    weights.identical_moves_turns, self.identical_move_users
+                ),
+            );
+        }
+        AltScore {
+            score: reasons.iter().map(|reason| reason.points).sum(),
+            reasons,
+        }
+    }
+}
+
+impl AltScore {
+    /// Scores `user_id`'s move on turn `turn_id` and stores the reasons for moderators.
+    pub(crate) fn assess(
+        user_id: i32,
+        turn_id: i32,
+        weights: &AltWeights,
+        rules: &Rules,
+        conn: &PgConnection,
+    ) -> QueryResult<AltScore> {
+        let now = chrono::Utc::now().naive_utc();
+        let score = AltSignals::load(user_id, weights, conn)?.score(weights, rules, now);
+        let explanation = serde_json::to_value(&score.reasons).unwrap_or_default();
+        diesel::insert_into(alt_scores::table)
+            .values((
+                alt_scores::user_id.eq(user_id),
+                alt_scores::turn_id.eq(turn_id),
+                alt_scores::score.eq(score.score),
+                alt_scores::explanation.eq(&explanation),
+                alt_scores::scored_at.eq(now),
+            ))
+            .on_conflict((alt_scores::user_id, alt_scores::turn_id))
+            .do_update()
+            .set((
+                alt_scores::score.eq(score.score),
+                alt_scores::explanation.eq(&explanation),
+                alt_scores::scored_at.eq(now),
+            ))
+            .execute(conn)?;
+        Ok(score)
+    }
+}
+
+/// The `points` for each of `users`, up to `max`.
+fn per_user(points: i32, users: &[i32], max: i32) -> i32 {
+    points
+        .saturating_mul(i32::try_from(users.len()).unwrap_or(i32::MAX))
+        .min(max)
+}
+
+/// Reads when the account was created and its karma from a Reddit `/api/v1/me` payload.
+fn reddit_account(payload: &Value) -> (Option<NaiveDateTime>, Option<i64>) {
+    let created = payload
+        .get("created_utc")
+        .and_then(Value::as_f64)
+        .and_then(|created| NaiveDateTime::from_timestamp_opt(created as i64, 0));
+    let karma = payload
+        .get("total_karma")
+        .and_then(Value::as_i64)
+        .or_else(|| {
+            let link = payload.get("link_karma").and_then(Value::as_i64)?;
+            let comment = payload.get("comment_karma").and_then(Value::as_i64)?;
+            Some(link + comment)
+        });
+    (created, karma)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn day(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 3, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reddit_account() {
+        let payload = serde_json::json!({
+            "name": "someone",
+            "created_utc": 1_677_672_000.0,
+            "link_karma": 3,
+            "comment_karma": 4,
+        });
+        assert_eq!(reddit_account(&payload), (Some(day(1)), Some(7)));
+        let payload = serde_json::json!({ "total_karma": 12, "link_karma": 3 });
+        assert_eq!(reddit_account(&payload), (None, Some(12)));
+    }
+
+    #[test]
+    fn test_score_nothing_suspicious() {
+        let signals = AltSignals {
+            account_created: Some(day(1) - Duration::days(400)),
+            karma: Some(5000),
+            join_date: Some(day(1)),
+            ..AltSignals::default()
+        };
+        let score = signals.score(&AltWeights::default(), &Rules::default(), day(20));
+        assert_eq!(score.score, 0);
+        assert!(score.reasons.is_empty());
+    }
+
+    #[test]
+    fn test_score_signals() {
+        let signals = AltSignals {
+            flagged: false,
+            shared_ip_users: vec![4, 9],
+            account_created: Some(day(10)),
+            karma: Some(2),
+            join_date: Some(day(19)),
+            identical_move_users: vec![4],
+        };
+        let weights = AltWeights::default();
+        let score = signals.score(&weights, &Rules::default(), day(20));
+        assert_eq!(
+            score
+                .reasons
+                .iter()
+                .map(|reason| (reason.signal.as_str(), reason.points))
+                .collect::<Vec<(&str, i32)>>(),
+            vec![
+                ("shared_ip", 20),
+                ("young_account", 15),
+                ("low_karma", 10),
+                ("recent_join", 5),
+                ("identical_moves", 10),
+            ]
+        );
+        assert_eq!(score.score, 60);
+        // Turning a signal off leaves it out of the reasons
+        let off = AltWeights {
+            shared_ip: 0,
+            ..weights.clone()
+        };
+        assert_eq!(signals.score(&off, &Rules::default(), day(20)).score, 40);
+        // However many accounts share an IP, the signal adds no more than its cap
+        let crowded = AltSignals {
+            shared_ip_users: (1..=50).collect(),
+            ..AltSignals::default()
+        };
+        assert_eq!(
+            crowded.score(&weights, &Rules::default(), day(20)).score,
+            weights.shared_ip_max
+        );
+    }
+
+    #[test]
+    fn test_default_weights_below_cutoff() {
+        // Every signal but the moderator's flag, as strongly as it can turn up
+        let signals = AltSignals {
+            flagged: false,
+            shared_ip_users: (1..=1000).collect(),
+            account_created: Some(day(20)),
+            karma: Some(-100),
+            join_date: Some(day(20)),
+            identical_move_users: (1..=1000).collect(),
+        };
+        let rules = Rules::default();
+        let score = signals.score(&AltWeights::default(), &rules, day(20));
+        assert_eq!(score.reasons.len(), 5);
+        assert!(score.score < rules.alt_cutoff);
+    }
+
+    #[test]
+    fn test_score_flagged() {
+        let signals = AltSignals {
+            flagged: true,
+            ..AltSignals::default()
+        };
+        let rules = Rules::default();
+        let score = signals.score(&AltWeights::default(), &rules, day(20));
+        assert_eq!(score.score, rules.alt_score);
+        assert_eq!(score.reasons[0].signal, "flagged");
+    }
+
+    #[test]
+    fn test_weights() {
+        use rocket::figment::providers::{Format, Toml};
+        let settings = Figment::from(Toml::string(
+            "[risk.alt_scoring]\nshared_ip = 60\nrecent_join = 0\n",
+        ));
+        let weights = AltWeights::load(&settings).unwrap();
+        assert_eq!((weights.shared_ip, weights.recent_join), (60, 0));
+        assert_eq!(weights.low_karma, AltWeights::default().low_karma);
+        assert_eq!(AltWeights::load(&Figment::new()), Ok(AltWeights::default()));
+    }
+}
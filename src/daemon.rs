@@ -0,0 +1,222 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Keeps the ringmaster running between rolls: it sleeps until the active turn's
+//! `rollstarttime`, rolls it, then waits for the turn that roll created. Each game is
+//! rolled on its own schedule.
+//!
+//! Only one daemon may roll a game at a time. A daemon takes a Postgres advisory lock on each
+//! game it rolls for as long as it runs. A daemon started for a game another daemon holds
+//! refuses to start, and a daemon rolling every game leaves such games to the other daemon.
+
+use crate::game::Game;
+use crate::lock::AdvisoryLock;
+use crate::structs::TurnInfo;
+use crate::RollOptions;
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::time::{Duration, Instant};
+
+/// How often to check for a new or rescheduled turn when `risk.daemon.poll` is not set.
+const DEFAULT_POLL: u64 = 60;
+
+/// The longest the daemon waits before rolling a game whose rolls keep failing again.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60 * 60);
+
+/// A game whose last rolls failed: how many in a row, and when to try again.
+struct Retry {
+    failures: u32,
+    at: Instant,
+}
+
+/// How long to wait after the `failures`th failed roll in a row: a poll after the first, twice
+/// as long after each one since, and at most `MAX_RETRY_WAIT` (or a poll, if that is longer).
+fn retry_wait(failures: u32, poll: Duration) -> Duration {
+    poll.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_RETRY_WAIT.max(poll))
+}
+
+/// How long to sleep before looking at the turn again, or `None` if it is time to roll.
+/// Sleeps are capped at `poll` so that a turn rescheduled in the meantime is noticed.
+fn time_to_roll(
+    rollstarttime: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+    poll: Duration,
+) -> Option<Duration> {
+    match rollstarttime {
+        // Turns without a roll time (such as after a season's finale) are never rolled
+        None => Some(poll),
+        Some(start) if start <= now => None,
+        Some(start) => Some((start - now).to_std().map_or(poll, |wait| wait.min(poll))),
+    }
+}
+
+/// Carries on after the database `error` if `conn` still reaches the database. A lost
+/// connection takes the daemon's advisory locks with it, and another daemon may since have
+/// taken its games, so the daemon stops and leaves restarting it to its supervisor.
+fn still_connected(
+    conn: &PgConnection,
+    error: diesel::result::Error,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match sql_query("SELECT 1").execute(conn) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Lost the database connection: {error}").into()),
+    }
+}
+
+/// Rolls each turn as its `rollstarttime` comes around, until the process is stopped. Rolls the
+/// game named by `--game`, or else every game, including games created while it runs.
+///
+/// A failed roll has already been rolled back and its turn reopened by the time we see the
+/// error, so the daemon logs it and tries again later, waiting longer after each failure in a
+/// row (see [`retry_wait`]). Database errors are logged, and the daemon carries on at the next
+/// poll, unless it has lost its connection (see [`still_connected`]).
+pub(crate) fn run(options: &RollOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let poll = Duration::from_secs(
+        rocket::build()
+            .figment()
+            .extract_inner::<u64>("risk.daemon.poll")
+            .unwrap_or(DEFAULT_POLL),
+    );
+    let conn: PgConnection = crate::establish_connection();
+    let mut locks: BTreeMap<i32, AdvisoryLock> = BTreeMap::new();
+    let mut retries: BTreeMap<i32, Retry> = BTreeMap::new();
+    println!("Ringmaster daemon started; checking for turns every {poll:?}");
+    loop {
+        let games = match &options.game {
+            Some(name) => Game::load(name, &conn).map(|game| vec![game]),
+            None => Game::load_all(&conn),
+        };
+        let games = match games {
+            Ok(games) => games,
+            Err(diesel::result::Error::NotFound) if options.game.is_some() => {
+                return Err(diesel::result::Error::NotFound.into())
+            }
+            Err(e) => {
+                eprintln!("Could not load the games to roll: {e}");
+                still_connected(&conn, e)?;
+                std::thread::sleep(poll);
+                continue;
+            }
+        };
+        let mut wait = poll;
+        for game in games {
+            if let Entry::Vacant(entry) = locks.entry(game.id) {
+                let lock = match AdvisoryLock::daemon(game.id, &conn) {
+                    Ok(lock) => lock,
+                    Err(e) => {
+                        eprintln!("Game {}: could not take the daemon lock: {e}", game.name);
+                        still_connected(&conn, e)?;
+                        continue;
+                    }
+                };
+                match lock {
+                    Some(lock) => {
+                        println!("Rolling game {}", game.name);
+                        entry.insert(lock);
+                    }
+                    None if options.game.is_some() => {
+                        return Err(format!(
+                            "Another ringmaster daemon is already rolling game {}",
+                            game.name
+                        )
+                        .into())
+                    }
+                    // Another daemon rolls this game
+                    None => continue,
+                }
+            }
+            if let Some(retry) = retries.get(&game.id) {
+                let now = Instant::now();
+                if retry.at > now {
+                    wait = wait.min(retry.at - now);
+                    continue;
+                }
+            }
+            let rollstarttime = match TurnInfo::get_rollable(game.id, &conn) {
+                Ok(turninfoblock) => turninfoblock.rollstarttime,
+                Err(diesel::result::Error::NotFound) => None,
+                Err(e) => {
+                    eprintln!("Game {}: could not load the turn to roll: {e}", game.name);
+                    still_connected(&conn, e)?;
+                    continue;
+                }
+            };
+            if let Some(next) = time_to_roll(rollstarttime, Utc::now().naive_utc(), poll) {
+                wait = wait.min(next);
+                continue;
+            }
+            match crate::runtime(&game.name, options) {
+                Ok(()) => {
+                    retries.remove(&game.id);
+                    println!(
+                        "Game {}: roll complete; waiting for the next turn",
+                        game.name
+                    );
+                }
+                Err(e) => {
+                    let failures = retries.get(&game.id).map_or(0, |retry| retry.failures) + 1;
+                    let retry_in = retry_wait(failures, poll);
+                    eprintln!(
+                        "Game {}: roll failed ({failures} in a row); trying again in {retry_in:?}: {e:?}",
+                        game.name
+                    );
+                    retries.insert(
+                        game.id,
+                        Retry {
+                            failures,
+                            at: Instant::now() + retry_in,
+                        },
+                    );
+                    wait = wait.min(retry_in);
+                }
+            }
+        }
+        std::thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_time_to_roll() {
+        let now = NaiveDate::from_ymd_opt(2022, 9, 1)
+            .unwrap()
+            .and_hms_opt(3, 59, 30)
+            .unwrap();
+        let poll = Duration::from_secs(60);
+        let at = |h, m, s| {
+            NaiveDate::from_ymd_opt(2022, 9, 1)
+                .unwrap()
+                .and_hms_opt(h, m, s)
+        };
+        assert_eq!(Some(poll), time_to_roll(None, now, poll));
+        assert_eq!(None, time_to_roll(at(3, 59, 30), now, poll));
+        assert_eq!(None, time_to_roll(at(3, 0, 0), now, poll));
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            time_to_roll(at(4, 0, 0), now, poll)
+        );
+        assert_eq!(Some(poll), time_to_roll(at(5, 0, 0), now, poll));
+    }
+
+    #[test]
+    fn test_retry_wait() {
+        let poll = Duration::from_secs(60);
+        assert_eq!(poll, retry_wait(1, poll));
+        assert_eq!(Duration::from_secs(120), retry_wait(2, poll));
+        assert_eq!(Duration::from_secs(480), retry_wait(4, poll));
+        assert_eq!(MAX_RETRY_WAIT, retry_wait(10, poll));
+        assert_eq!(MAX_RETRY_WAIT, retry_wait(u32::MAX, poll));
+        // A poll longer than the cap is never shortened
+        let long = MAX_RETRY_WAIT * 2;
+        assert_eq!(long, retry_wait(3, long));
+    }
+}
@@ -31,6 +31,9 @@ pub enum Error {
     #[error("Unauthorized Error")]
     Unauthorized {},
 
+    #[error("Forbidden")]
+    Forbidden {},
+
     #[error("Bad Request")]
     BadRequest {},
 
@@ -56,6 +59,7 @@ impl<'r, 'o: 'r> Responder<'r, 'o> for Error {
 
         match self {
             Error::Unauthorized {} => Status::Unauthorized.respond_to(req),
+            Error::Forbidden {} => Status::Forbidden.respond_to(req),
             Error::NotFound {} => Status::NotFound.respond_to(req),
             Error::BadRequest {} => Status::BadRequest.respond_to(req),
             Error::InternalServerError {} => Status::InternalServerError.respond_to(req),
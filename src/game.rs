@@ -0,0 +1,38 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Game instances, shared by the server (which scopes its routes by game) and the ringmaster
+//! (which rolls each game on its own).
+//!
+//! Each turn belongs to a game. Seasons are numbered across every game, so anything keyed by
+//! season or turn is already specific to one game; only "the current turn" needs the game.
+
+use crate::schema::games;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+
+/// The game that requests and rolls which don't name one belong to.
+pub const DEFAULT_GAME: &str = "default";
+
+/// One game hosted on this database.
+#[derive(Queryable, Debug, Clone, PartialEq, Eq)]
+pub struct Game {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+impl Game {
+    /// Loads the game called `name`.
+    pub fn load(name: &str, conn: &PgConnection) -> QueryResult<Game> {
+        games::table
+            .filter(games::name.eq(name))
+            .first::<Game>(conn)
+    }
+
+    /// Loads every game, in the order they were created.
+    pub fn load_all(conn: &PgConnection) -> QueryResult<Vec<Game>> {
+        games::table.order_by(games::id).load::<Game>(conn)
+    }
+}
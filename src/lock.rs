@@ -0,0 +1,68 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Postgres advisory locks, which stop two ringmasters from doing the same work at once.
+//!
+//! The locks are held by the database session, so they are released when the connection
+//! closes, even if the ringmaster that took them crashed.
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Bool, Integer};
+
+/// Held, per game, by the one daemon allowed to roll that game.
+const DAEMON: i32 = 0x7272_6d64; // "rrmd"
+/// Held, per turn, by whichever ringmaster is rolling that turn.
+const ROLL: i32 = 0x7272_726c; // "rrrl"
+
+#[derive(QueryableByName)]
+struct Locked {
+    #[sql_type = "Bool"]
+    locked: bool,
+}
+
+/// An advisory lock, released when dropped.
+pub struct AdvisoryLock<'a> {
+    namespace: i32,
+    id: i32,
+    conn: &'a PgConnection,
+}
+
+impl<'a> AdvisoryLock<'a> {
+    /// Takes the lock held by the daemon rolling `game`, or `None` if another daemon has it.
+    pub fn daemon(game: i32, conn: &'a PgConnection) -> QueryResult<Option<AdvisoryLock<'a>>> {
+        AdvisoryLock::try_acquire(DAEMON, game, conn)
+    }
+
+    /// Takes the lock for rolling `turn_id`, or `None` if another ringmaster is rolling it.
+    pub fn roll(turn_id: i32, conn: &'a PgConnection) -> QueryResult<Option<AdvisoryLock<'a>>> {
+        AdvisoryLock::try_acquire(ROLL, turn_id, conn)
+    }
+
+    fn try_acquire(
+        namespace: i32,
+        id: i32,
+        conn: &'a PgConnection,
+    ) -> QueryResult<Option<AdvisoryLock<'a>>> {
+        let lock = sql_query("SELECT pg_try_advisory_lock($1, $2) AS locked")
+            .bind::<Integer, _>(namespace)
+            .bind::<Integer, _>(id)
+            .get_result::<Locked>(conn)?;
+        Ok(lock.locked.then_some(AdvisoryLock {
+            namespace,
+            id,
+            conn,
+        }))
+    }
+}
+
+impl Drop for AdvisoryLock<'_> {
+    fn drop(&mut self) {
+        let _ = sql_query("SELECT pg_advisory_unlock($1, $2) AS locked")
+            .bind::<Integer, _>(self.namespace)
+            .bind::<Integer, _>(self.id)
+            .get_result::<Locked>(self.conn);
+    }
+}
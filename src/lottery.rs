@@ -0,0 +1,546 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The territory lottery, shared by the ringmaster (which rolls it) and the
+//! server (which forecasts it).
+
+use rand::{Rng, RngCore};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Based on random number `lottery`, return the ID of the victorious team
+#[must_use]
+pub fn determine_victor(lottery: f64, powers: &BTreeMap<i32, f64>) -> i32 {
+    // This is the incrementing value that we will add each team's power to
+    let mut victorsum = 0_f64;
+    // This will be what we return, the id of the victorious team.
+    let mut victor = 0;
+    for (key, power) in powers {
+        // Add to the sum
+        victorsum += power;
+
+        // Is the sum greater than the random number?
+        if lottery - victorsum < 0_f64 {
+            // If so, we have our winner! Go!
+            victor = *key;
+            break;
+        }
+    }
+    victor
+}
+
+/// How the victor of a territory that more than one team put power on is chosen.
+pub trait VictorStrategy {
+    /// Picks the victor from each team's power (of which at least one is positive),
+    /// returning the victorious team and the random number that was drawn to pick it.
+    fn victor(&self, powers: &BTreeMap<i32, f64>, rng: &mut dyn RngCore) -> (i32, f64);
+
+    /// Each team's chance of being picked by `victor`.
+    fn chances(&self, powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64>;
+}
+
+/// Each team's share of the total.
+fn shares(powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+    let totalpower: f64 = powers.values().sum();
+    powers
+        .iter()
+        .map(|(team, power)| (*team, power / totalpower))
+        .collect()
+}
+
+/// Each team's chance is its share of the power. This is the classic rule.
+pub struct Lottery;
+
+impl VictorStrategy for Lottery {
+    fn victor(&self, powers: &BTreeMap<i32, f64>, rng: &mut dyn RngCore) -> (i32, f64) {
+        let totalpower: f64 = powers.values().sum();
+        let lottery = rng.gen_range(0_f64..totalpower);
+        (determine_victor(lottery, powers), lottery)
+    }
+
+    fn chances(&self, powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+        shares(powers)
+    }
+}
+
+/// The team with the most power wins outright; a tie is broken at random.
+pub struct HighestPower;
+
+impl HighestPower {
+    fn leaders(powers: &BTreeMap<i32, f64>) -> Vec<i32> {
+        let most = powers.values().copied().fold(0_f64, f64::max);
+        powers
+            .iter()
+            .filter(|(_, power)| **power == most)
+            .map(|(team, _)| *team)
+            .collect()
+    }
+}
+
+impl VictorStrategy for HighestPower {
+    fn victor(&self, powers: &BTreeMap<i32, f64>, rng: &mut dyn RngCore) -> (i32, f64) {
+        let leaders = HighestPower::leaders(powers);
+        let lottery = rng.gen_range(0_f64..leaders.len() as f64);
+        (leaders[lottery as usize], lottery)
+    }
+
+    fn chances(&self, powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+        let leaders = HighestPower::leaders(powers);
+        powers
+            .keys()
+            .map(|team| match leaders.contains(team) {
+                true => (*team, 1.0 / leaders.len() as f64),
+                false => (*team, 0.0),
+            })
+            .collect()
+    }
+}
+
+/// A lottery weighted by the square of each team's power, favouring the stronger team.
+pub struct PowerSquared;
+
+impl PowerSquared {
+    fn squared(powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+        powers
+            .iter()
+            .map(|(team, power)| (*team, power * power))
+            .collect()
+    }
+}
+
+impl VictorStrategy for PowerSquared {
+    fn victor(&self, powers: &BTreeMap<i32, f64>, rng: &mut dyn RngCore) -> (i32, f64) {
+        Lottery.victor(&PowerSquared::squared(powers), rng)
+    }
+
+    fn chances(&self, powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+        shares(&PowerSquared::squared(powers))
+    }
+}
+
+/// A lottery in which no team's chance can exceed `cap`, so the underdogs always
+/// keep at least `1 - cap` between them. When fewer than `1 / cap` teams put power on the
+/// territory, no split keeps them all under the cap, and they get an even chance instead.
+pub struct CappedUnderdog {
+    pub cap: f64,
+}
+
+impl CappedUnderdog {
+    fn capped(&self, powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+        let totalpower: f64 = powers.values().sum();
+        let playing = powers.values().filter(|power| **power > 0.0).count();
+        if self.cap * playing as f64 <= 1.0 {
+            return powers
+                .iter()
+                .map(|(team, power)| match *power > 0.0 {
+                    true => (*team, totalpower / playing as f64),
+                    false => (*team, 0.0),
+                })
+                .collect();
+        }
+        // Cap the favourites one round at a time: shrinking a team's power raises everyone
+        // else's share, which can push another team over the cap
+        let mut capped: BTreeSet<i32> = BTreeSet::new();
+        loop {
+            let uncapped: f64 = powers
+                .iter()
+                .filter(|(team, _)| !capped.contains(team))
+                .map(|(_, power)| power)
+                .sum();
+            // The power whose share is exactly the cap, once the capped teams are held to it
+            let limit = self.cap * uncapped / (1.0 - self.cap * capped.len() as f64);
+            let over: Vec<i32> = powers
+                .iter()
+                .filter(|(team, power)| !capped.contains(team) && **power > limit)
+                .map(|(team, _)| *team)
+                .collect();
+            if over.is_empty() {
+                return powers
+                    .iter()
+                    .map(|(team, power)| match capped.contains(team) {
+                        true => (*team, limit),
+                        false => (*team, *power),
+                    })
+                    .collect();
+            }
+            capped.extend(over);
+        }
+    }
+}
+
+impl VictorStrategy for CappedUnderdog {
+    fn victor(&self, powers: &BTreeMap<i32, f64>, rng: &mut dyn RngCore) -> (i32, f64) {
+        Lottery.victor(&self.capped(powers), rng)
+    }
+
+    fn chances(&self, powers: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+        shares(&self.capped(powers))
+    }
+}
+
+/// The `VictorStrategy` a season is played with, as set in its `season_rules`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strategy {
+    #[default]
+    Lottery,
+    HighestPower,
+    PowerSquared,
+    CappedUnderdog {
+        cap: f64,
+    },
+}
+
+impl Strategy {
+    /// The strategy called `kind`, with the `cap` that `capped_underdog` needs.
+    pub fn new(kind: &str, cap: Option<f64>) -> Result<Strategy, String> {
+        match (kind, cap) {
+            ("lottery", _) => Ok(Strategy::Lottery),
+            ("highest_power", _) => Ok(Strategy::HighestPower),
+            ("power_squared", _) => Ok(Strategy::PowerSquared),
+            ("capped_underdog", Some(cap)) if cap > 0.0 && cap < 1.0 => {
+                Ok(Strategy::CappedUnderdog { cap })
+            }
+            ("capped_underdog", cap) => Err(format!(
+                "The capped underdog strategy needs a cap between 0 and 1, not {cap:?}"
+            )),
+            (kind, _) => Err(format!("Unknown territory resolution strategy {kind}")),
+        }
+    }
+
+    #[must_use]
+    pub fn victor_strategy(self) -> Box<dyn VictorStrategy> {
+        match self {
+            Strategy::Lottery => Box::new(Lottery),
+            Strategy::HighestPower => Box::new(HighestPower),
+            Strategy::PowerSquared => Box::new(PowerSquared),
+            Strategy::CappedUnderdog { cap } => Box::new(CappedUnderdog { cap }),
+        }
+    }
+}
+
+/// A single territory up for grabs, and the power each team put forth on it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Contest {
+    pub territory: i32,
+    pub owner: i32,
+    pub powers: BTreeMap<i32, f64>,
+}
+
+impl Contest {
+    /// Builds one contest per owned territory from `(territory, owner)` pairs and
+    /// `(territory, team, power, alt_score)` moves. Moves with an alt score at or above
+    /// `alt_cutoff` don't count.
+    #[must_use]
+    pub fn from_moves(
+        owners: &[(i32, i32)],
+        moves: &[(i32, i32, f64, i32)],
+        alt_cutoff: i32,
+    ) -> Vec<Contest> {
+        let mut contests = owners
+            .iter()
+            .map(|&(territory, owner)| {
+                (
+                    territory,
+                    Contest {
+                        territory,
+                        owner,
+                        powers: BTreeMap::new(),
+                    },
+                )
+            })
+            .collect::<BTreeMap<i32, Contest>>();
+        for &(territory, team, power, alt_score) in moves {
+            if alt_score >= alt_cutoff {
+                continue;
+            }
+            if let Some(contest) = contests.get_mut(&territory) {
+                *contest.powers.entry(team).or_insert(0.0) += power;
+            }
+        }
+        contests.into_values().collect()
+    }
+
+    /// Rolls the territory the way the ringmaster does: nobody putting forth any
+    /// power leaves it with its owner, a lone team with power takes it, and
+    /// otherwise `strategy` picks the victor.
+    pub fn roll(&self, strategy: &dyn VictorStrategy, rng: &mut dyn RngCore) -> i32 {
+        let mut contenders = self.powers.iter().filter(|(_, power)| **power > 0.0);
+        match (contenders.next(), contenders.next()) {
+            (None, _) => self.owner,
+            (Some((team, _)), None) => *team,
+            _ => strategy.victor(&self.powers, rng).0,
+        }
+    }
+}
+
+/// The results of rolling the same contests many times over.
+#[derive(Debug, Default, PartialEq)]
+pub struct Forecast {
+    /// The number of times the roll was simulated
+    pub runs: u32,
+    /// Each team's territory count in every run, sorted ascending
+    pub territory_counts: BTreeMap<i32, Vec<u32>>,
+    /// How many runs each team won each territory in, by territory then team
+    pub wins: BTreeMap<i32, BTreeMap<i32, u32>>,
+}
+
+impl Forecast {
+    /// Simulates the roll of `contests` under `strategy` `runs` times.
+    pub fn simulate(
+        contests: &[Contest],
+        strategy: &dyn VictorStrategy,
+        runs: u32,
+        rng: &mut dyn RngCore,
+    ) -> Forecast {
+        let mut forecast = Forecast {
+            runs,
+            ..Forecast::default()
+        };
+        // Every team that could end up with a territory gets a count, even if it is always zero
+        for contest in contests {
+            for team in std::iter::once(&contest.owner).chain(contest.powers.keys()) {
+                forecast.territory_counts.entry(*team).or_default();
+            }
+        }
+        for _ in 0..runs {
+            let mut counts: BTreeMap<i32, u32> = BTreeMap::new();
+            for contest in contests {
+                let victor = contest.roll(strategy, rng);
+                *counts.entry(victor).or_insert(0) += 1;
+                *forecast
+                    .wins
+                    .entry(contest.territory)
+                    .or_default()
+                    .entry(victor)
+                    .or_insert(0) += 1;
+            }
+            for (team, history) in &mut forecast.territory_counts {
+                history.push(counts.get(team).copied().unwrap_or(0));
+            }
+        }
+        for history in forecast.territory_counts.values_mut() {
+            history.sort_unstable();
+        }
+        forecast
+    }
+
+    /// The mean territory count for `team`.
+    #[must_use]
+    pub fn expected(&self, team: i32) -> f64 {
+        match self.territory_counts.get(&team) {
+            Some(history) if !history.is_empty() => {
+                f64::from(history.iter().sum::<u32>()) / history.len() as f64
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// The `percentile`th (0-100) territory count for `team`, by nearest rank.
+    #[must_use]
+    pub fn percentile(&self, team: i32, percentile: u32) -> u32 {
+        match self.territory_counts.get(&team) {
+            Some(history) if !history.is_empty() => {
+                let rank = (percentile as usize * history.len() + 99) / 100;
+                history[rank.clamp(1, history.len()) - 1]
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha12Rng;
+
+    #[test]
+    fn test_determine_victor() {
+        let powers = BTreeMap::from([(1, 10.0), (2, 0.0), (3, 30.0)]);
+        assert_eq!(1, determine_victor(0.0, &powers));
+        assert_eq!(1, determine_victor(9.99, &powers));
+        assert_eq!(3, determine_victor(10.0, &powers));
+        assert_eq!(3, determine_victor(39.99, &powers));
+    }
+
+    #[test]
+    fn test_contests_from_moves() {
+        let contests = Contest::from_moves(
+            &[(1, 5), (2, 6)],
+            &[
+                (1, 5, 2.0, 0),
+                (1, 7, 1.0, 0),
+                (1, 7, 1.5, 0),
+                (1, 8, 9.0, 80),
+                (3, 5, 1.0, 0),
+            ],
+            75,
+        );
+        assert_eq!(
+            vec![
+                Contest {
+                    territory: 1,
+                    owner: 5,
+                    powers: BTreeMap::from([(5, 2.0), (7, 2.5)]),
+                },
+                Contest {
+                    territory: 2,
+                    owner: 6,
+                    powers: BTreeMap::new(),
+                },
+            ],
+            contests
+        );
+    }
+
+    #[test]
+    fn test_forecast() {
+        let contests = vec![
+            Contest {
+                territory: 1,
+                owner: 1,
+                powers: BTreeMap::from([(1, 1.0), (2, 3.0)]),
+            },
+            Contest {
+                territory: 2,
+                owner: 1,
+                powers: BTreeMap::new(),
+            },
+            Contest {
+                territory: 3,
+                owner: 2,
+                powers: BTreeMap::from([(3, 1.0)]),
+            },
+        ];
+        let forecast = Forecast::simulate(
+            &contests,
+            &Lottery,
+            10_000,
+            &mut ChaCha12Rng::seed_from_u64(55),
+        );
+        // Territory 2 never moves and team 3 always takes territory 3
+        assert_eq!(Some(&BTreeMap::from([(1, 10_000)])), forecast.wins.get(&2));
+        assert_eq!(Some(&BTreeMap::from([(3, 10_000)])), forecast.wins.get(&3));
+        // Team 2 takes territory 1 about three times in four
+        let wins = forecast.wins[&1][&2];
+        assert!((7_000..8_000).contains(&wins), "{wins}");
+        assert!((forecast.expected(1) - 1.25).abs() < 0.05);
+        assert_eq!(1, forecast.percentile(1, 5));
+        assert_eq!(2, forecast.percentile(1, 95));
+        assert_eq!(0, forecast.percentile(2, 5));
+        assert_eq!(1, forecast.percentile(3, 50));
+        assert_eq!(0, forecast.percentile(4, 50));
+    }
+
+    #[test]
+    fn test_lottery_matches_ringmaster_draw() {
+        // The lottery must draw exactly as the ringmaster always has, so old rolls still replay
+        let powers = BTreeMap::from([(1, 10.0), (3, 30.0)]);
+        let expected = ChaCha12Rng::seed_from_u64(55).gen_range(0_f64..40.0);
+        let (victor, lottery) = Lottery.victor(&powers, &mut ChaCha12Rng::seed_from_u64(55));
+        assert_eq!(expected, lottery);
+        assert_eq!(determine_victor(expected, &powers), victor);
+    }
+
+    #[test]
+    fn test_highest_power() {
+        let mut rng = ChaCha12Rng::seed_from_u64(55);
+        let powers = BTreeMap::from([(1, 10.0), (2, 30.0), (3, 29.9)]);
+        assert_eq!(2, HighestPower.victor(&powers, &mut rng).0);
+        let tied = BTreeMap::from([(1, 10.0), (2, 30.0), (3, 30.0)]);
+        let winners = (0..100)
+            .map(|_| HighestPower.victor(&tied, &mut rng).0)
+            .collect::<std::collections::BTreeSet<i32>>();
+        assert_eq!(std::collections::BTreeSet::from([2, 3]), winners);
+        assert_eq!(
+            BTreeMap::from([(1, 0.0), (2, 0.5), (3, 0.5)]),
+            HighestPower.chances(&tied)
+        );
+    }
+
+    #[test]
+    fn test_power_squared() {
+        // 1:3 power is 1:9 squared, so team 2 wins nine times in ten
+        let powers = BTreeMap::from([(1, 1.0), (2, 3.0)]);
+        let mut rng = ChaCha12Rng::seed_from_u64(55);
+        let wins = (0..10_000)
+            .filter(|_| PowerSquared.victor(&powers, &mut rng).0 == 2)
+            .count();
+        assert!((8_800..9_200).contains(&wins), "{wins}");
+        assert_eq!(
+            BTreeMap::from([(1, 0.1), (2, 0.9)]),
+            PowerSquared.chances(&powers)
+        );
+    }
+
+    #[test]
+    fn test_capped_underdog() {
+        // Team 2 would win 99 times in 100, but is capped at 80
+        let powers = BTreeMap::from([(1, 1.0), (2, 99.0)]);
+        let strategy = CappedUnderdog { cap: 0.8 };
+        let mut rng = ChaCha12Rng::seed_from_u64(55);
+        let wins = (0..10_000)
+            .filter(|_| strategy.victor(&powers, &mut rng).0 == 2)
+            .count();
+        assert!((7_800..8_200).contains(&wins), "{wins}");
+        assert!((strategy.chances(&powers)[&2] - 0.8).abs() < 1e-9);
+        // An even contest isn't touched
+        let even = BTreeMap::from([(1, 1.0), (2, 1.0)]);
+        let (_, lottery) = strategy.victor(&even, &mut ChaCha12Rng::seed_from_u64(55));
+        assert_eq!(
+            Lottery.victor(&even, &mut ChaCha12Rng::seed_from_u64(55)).1,
+            lottery
+        );
+
+        // Capping team 1 pushes team 2 over the cap too
+        let chances = CappedUnderdog { cap: 0.3 }.chances(&BTreeMap::from([
+            (1, 10.0),
+            (2, 9.0),
+            (3, 1.0),
+            (4, 1.0),
+        ]));
+        for (team, chance) in [(1, 0.3), (2, 0.3), (3, 0.2), (4, 0.2)] {
+            assert!((chances[&team] - chance).abs() < 1e-9, "{chances:?}");
+        }
+    }
+
+    #[test]
+    fn test_capped_underdog_too_few_teams() {
+        // Three teams can't all stay under 30%, so they get a third each
+        let chances =
+            CappedUnderdog { cap: 0.3 }.chances(&BTreeMap::from([(1, 10.0), (2, 9.0), (3, 1.0)]));
+        for team in 1..=3 {
+            assert!((chances[&team] - 1.0 / 3.0).abs() < 1e-9, "{chances:?}");
+        }
+        // A team without power still can't win
+        let strategy = CappedUnderdog { cap: 0.8 };
+        let powers = BTreeMap::from([(1, 5.0), (2, 0.0)]);
+        assert_eq!(
+            BTreeMap::from([(1, 1.0), (2, 0.0)]),
+            strategy.chances(&powers)
+        );
+        let mut rng = ChaCha12Rng::seed_from_u64(55);
+        assert!((0..100).all(|_| strategy.victor(&powers, &mut rng).0 == 1));
+    }
+
+    #[test]
+    fn test_strategy_new() {
+        assert_eq!(
+            Ok(Strategy::CappedUnderdog { cap: 0.75 }),
+            Strategy::new("capped_underdog", Some(0.75))
+        );
+        assert_eq!(
+            Ok(Strategy::HighestPower),
+            Strategy::new("highest_power", None)
+        );
+        // The cap only matters to capped_underdog
+        assert_eq!(
+            Ok(Strategy::PowerSquared),
+            Strategy::new("power_squared", Some(2.0))
+        );
+        assert_eq!(Ok(Strategy::Lottery), Strategy::new("lottery", None));
+        assert!(Strategy::new("capped_underdog", Some(1.5)).is_err());
+        assert!(Strategy::new("capped_underdog", None).is_err());
+        assert!(Strategy::new("coin_toss", None).is_err());
+    }
+}
@@ -0,0 +1,481 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Map files: a TOML description of a map's regions, territories, adjacency and starting
+//! owners, which the ringmaster checks and loads into the database for a new season.
+//!
+//! ```toml
+//! name = "ohio"
+//!
+//! [[regions]]
+//! id = 1
+//! name = "North"
+//!
+//! [[territories]]
+//! id = 1
+//! name = "Toledo"
+//! region = 1
+//! owner = 2
+//! neighbors = [2]
+//! ```
+//!
+//! A territory's `neighbors` leave out the territory itself, which is always adjacent to
+//! itself, and `owner` defaults to team 0.
+
+use crate::schema::{regions, territories, territory_adjacency, territory_ownership, turninfo};
+use crate::structs::{RollState, TerritoryOwnersInsert, TurnInfo};
+use chrono::NaiveDateTime;
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel_citext::types::CiString;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::Path;
+
+/// The last turn of adjacency rows that are still open.
+const OPEN_ENDED: i32 = 999999;
+
+/// A map as described in a map file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Map {
+    /// Recorded as the `map` of the turns played on it
+    pub name: String,
+    pub regions: Vec<Region>,
+    pub territories: Vec<Territory>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Territory {
+    pub id: i32,
+    pub name: String,
+    pub region: i32,
+    /// The team that owns the territory on day 1
+    #[serde(default)]
+    pub owner: i32,
+    #[serde(default)]
+    pub neighbors: Vec<i32>,
+}
+
+impl Map {
+    /// Reads a map from the TOML file at `path`.
+    pub fn read(path: &Path) -> Result<Map, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+        Ok(Map::parse(&text)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Map, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Checks that the map can be played, returning a description of each problem found.
+    ///
+    /// Every id must be unique, every territory must be in a region and every region must
+    /// have a territory. Neighbors must be territories on the map, adjacency must run both
+    /// ways, and every territory must be reachable from every other.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.territories.is_empty() {
+            errors.push(String::from("The map has no territories"));
+        }
+        let mut region_ids = BTreeSet::new();
+        for region in &self.regions {
+            if !region_ids.insert(region.id) {
+                errors.push(format!("Region id {} is used more than once", region.id));
+            }
+        }
+        let mut names = BTreeSet::new();
+        let mut neighbors = BTreeMap::new();
+        for territory in &self.territories {
+            if neighbors.insert(territory.id, territory).is_some() {
+                errors.push(format!(
+                    "Territory id {} is used more than once",
+                    territory.id
+                ));
+            }
+            // Territory names are case insensitive in the database
+            if !names.insert(territory.name.to_lowercase()) {
+                errors.push(format!(
+                    "Territory name {} is used more than once",
+                    territory.name
+                ));
+            }
+            if !region_ids.contains(&territory.region) {
+                errors.push(format!(
+                    "Territory {} is in region {}, which is not on the map",
+                    territory.id, territory.region
+                ));
+            }
+        }
+        for region in &self.regions {
+            if !self.territories.iter().any(|t| t.region == region.id) {
+                errors.push(format!("Region {} has no territories", region.id));
+            }
+        }
+        for territory in &self.territories {
+            for neighbor in &territory.neighbors {
+                match neighbors.get(neighbor) {
+                    None => errors.push(format!(
+                        "Territory {} neighbors territory {neighbor}, which is not on the map",
+                        territory.id
+                    )),
+                    Some(other) if !other.neighbors.contains(&territory.id) => {
+                        errors.push(format!(
+                        "Territory {} neighbors territory {neighbor}, but not the other way round",
+                        territory.id
+                    ))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        let unreachable = self.unreachable();
+        if !unreachable.is_empty() {
+            errors.push(format!(
+                "Territories {unreachable:?} can't be reached from territory {}",
+                self.territories[0].id
+            ));
+        }
+        errors
+    }
+
+    /// Checks that no other game is playing on the map's territories or regions, returning a
+    /// description of each one in use. `in_use` holds the `(game, territory, region)` of each
+    /// territory on another game's current turn.
+    ///
+    /// Territories, regions and adjacency are shared by every game, so loading the map over
+    /// them would change the map of a game in progress.
+    pub fn validate_in_use(&self, in_use: &[(i32, i32, i32)]) -> Vec<String> {
+        let mut errors = BTreeSet::new();
+        for &(game, territory, region) in in_use {
+            if self.territories.iter().any(|t| t.id == territory) {
+                errors.insert(format!(
+                    "Territory {territory} is on the current turn of game {game}"
+                ));
+            }
+            if self.regions.iter().any(|r| r.id == region) {
+                errors.insert(format!(
+                    "Region {region} is on the current turn of game {game}"
+                ));
+            }
+        }
+        errors.into_iter().collect()
+    }
+
+    /// The `(game, territory, region)` of each territory on the current turn of a game other
+    /// than `game`: the turn taking moves, or one being rolled. Games whose season is over
+    /// have no current turn, so their territories are free to be used again.
+    fn in_use(game: i32, conn: &PgConnection) -> QueryResult<Vec<(i32, i32, i32)>> {
+        territory_ownership::table
+            .inner_join(turninfo::table.on(turninfo::id.eq(territory_ownership::turn_id)))
+            .inner_join(
+                territories::table.on(territories::id.eq(territory_ownership::territory_id)),
+            )
+            .filter(turninfo::game.ne(game))
+            .filter(
+                turninfo::active
+                    .eq(true)
+                    .or(turninfo::roll_state.eq(RollState::Locked))
+                    .or(turninfo::roll_state.eq(RollState::Rolling)),
+            )
+            .select((
+                turninfo::game,
+                territory_ownership::territory_id,
+                territories::region,
+            ))
+            .load(conn)
+    }
+
+    /// The territories that can't be reached from the first territory on the map.
+    fn unreachable(&self) -> Vec<i32> {
+        let Some(first) = self.territories.first() else {
+            return Vec::new();
+        };
+        let neighbors = self
+            .territories
+            .iter()
+            .map(|territory| (territory.id, &territory.neighbors))
+            .collect::<BTreeMap<i32, &Vec<i32>>>();
+        let mut reached = BTreeSet::from([first.id]);
+        let mut queue = VecDeque::from([first.id]);
+        while let Some(territory) = queue.pop_front() {
+            for &neighbor in neighbors.get(&territory).into_iter().flat_map(|n| n.iter()) {
+                if neighbors.contains_key(&neighbor) && reached.insert(neighbor) {
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        neighbors
+            .keys()
+            .filter(|territory| !reached.contains(territory))
+            .copied()
+            .collect()
+    }
+
+    /// Every `(territory_id, adjacent_id)` pair on the map, including each territory's
+    /// adjacency to itself.
+    fn adjacency(&self) -> BTreeSet<(i32, i32)> {
+        self.territories
+            .iter()
+            .flat_map(|territory| {
+                std::iter::once(territory.id)
+                    .chain(territory.neighbors.iter().copied())
+                    .map(move |neighbor| (territory.id, neighbor))
+            })
+            .collect()
+    }
+
+    /// Loads the map into the database as day 1 of `season` in `game`, creating the turn if
+    /// it doesn't exist yet. Returns the day 1 turn.
+    ///
+    /// The map may not use territories or regions another game is playing on (see
+    /// [`Map::validate_in_use`]). Regions and territories are added or renamed to match the map. Adjacency rows still
+    /// open for the map's territories are closed before the day 1 turn, so earlier turns keep
+    /// the adjacency they were played with.
+    pub fn load(
+        &self,
+        game: i32,
+        season: i32,
+        start_time: Option<NaiveDateTime>,
+        conn: &PgConnection,
+    ) -> Result<TurnInfo, Box<dyn std::error::Error>> {
+        let errors = self.validate();
+        if !errors.is_empty() {
+            return Err(format!("Map {} is not valid: {}", self.name, errors.join("; ")).into());
+        }
+        conn.transaction(|| {
+            let errors = self.validate_in_use(&Map::in_use(game, conn)?);
+            if !errors.is_empty() {
+                return Err(format!(
+                    "Map {} is in use by another game: {}",
+                    self.name,
+                    errors.join("; ")
+                )
+                .into());
+            }
+            if let Ok(turn) = TurnInfo::get(game, season, 1, conn) {
+                if !TerritoryOwnersInsert::load(turn.id, conn)?.is_empty() {
+                    return Err(format!("Season {season} already has territory owners").into());
+                }
+            }
+            TurnInfo::insert_new(
+                game,
+                season,
+                1,
+                true,
+                false,
+                false,
+                Some(self.name.clone()),
+                false,
+                start_time,
+                conn,
+            )?;
+            let turn = TurnInfo::get(game, season, 1, conn)?;
+            self.insert_regions(conn)?;
+            self.insert_territories(conn)?;
+            self.insert_adjacency(turn.id, conn)?;
+            let owners = self
+                .territories
+                .iter()
+                .map(|territory| TerritoryOwnersInsert {
+                    territory_id: territory.id,
+                    owner_id: territory.owner,
+                    turn_id: turn.id,
+                    previous_owner_id: territory.owner,
+                    random_number: 0_f64,
+                    mvp: None,
+                })
+                .collect::<Vec<TerritoryOwnersInsert>>();
+            TerritoryOwnersInsert::insert(&owners, conn)?;
+            Ok(turn)
+        })
+    }
+
+    fn insert_regions(&self, conn: &PgConnection) -> QueryResult<usize> {
+        let rows = self
+            .regions
+            .iter()
+            .map(|region| {
+                (
+                    regions::id.eq(region.id),
+                    regions::name.eq(CiString::from(region.name.as_str())),
+                )
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(regions::table)
+            .values(&rows)
+            .on_conflict(regions::id)
+            .do_update()
+            .set(regions::name.eq(excluded(regions::name)))
+            .execute(conn)
+    }
+
+    fn insert_territories(&self, conn: &PgConnection) -> QueryResult<usize> {
+        let rows = self
+            .territories
+            .iter()
+            .map(|territory| {
+                (
+                    territories::id.eq(territory.id),
+                    territories::name.eq(CiString::from(territory.name.as_str())),
+                    territories::region.eq(territory.region),
+                )
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(territories::table)
+            .values(&rows)
+            .on_conflict(territories::id)
+            .do_update()
+            .set((
+                territories::name.eq(excluded(territories::name)),
+                territories::region.eq(excluded(territories::region)),
+            ))
+            .execute(conn)
+    }
+
+    /// Closes the adjacency still open for the map's territories at `turn_id` and opens the
+    /// map's own from then on.
+    fn insert_adjacency(&self, turn_id: i32, conn: &PgConnection) -> QueryResult<usize> {
+        let ids = self.territories.iter().map(|t| t.id).collect::<Vec<i32>>();
+        diesel::update(
+            territory_adjacency::table
+                .filter(
+                    territory_adjacency::territory_id
+                        .eq_any(&ids)
+                        .or(territory_adjacency::adjacent_id.eq_any(&ids)),
+                )
+                .filter(territory_adjacency::max_turn.ge(turn_id)),
+        )
+        .set(territory_adjacency::max_turn.eq(turn_id - 1))
+        .execute(conn)?;
+        let note = format!("map {}", self.name);
+        let rows = self
+            .adjacency()
+            .into_iter()
+            .map(|(territory, adjacent)| {
+                (
+                    territory_adjacency::territory_id.eq(territory),
+                    territory_adjacency::adjacent_id.eq(adjacent),
+                    territory_adjacency::note.eq(note.as_str()),
+                    territory_adjacency::min_turn.eq(turn_id - 1),
+                    territory_adjacency::max_turn.eq(OPEN_ENDED),
+                )
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(territory_adjacency::table)
+            .values(&rows)
+            .execute(conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIANGLE: &str = r#"
+        name = "triangle"
+
+        [[regions]]
+        id = 1
+        name = "West"
+
+        [[regions]]
+        id = 2
+        name = "East"
+
+        [[territories]]
+        id = 1
+        name = "Alpha"
+        region = 1
+        owner = 3
+        neighbors = [2, 3]
+
+        [[territories]]
+        id = 2
+        name = "Bravo"
+        region = 1
+        neighbors = [1, 3]
+
+        [[territories]]
+        id = 3
+        name = "Charlie"
+        region = 2
+        neighbors = [1, 2]
+    "#;
+
+    #[test]
+    fn test_parse_and_validate() {
+        let map = Map::parse(TRIANGLE).unwrap();
+        assert_eq!(map.name, "triangle");
+        assert_eq!(map.territories[0].owner, 3);
+        assert_eq!(map.territories[1].owner, 0);
+        assert_eq!(map.validate(), Vec::<String>::new());
+        assert_eq!(map.adjacency().len(), 9);
+        assert!(map.adjacency().contains(&(2, 2)));
+    }
+
+    #[test]
+    fn test_validate_problems() {
+        let mut map = Map::parse(TRIANGLE).unwrap();
+        // 3 is cut off from 1 and 2, and is in a region that doesn't exist
+        map.territories[0].neighbors = vec![2];
+        map.territories[1].neighbors = vec![1, 4];
+        map.territories[2].neighbors = vec![1];
+        map.territories[2].region = 5;
+        map.regions.push(Region {
+            id: 6,
+            name: String::from("Empty"),
+        });
+        assert_eq!(
+            map.validate(),
+            vec![
+                "Territory 3 is in region 5, which is not on the map",
+                "Region 2 has no territories",
+                "Region 6 has no territories",
+                "Territory 2 neighbors territory 4, which is not on the map",
+                "Territory 3 neighbors territory 1, but not the other way round",
+                "Territories [3] can't be reached from territory 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_example_map() {
+        let map = Map::parse(include_str!("../db/maps/example.toml")).unwrap();
+        assert_eq!(map.validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_validate_in_use() {
+        let map = Map::parse(TRIANGLE).unwrap();
+        assert!(map.validate_in_use(&[]).is_empty());
+        // Game 2 plays on territory 3 and region 2; game 3 on territories the map doesn't use
+        assert_eq!(
+            map.validate_in_use(&[(2, 3, 2), (3, 7, 4), (3, 8, 4)]),
+            vec![
+                "Region 2 is on the current turn of game 2",
+                "Territory 3 is on the current turn of game 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_duplicates() {
+        let mut map = Map::parse(TRIANGLE).unwrap();
+        map.territories[2].id = 2;
+        map.territories[2].name = String::from("alpha");
+        let errors = map.validate();
+        assert!(errors.contains(&String::from("Territory id 2 is used more than once")));
+        assert!(errors.contains(&String::from("Territory name alpha is used more than once")));
+    }
+}
@@ -0,0 +1,301 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Roles, and the changes each may make through `/admin`.
+//!
+//! Roles are kept in `users.role_id`, and each may do everything the roles below it may. Every
+//! change made through `/admin` is written to `audit_log` along with the user who made it.
+
+use crate::db::DbConn;
+use crate::model::Claims;
+use crate::schema::{audit_log, turninfo, users};
+use crate::sys::SysInfo;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel_citext::types::CiString;
+use rocket::http::CookieJar;
+use rocket::State;
+use serde_json::{json, Value};
+
+/// The `audit_log` event recorded for each change made through `/admin`.
+pub(crate) const ADMIN_EVENT: i32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    /// No access to `/admin`
+    Player,
+    /// May put players of their own team back to playing for it
+    Captain,
+    /// May also ban users, flag alts and fix anyone's `playing_for`
+    Moderator,
+    /// May also hand out roles and edit turns
+    Admin,
+}
+
+impl Role {
+    pub(crate) fn from_id(id: Option<i32>) -> Role {
+        match id {
+            Some(1) => Role::Captain,
+            Some(2) => Role::Moderator,
+            Some(3) => Role::Admin,
+            _ => Role::Player,
+        }
+    }
+
+    pub(crate) fn id(self) -> i32 {
+        match self {
+            Role::Player => 0,
+            Role::Captain => 1,
+            Role::Moderator => 2,
+            Role::Admin => 3,
+        }
+    }
+}
+
+/// A user as `/admin` sees them.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AdminUser {
+    pub(crate) id: i32,
+    pub(crate) name: String,
+    pub(crate) role: Role,
+    pub(crate) current_team: i32,
+    pub(crate) playing_for: i32,
+    pub(crate) is_alt: bool,
+    pub(crate) banned: bool,
+}
+
+/// A change to a user made through `/admin`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UserChange {
+    Banned(bool),
+    IsAlt(bool),
+    PlayingFor(i32),
+    Role(Role),
+}
+
+/// Changes to a turn made through `/admin`. Fields left out are left alone.
+#[derive(Serialize, Deserialize, AsChangeset, Debug, Default, Clone)]
+#[table_name = "turninfo"]
+pub(crate) struct TurnEdit {
+    pub(crate) active: Option<bool>,
+    pub(crate) complete: Option<bool>,
+    pub(crate) finale: Option<bool>,
+    pub(crate) rollstarttime: Option<NaiveDateTime>,
+    pub(crate) allornothingenabled: Option<bool>,
+    pub(crate) map: Option<String>,
+}
+
+impl AdminUser {
+    pub(crate) fn load(id: i32, conn: &PgConnection) -> QueryResult<AdminUser> {
+        users::table
+            .filter(users::id.eq(id))
+            .select((
+                users::id,
+                users::uname,
+                users::role_id,
+                users::current_team,
+                users::playing_for,
+                users::is_alt,
+                users::banned,
+            ))
+            .first::<(i32, CiString, Option<i32>, i32, i32, bool, bool)>(conn)
+            .map(
+                |(id, name, role, current_team, playing_for, is_alt, banned)| AdminUser {
+                    id,
+                    name: name.to_string(),
+                    role: Role::from_id(role),
+                    current_team,
+                    playing_for,
+                    is_alt,
+                    banned,
+                },
+            )
+    }
+
+    /// Loads the logged-in user, who must have a role (and not be banned) to use `/admin`.
+    pub(crate) async fn from_private_cookie(
+        cookies: &CookieJar<'_>,
+        config: &State<SysInfo>,
+        conn: &DbConn,
+    ) -> Result<AdminUser, crate::Error> {
+        let (claims, _) = Claims::from_private_cookie(cookies, config)?;
+        let user = conn
+            .run(move |c| AdminUser::load(claims.id, c))
+            .await
+            .map_err(|_| crate::Error::Unauthorized {})?;
+        if user.role == Role::Player || user.banned {
+            return Err(crate::Error::Forbidden {});
+        }
+        Ok(user)
+    }
+
+    /// Makes `change` to the user `target` on behalf of this user, and records it in
+    /// `audit_log`. Returns the user as they are after the change.
+    pub(crate) fn change(
+        &self,
+        target: i32,
+        change: UserChange,
+        cip: Option<String>,
+        conn: &PgConnection,
+    ) -> Result<AdminUser, crate::Error> {
+        conn.transaction::<_, crate::Error, _>(|| {
+            let before = AdminUser::load(target, conn).map_err(|e| match e {
+                diesel::result::Error::NotFound => crate::Error::NotFound {},
+                e => e.into(),
+            })?;
+            if !change.permitted(self, &before) {
+                return Err(crate::Error::Forbidden {});
+            }
+            let after = change.apply(&before);
+            diesel::update(users::table.filter(users::id.eq(target)))
+                .set((
+                    users::banned.eq(after.banned),
+                    users::is_alt.eq(after.is_alt),
+                    users::playing_for.eq(after.playing_for),
+                    users::role_id.eq(Some(after.role.id())),
+                ))
+                .execute(conn)?;
+            self.audit(
+                json!({ "user": target, "change": change, "before": before }),
+                cip,
+                conn,
+            )?;
+            Ok(after)
+        })
+    }
+
+    /// Applies `edit` to the turn `turn_id` and records it in `audit_log`. Only admins may
+    /// edit turns.
+    pub(crate) fn edit_turn(
+        &self,
+        turn_id: i32,
+        edit: TurnEdit,
+        cip: Option<String>,
+        conn: &PgConnection,
+    ) -> Result<(), crate::Error> {
+        if self.role != Role::Admin {
+            return Err(crate::Error::Forbidden {});
+        }
+        if edit.is_empty() {
+            return Err(crate::Error::BadRequest {});
+        }
+        conn.transaction::<_, crate::Error, _>(|| {
+            let updated = diesel::update(turninfo::table.filter(turninfo::id.eq(turn_id)))
+                .set(&edit)
+                .execute(conn)?;
+            if updated == 0 {
+                return Err(crate::Error::NotFound {});
+            }
+            self.audit(json!({ "turn": turn_id, "edit": edit }), cip, conn)?;
+            Ok(())
+        })
+    }
+
+    /// Writes an admin action taken by this user to `audit_log`.
+    fn audit(&self, action: Value, cip: Option<String>, conn: &PgConnection) -> QueryResult<usize> {
+        diesel::insert_into(audit_log::table)
+            .values((
+                audit_log::user_id.eq(self.id),
+                audit_log::event.eq(ADMIN_EVENT),
+                audit_log::data.eq(json!({ "by": self.name, "action": action })),
+                audit_log::cip.eq(cip),
+            ))
+            .execute(conn)
+    }
+}
+
+impl UserChange {
+    /// Whether `actor` may make this change to `target`. Apart from captains fixing their own
+    /// players, users may only act on those with a lower role than their own.
+    pub(crate) fn permitted(&self, actor: &AdminUser, target: &AdminUser) -> bool {
+        match *self {
+            UserChange::Banned(_) | UserChange::IsAlt(_) => {
+                actor.role >= Role::Moderator && actor.role > target.role
+            }
+            // -1 leaves the user playing for nobody, as when their team is eliminated
+            UserChange::PlayingFor(team) if team <= 0 && team != -1 => false,
+            UserChange::PlayingFor(team) => {
+                actor.role >= Role::Moderator
+                    || (actor.role == Role::Captain
+                        && target.current_team == actor.current_team
+                        && team == actor.current_team)
+            }
+            UserChange::Role(_) => actor.role == Role::Admin && actor.role > target.role,
+        }
+    }
+
+    /// `user` with this change made.
+    pub(crate) fn apply(&self, user: &AdminUser) -> AdminUser {
+        let mut user = user.clone();
+        match *self {
+            UserChange::Banned(banned) => user.banned = banned,
+            UserChange::IsAlt(is_alt) => user.is_alt = is_alt,
+            UserChange::PlayingFor(team) => user.playing_for = team,
+            UserChange::Role(role) => user.role = role,
+        }
+        user
+    }
+}
+
+impl TurnEdit {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.active.is_none()
+            && self.complete.is_none()
+            && self.finale.is_none()
+            && self.rollstarttime.is_none()
+            && self.allornothingenabled.is_none()
+            && self.map.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: i32, role: Role, current_team: i32) -> AdminUser {
+        AdminUser {
+            id,
+            name: format!("user{id}"),
+            role,
+            current_team,
+            playing_for: current_team,
+            is_alt: false,
+            banned: false,
+        }
+    }
+
+    #[test]
+    fn test_role_ids() {
+        for role in [Role::Player, Role::Captain, Role::Moderator, Role::Admin] {
+            assert_eq!(Role::from_id(Some(role.id())), role);
+        }
+        assert_eq!(Role::from_id(None), Role::Player);
+        assert_eq!(Role::from_id(Some(7)), Role::Player);
+    }
+
+    #[test]
+    fn test_permitted() {
+        let (admin, moderator) = (user(1, Role::Admin, 1), user(2, Role::Moderator, 1));
+        let (captain, player) = (user(3, Role::Captain, 4), user(4, Role::Player, 4));
+        let outsider = user(5, Role::Player, 5);
+        assert!(UserChange::Banned(true).permitted(&moderator, &player));
+        assert!(UserChange::IsAlt(true).permitted(&admin, &moderator));
+        // Nobody may act on someone of their own rank, nor captains ban anyone
+        assert!(!UserChange::Banned(true).permitted(&moderator, &user(6, Role::Moderator, 1)));
+        assert!(!UserChange::Banned(true).permitted(&captain, &player));
+        // Captains only put their own players back on their own team
+        assert!(UserChange::PlayingFor(4).permitted(&captain, &player));
+        assert!(!UserChange::PlayingFor(5).permitted(&captain, &player));
+        assert!(!UserChange::PlayingFor(4).permitted(&captain, &outsider));
+        assert!(UserChange::PlayingFor(-1).permitted(&moderator, &outsider));
+        assert!(!UserChange::PlayingFor(0).permitted(&admin, &outsider));
+        // Only admins hand out roles
+        assert!(UserChange::Role(Role::Moderator).permitted(&admin, &player));
+        assert!(!UserChange::Role(Role::Captain).permitted(&moderator, &player));
+        assert!(!UserChange::Role(Role::Player).permitted(&admin, &user(7, Role::Admin, 1)));
+        assert!(UserChange::Banned(true).apply(&player).banned);
+    }
+}
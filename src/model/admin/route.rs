@@ -0,0 +1,143 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::db::DbConn;
+use crate::model::admin::{AdminUser, Role, TurnEdit, UserChange};
+use crate::model::reddit::route::Cip;
+use crate::sys::SysInfo;
+use rocket::http::CookieJar;
+use rocket::serde::json::Json;
+use rocket::State;
+
+#[derive(Deserialize)]
+pub(crate) struct Banned {
+    pub(crate) banned: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IsAlt {
+    pub(crate) is_alt: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PlayingFor {
+    pub(crate) team: i32,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SetRole {
+    pub(crate) role: Role,
+}
+
+/// # User
+/// Shows a user as `/admin` sees them, including their role and whether they are banned.
+#[get("/users/<id>")]
+pub(crate) async fn user(
+    id: i32,
+    cookies: &CookieJar<'_>,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<AdminUser>, crate::Error> {
+    AdminUser::from_private_cookie(cookies, config, &conn).await?;
+    conn.run(move |c| AdminUser::load(id, c))
+        .await
+        .map(Json)
+        .map_err(|_| crate::Error::NotFound {})
+}
+
+/// # Ban
+/// Bans (or unbans) a user. Banned users can't log in, join a team or move.
+#[post("/users/<id>/banned", format = "application/json", data = "<banned>")]
+pub(crate) async fn ban(
+    id: i32,
+    banned: Json<Banned>,
+    cookies: &CookieJar<'_>,
+    cip: Cip,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<AdminUser>, crate::Error> {
+    let change = UserChange::Banned(banned.banned);
+    change_user(id, change, cookies, cip, conn, config).await
+}
+
+/// # Alt
+/// Flags (or unflags) a user as an alt.
+#[post("/users/<id>/is_alt", format = "application/json", data = "<is_alt>")]
+pub(crate) async fn alt(
+    id: i32,
+    is_alt: Json<IsAlt>,
+    cookies: &CookieJar<'_>,
+    cip: Cip,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<AdminUser>, crate::Error> {
+    let change = UserChange::IsAlt(is_alt.is_alt);
+    change_user(id, change, cookies, cip, conn, config).await
+}
+
+/// # Playing For
+/// Sets the team a user is playing for, or -1 for none.
+#[post(
+    "/users/<id>/playing_for",
+    format = "application/json",
+    data = "<playing_for>"
+)]
+pub(crate) async fn playing_for(
+    id: i32,
+    playing_for: Json<PlayingFor>,
+    cookies: &CookieJar<'_>,
+    cip: Cip,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<AdminUser>, crate::Error> {
+    let change = UserChange::PlayingFor(playing_for.team);
+    change_user(id, change, cookies, cip, conn, config).await
+}
+
+/// # Role
+/// Gives a user a role: `player`, `captain`, `moderator` or `admin`.
+#[post("/users/<id>/role", format = "application/json", data = "<role>")]
+pub(crate) async fn role(
+    id: i32,
+    role: Json<SetRole>,
+    cookies: &CookieJar<'_>,
+    cip: Cip,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<AdminUser>, crate::Error> {
+    let change = UserChange::Role(role.role);
+    change_user(id, change, cookies, cip, conn, config).await
+}
+
+/// # Edit Turn
+/// Edits a turn's `active`, `complete`, `finale`, `rollstarttime`, `allornothingenabled` or
+/// `map`. Fields left out are left alone.
+#[patch("/turns/<id>", format = "application/json", data = "<edit>")]
+pub(crate) async fn edit_turn(
+    id: i32,
+    edit: Json<TurnEdit>,
+    cookies: &CookieJar<'_>,
+    cip: Cip,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<String>, crate::Error> {
+    let admin = AdminUser::from_private_cookie(cookies, config, &conn).await?;
+    let edit = edit.into_inner();
+    conn.run(move |c| admin.edit_turn(id, edit, cip.0, c))
+        .await?;
+    std::result::Result::Ok(Json(String::from("Okay")))
+}
+
+async fn change_user(
+    id: i32,
+    change: UserChange,
+    cookies: &CookieJar<'_>,
+    cip: Cip,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<AdminUser>, crate::Error> {
+    let admin = AdminUser::from_private_cookie(cookies, config, &conn).await?;
+    conn.run(move |c| admin.change(id, change, cip.0, c))
+        .await
+        .map(Json)
+}
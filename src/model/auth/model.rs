@@ -1,17 +1,28 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::db::DbConn;
+use crate::model::token::{ApiToken, Bearer, Scope};
+use crate::model::{Ratings, Stats};
+use crate::rules::Rules;
 use crate::schema::{
     continuation_polls, continuation_responses, logs, territories, turninfo, turns,
 };
 use crate::sys::SysInfo;
+use chrono::Utc;
 use diesel::prelude::*;
 use jsonwebtoken::errors::Error;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use rocket::http::CookieJar;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::time::Duration;
 use rocket::State;
 use serde::{Deserialize, Serialize};
 
+/// How long a session lasts before it must be renewed through `/auth/<platform>/refresh`:
+/// 30 days, in seconds.
+pub(crate) const SESSION_LENGTH: usize = 2_592_000;
+
+/// `token` is the provider's access token, and `refresh_token` the one used to get another.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct Claims {
     pub(crate) id: i32,
@@ -51,6 +62,34 @@ pub struct MoveSub {
     pub aon: Option<bool>,
 }
 
+/// The multipliers a move gets, before any all-or-nothing roll.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Multipliers {
+    /// Whether the target is already owned by the player's team
+    pub(crate) home: bool,
+    pub(crate) home_multiplier: f64,
+    pub(crate) regional_multiplier: f64,
+    /// Whether the move may go all-or-nothing, which triples its power or zeroes it
+    pub(crate) aon_eligible: bool,
+}
+
+/// What a move on `target` would be worth, for `/auth/move/preview`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct MovePreview {
+    pub(crate) target: i32,
+    pub(crate) team: i32,
+    pub(crate) allowed: bool,
+    /// Why the move isn't allowed
+    pub(crate) reason: Option<String>,
+    #[serde(flatten)]
+    pub(crate) multipliers: Option<Multipliers>,
+    pub(crate) multiplier: Option<f64>,
+    pub(crate) stars: i32,
+    pub(crate) weight: f64,
+    pub(crate) merc: bool,
+    pub(crate) power: Option<f64>,
+}
+
 #[derive(Serialize, Deserialize, Queryable)]
 pub(crate) struct Poll {
     pub(crate) id: i32,
@@ -85,6 +124,55 @@ impl Claims {
         }
     }
 
+    /// Claims for a session starting now.
+    pub(crate) fn new(
+        id: i32,
+        user: String,
+        access_token: &str,
+        refresh_token: Option<&str>,
+    ) -> Claims {
+        Claims {
+            id,
+            user,
+            token: Some(access_token.to_string()),
+            refresh_token: refresh_token.map(String::from),
+            exp: Utc::now().timestamp() as usize + SESSION_LENGTH,
+        }
+    }
+
+    /// These claims, for a session renewed now with the provider's new tokens. Providers that
+    /// don't rotate refresh tokens return none, in which case the old one is kept.
+    pub(crate) fn renewed(&self, access_token: &str, refresh_token: Option<&str>) -> Claims {
+        Claims::new(
+            self.id,
+            self.user.clone(),
+            access_token,
+            refresh_token.or(self.refresh_token.as_deref()),
+        )
+    }
+
+    /// Sets the private `jwt` cookie that holds these claims, and the `username` cookie
+    /// alongside it, both lasting as long as the session.
+    pub(crate) fn to_private_cookies(
+        &self,
+        cookies: &CookieJar<'_>,
+        config: &State<SysInfo>,
+    ) -> Result<(), crate::Error> {
+        let jwt = Claims::put(config.settings.cookie_key.as_bytes(), self.clone())
+            .map_err(|_| crate::Error::InternalServerError {})?;
+        for (name, value) in [("username", self.user.clone()), ("jwt", jwt)] {
+            cookies.add_private(
+                Cookie::build(name, value)
+                    .same_site(SameSite::Lax)
+                    .domain(config.settings.base_url.clone())
+                    .path("/")
+                    .max_age(Duration::seconds(SESSION_LENGTH as i64))
+                    .finish(),
+            );
+        }
+        Ok(())
+    }
+
     pub(crate) fn from_private_cookie(
         cookies: &CookieJar<'_>,
         config: &State<SysInfo>,
@@ -98,6 +186,88 @@ impl Claims {
         )
         .map_err(|_| crate::Error::BadRequest {})
     }
+
+    /// Like `from_private_cookie`, but falls back to a personal API token sent as
+    /// `Authorization: Bearer`, which must allow `scope`. Uses of the token are logged under
+    /// `route`. Claims made from a token carry no Reddit/Discord tokens.
+    pub(crate) async fn authenticate(
+        cookies: &CookieJar<'_>,
+        config: &State<SysInfo>,
+        bearer: &Bearer,
+        scope: Scope,
+        route: &'static str,
+        conn: &DbConn,
+    ) -> Result<(Claims, Header), crate::Error> {
+        let token = match (&bearer.0, cookies.get_private("jwt")) {
+            (Some(token), None) => token.clone(),
+            _ => return Claims::from_private_cookie(cookies, config),
+        };
+        let (id, user) = conn
+            .run(move |c| ApiToken::authenticate(&token, scope, route, c))
+            .await?;
+        Ok((
+            Claims {
+                id,
+                user,
+                token: None,
+                refresh_token: None,
+                exp: 0,
+            },
+            Header::default(),
+        ))
+    }
+}
+
+impl Multipliers {
+    /// The move's multiplier, before any all-or-nothing roll.
+    pub(crate) fn multiplier(&self) -> f64 {
+        self.home_multiplier * self.regional_multiplier
+    }
+}
+
+impl MovePreview {
+    /// Previews a move on `target` by a player of `team` (whose own team is `current_team`),
+    /// with `multipliers` from the same checks `/auth/move` makes.
+    pub(crate) fn new(
+        target: i32,
+        team: i32,
+        current_team: i32,
+        stats: &Stats,
+        multipliers: Result<Multipliers, String>,
+        rules: &Rules,
+    ) -> MovePreview {
+        let stars = Ratings::load(stats, rules).overall;
+        let weight = star_weight(stars);
+        let (multipliers, reason) = match multipliers {
+            Ok(multipliers) => (Some(multipliers), None),
+            Err(reason) => (None, Some(reason)),
+        };
+        let multiplier = multipliers.as_ref().map(Multipliers::multiplier);
+        MovePreview {
+            target,
+            team,
+            allowed: multipliers.is_some(),
+            reason,
+            multipliers,
+            multiplier,
+            stars,
+            weight,
+            merc: team != current_team,
+            power: multiplier.map(|multiplier| multiplier * weight),
+        }
+    }
+}
+
+/// The weight a move counts for, from the player's overall star rating.
+pub(crate) fn star_weight(stars: i32) -> f64 {
+    match stars {
+        1 => 1.0,
+        2 => 2.0,
+        3 => 3.0,
+        4 => 4.0,
+        5 => 5.0,
+        _ => 1.0,
+    }
 }
 
 impl Log {
@@ -202,3 +372,57 @@ impl PollResponse {
             .execute(conn)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renewed() {
+        let old = Claims {
+            id: 4,
+            user: String::from("mautamu"),
+            token: Some(String::from("access")),
+            refresh_token: Some(String::from("refresh")),
+            exp: 0,
+        };
+        let renewed = old.renewed("access2", None);
+        assert_eq!((renewed.id, renewed.user.as_str()), (4, "mautamu"));
+        assert_eq!(renewed.token.as_deref(), Some("access2"));
+        // Reddit keeps the refresh token it first gave out, Discord hands out a new one
+        assert_eq!(renewed.refresh_token.as_deref(), Some("refresh"));
+        let rotated = old.renewed("access2", Some("refresh2"));
+        assert_eq!(rotated.refresh_token.as_deref(), Some("refresh2"));
+        assert!(renewed.exp >= Utc::now().timestamp() as usize + SESSION_LENGTH - 60);
+    }
+
+    #[test]
+    fn test_move_preview() {
+        let rules = Rules::default();
+        let stats = Stats {
+            totalTurns: 30,
+            gameTurns: 12,
+            mvps: 1,
+            streak: 4,
+        };
+        let multipliers = Multipliers {
+            home: true,
+            home_multiplier: 1.5,
+            regional_multiplier: 2.0,
+            aon_eligible: false,
+        };
+        let preview = MovePreview::new(12, 4, 4, &stats, Ok(multipliers), &rules);
+        assert!(preview.allowed && !preview.merc);
+        assert_eq!((preview.stars, preview.weight), (3, 3.0));
+        assert_eq!(preview.multiplier, Some(3.0));
+        assert_eq!(preview.power, Some(9.0));
+        // A mercenary move that isn't allowed still shows the player's stars
+        let refused = Err(String::from("Territory is not adjacent"));
+        let preview = MovePreview::new(12, 4, 7, &stats, refused, &rules);
+        assert!(!preview.allowed && preview.merc);
+        assert_eq!(preview.reason.as_deref(), Some("Territory is not adjacent"));
+        assert_eq!((preview.multiplier, preview.power), (None, None));
+        assert_eq!(preview.weight, 3.0);
+        assert_eq!(star_weight(0), 1.0);
+    }
+}
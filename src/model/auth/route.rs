@@ -1,19 +1,27 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::alts::{AltScore, AltWeights};
 use crate::db::DbConn;
+use crate::game::DEFAULT_GAME;
+use crate::model::token::{Bearer, Scope};
 use crate::model::{
-    Claims, CurrentStrength, Latest, Log, MoveInfo, MoveSub, PlayerWithTurnsAndAdditionalTeam,
-    Poll, PollResponse, Ratings, Stats, TurnInfo, UpdateUser,
+    star_weight, Claims, CurrentStrength, Latest, Log, MoveInfo, MovePreview, MoveSub, Multipliers,
+    PlayerWithTurnsAndAdditionalTeam, Poll, PollResponse, Ratings, Stats, TurnInfo, UpdateUser,
+    User,
 };
+use crate::moves::{check_bracket, check_move, MoveError};
+use crate::rules::Rules;
 use crate::schema::{
-    cfbr_stats, region_ownership, territory_adjacency, territory_ownership, turns, users,
+    cfbr_stats, move_snapshots, playoff_brackets, region_ownership, territory_adjacency,
+    territory_ownership, turninfo, turns, users,
 };
 use crate::sys::SysInfo;
 use diesel::prelude::*;
 use diesel::result::Error;
 use rocket::http::{CookieJar, Status};
 use rocket::State;
+use std::collections::BTreeMap;
 extern crate rand;
 use diesel_citext::types::CiString;
 use rand::{thread_rng, Rng};
@@ -26,10 +34,11 @@ use rocket::serde::json::Json;
 #[get("/me")]
 pub(crate) async fn me(
     cookies: &CookieJar<'_>,
+    bearer: Bearer,
     conn: DbConn,
     config: &State<SysInfo>,
 ) -> Result<Json<PlayerWithTurnsAndAdditionalTeam>, crate::Error> {
-    let c = Claims::from_private_cookie(cookies, config)?;
+    let c = Claims::authenticate(cookies, config, &bearer, Scope::Read, "me", &conn).await?;
     let username = c.0.user.clone();
     let user = conn
         .run(move |connection| {
@@ -48,6 +57,7 @@ pub(crate) async fn me(
 pub(crate) async fn join_team(
     team: i32,
     cookies: &CookieJar<'_>,
+    bearer: Bearer,
     conn: DbConn,
     config: &State<SysInfo>,
 ) -> Result<Json<String>, crate::Error> {
@@ -56,7 +66,8 @@ pub(crate) async fn join_team(
         return Err(crate::Error::BadRequest {});
     }
     // Get user information from cookies
-    let c = Claims::from_private_cookie(cookies, config)?;
+    let c = Claims::authenticate(cookies, config, &bearer, Scope::Move, "join", &conn).await?;
+    refuse_banned(c.0.id, &conn).await?;
     //see if user already has team, and if user has current_team
     let username = c.0.user.clone();
     let users = conn
@@ -109,19 +120,22 @@ pub(crate) async fn join_team(
     }
 }
 
-#[post("/my_move", rank = 1)]
+#[post("/my_move?<game>", rank = 1)]
 pub(crate) async fn my_move(
+    game: Option<String>,
     cookies: &CookieJar<'_>,
+    bearer: Bearer,
     conn: DbConn,
     config: &State<SysInfo>,
 ) -> Result<Json<String>, crate::Error> {
     // Get latest turn
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
     let latest = conn
-        .run(move |c| Latest::latest(c))
+        .run(move |c| Latest::latest(&game, c))
         .await
         .map_err(|_| crate::Error::InternalServerError {})?;
     // Get user information from cookies
-    let c = Claims::from_private_cookie(cookies, config)?;
+    let c = Claims::authenticate(cookies, config, &bearer, Scope::Read, "my_move", &conn).await?;
     // Return the territory the user has attacked
     std::result::Result::Ok(Json(
         conn.run(move |connection| MoveInfo::get(latest.season, latest.day, c.0.id, connection))
@@ -131,26 +145,49 @@ pub(crate) async fn my_move(
     ))
 }
 
-#[post("/move", rank = 1, format = "application/json", data = "<movesub>")]
+#[post(
+    "/move?<game>",
+    rank = 1,
+    format = "application/json",
+    data = "<movesub>"
+)]
 pub(crate) async fn make_move(
+    game: Option<String>,
     movesub: Json<MoveSub>,
     cookies: &CookieJar<'_>,
+    bearer: Bearer,
     conn: DbConn,
     config: &State<SysInfo>,
+    alt_weights: &State<AltWeights>,
 ) -> Result<Json<i32>, crate::Error> {
     let target = movesub.target;
     let mut log = Log::begin(String::from("move"), target.to_string());
 
     // Get latest turn
-    let latest = conn.run(move |c| TurnInfo::latest(c)).await.map_err(|_| {
-        dbg!("Failed at point 1");
-        crate::Error::InternalServerError {}
-    })?;
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    let latest = conn
+        .run(move |c| TurnInfo::latest(&game, c))
+        .await
+        .map_err(|_| {
+            dbg!("Failed at point 1");
+            crate::Error::InternalServerError {}
+        })?;
 
     log.payload.push_str(&format!("Latest: {}\n", latest.id));
 
+    // Get the rules the current season is played by
+    let season = latest.season;
+    let rules = conn
+        .run(move |c| Rules::load(season, c))
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to load season rules for season {season}: {e}");
+            crate::Error::InternalServerError {}
+        })?;
+
     // Get user information from cookies
-    let c = Claims::from_private_cookie(cookies, config)?;
+    let c = Claims::authenticate(cookies, config, &bearer, Scope::Move, "move", &conn).await?;
+    refuse_banned(c.0.id, &conn).await?;
 
     log.payload.push_str(&format!("Claims: {:?}\n", c.0.id));
 
@@ -158,9 +195,17 @@ pub(crate) async fn make_move(
 
     //get user's team information, and whether they can make that move
     let temp_pfix = c.0.clone();
+    let tmprules = rules.clone();
     let (user, multiplier) = conn
         .run(move |connection| {
-            handle_territory_info(&temp_pfix, target, &tmplatest, connection, movesub.aon)
+            handle_territory_info(
+                &temp_pfix,
+                target,
+                &tmplatest,
+                &tmprules,
+                connection,
+                movesub.aon,
+            )
         })
         .await
         .map_err(|_| {
@@ -178,16 +223,9 @@ pub(crate) async fn make_move(
         streak: user.6.unwrap_or(0),
     };
 
-    let user_ratings = Ratings::load(&user_stats);
+    let user_ratings = Ratings::load(&user_stats, &rules);
 
-    let user_weight: f64 = match user_ratings.overall {
-        1 => 1.0,
-        2 => 2.0,
-        3 => 3.0,
-        4 => 4.0,
-        5 => 5.0,
-        _ => 1.0,
-    };
+    let user_weight: f64 = star_weight(user_ratings.overall);
     let user_power: f64 = multiplier * user_weight;
     let mut merc: bool = false;
 
@@ -195,12 +233,26 @@ pub(crate) async fn make_move(
         merc = true;
     }
 
+    // Score how likely the player is to be an alt, keeping the reasons for moderators
+    let weights = alt_weights.inner().clone();
+    let (user_id, turn_id, tmprules) = (user.1, latest.id, rules.clone());
+    let alt = conn
+        .run(move |c| AltScore::assess(user_id, turn_id, &weights, &tmprules, c))
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to score alt signals for user {user_id}: {e}");
+            crate::Error::InternalServerError {}
+        })?;
+
+    log.payload.push_str(&format!("Alt score: {}\n", alt.score));
+
     let insert_turn = conn
         .run(move |connection| {
             insert_turn(
                 &user,
                 user_ratings,
                 &latest,
+                alt.score,
                 target,
                 multiplier,
                 user_weight,
@@ -223,6 +275,14 @@ pub(crate) async fn make_move(
     log.payload
         .push_str(&format!("New Turn: {:?}\n", insert_turn[0]));
 
+    // Keep the user's counters from before their first move this turn, in case they cancel it
+    conn.run(move |connection| snapshot_user(&user, turn_id, connection))
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to snapshot user {user_id} on turn {turn_id}: {e}");
+            crate::Error::InternalServerError {}
+        })?;
+
     //now we go update the user
     conn.run(move |connection| {
         UpdateUser::do_update(
@@ -252,11 +312,97 @@ pub(crate) async fn make_move(
     std::result::Result::Ok(Json(insert_turn[0]))
 }
 
-#[get("/polls", rank = 1)]
-pub(crate) async fn get_polls(conn: DbConn) -> Result<Json<Vec<Poll>>, crate::Error> {
+/// # Move Preview
+/// Shows what a move on `target` would be worth to the logged-in user, without making it:
+/// whether it's allowed, its multipliers, the stars it counts for, whether it's a mercenary
+/// move, and its power. All-or-nothing isn't rolled, so an eligible move's power is shown
+/// before it is tripled or zeroed.
+#[get("/move/preview?<target>&<game>", rank = 1)]
+pub(crate) async fn preview(
+    target: i32,
+    game: Option<String>,
+    cookies: &CookieJar<'_>,
+    bearer: Bearer,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<MovePreview>, crate::Error> {
     // Get latest turn
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
     let latest = conn
-        .run(move |c| Latest::latest(c))
+        .run(move |c| TurnInfo::latest(&game, c))
+        .await
+        .map_err(|_| crate::Error::InternalServerError {})?;
+
+    // Get the rules the current season is played by
+    let season = latest.season;
+    let rules = conn
+        .run(move |c| Rules::load(season, c))
+        .await
+        .map_err(|_| crate::Error::InternalServerError {})?;
+
+    let c =
+        Claims::authenticate(cookies, config, &bearer, Scope::Read, "move_preview", &conn).await?;
+    conn.run(move |connection| preview_move(&c.0, target, &latest, &rules, connection))
+        .await
+        .map(Json)
+        .map_err(|_| crate::Error::NotFound {})
+}
+
+/// # Cancel Move
+/// Withdraws the logged-in user's move on the current turn, and puts their counters back as
+/// they were before they moved. Moves can't be cancelled once the turn is locked for its roll.
+/// Returns the territory the move was on.
+#[delete("/move?<game>", rank = 1)]
+pub(crate) async fn cancel_move(
+    game: Option<String>,
+    cookies: &CookieJar<'_>,
+    bearer: Bearer,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<i32>, crate::Error> {
+    let mut log = Log::begin(String::from("cancel_move"), String::new());
+
+    // Get latest turn, which is no longer active once it's locked
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    let latest = conn
+        .run(move |c| TurnInfo::latest(&game, c))
+        .await
+        .map_err(|e| match e {
+            Error::NotFound => crate::Error::BadRequest {},
+            _ => crate::Error::InternalServerError {},
+        })?;
+
+    log.payload.push_str(&format!("Latest: {}\n", latest.id));
+
+    let c =
+        Claims::authenticate(cookies, config, &bearer, Scope::Move, "cancel_move", &conn).await?;
+    refuse_banned(c.0.id, &conn).await?;
+
+    log.payload.push_str(&format!("Claims: {:?}\n", c.0.id));
+
+    let (user_id, turn_id) = (c.0.id, latest.id);
+    let territory = conn
+        .run(move |connection| withdraw_move(user_id, turn_id, connection))
+        .await?;
+
+    log.query = territory.to_string();
+    log.payload
+        .push_str(&format!("Cancelled Turn: {territory:?}\n"));
+
+    conn.run(move |c| log.insert(c)).await?;
+
+    std::result::Result::Ok(Json(territory))
+}
+
+#[get("/polls?<game>", rank = 1)]
+pub(crate) async fn get_polls(
+    game: Option<String>,
+    conn: DbConn,
+) -> Result<Json<Vec<Poll>>, crate::Error> {
+    // Get latest turn
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    let latest = conn
+        .run(move |c| Latest::latest(&game, c))
         .await
         .map_err(|_| crate::Error::InternalServerError {})?;
 
@@ -272,45 +418,30 @@ pub(crate) async fn get_polls(conn: DbConn) -> Result<Json<Vec<Poll>>, crate::Er
 #[get("/poll/respond?<poll>&<response>", rank = 1)]
 pub(crate) async fn submit_poll(
     cookies: &CookieJar<'_>,
+    bearer: Bearer,
     conn: DbConn,
     config: &State<SysInfo>,
     poll: i32,
     response: bool,
-) -> Result<Json<bool>, Status> {
+) -> Result<Json<bool>, crate::Error> {
     // get user id
-    match cookies.get_private("jwt") {
-        Some(cookie) => {
-            match Claims::interpret(
-                config.settings.cookie_key.as_bytes(),
-                cookie.value().to_string(),
-            ) {
-                Ok(c) => {
-                    // id, name Json(c.0.user)
-                    match conn
-                        .run(move |connection| {
-                            PollResponse::upsert(
-                                PollResponse {
-                                    id: -1,
-                                    poll,
-                                    user_id: c.0.id,
-                                    response,
-                                },
-                                connection,
-                            )
-                        })
-                        .await
-                    {
-                        Ok(inner) => match inner {
-                            1 => std::result::Result::Ok(Json(true)),
-                            _ => std::result::Result::Err(Status::InternalServerError),
-                        },
-                        Err(_E) => std::result::Result::Err(Status::InternalServerError),
-                    }
-                }
-                Err(_err) => std::result::Result::Err(Status::Unauthorized),
-            }
-        }
-        None => std::result::Result::Err(Status::Unauthorized),
+    let c = Claims::authenticate(cookies, config, &bearer, Scope::Move, "poll", &conn).await?;
+    match conn
+        .run(move |connection| {
+            PollResponse::upsert(
+                PollResponse {
+                    id: -1,
+                    poll,
+                    user_id: c.0.id,
+                    response,
+                },
+                connection,
+            )
+        })
+        .await
+    {
+        Ok(1) => std::result::Result::Ok(Json(true)),
+        _ => std::result::Result::Err(crate::Error::InternalServerError {}),
     }
 }
 
@@ -345,6 +476,15 @@ pub(crate) async fn view_response(
     }
 }
 
+/// Turns away users who have been banned through `/admin`.
+async fn refuse_banned(user_id: i32, conn: &DbConn) -> Result<(), crate::Error> {
+    match conn.run(move |c| User::banned(user_id, c)).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(crate::Error::Forbidden {}),
+        Err(_) => Err(crate::Error::Unauthorized {}),
+    }
+}
+
 // Returns the # of regions owned by `team` on `latest` turn.
 pub(crate) fn handleregionalownership(
     latest: &TurnInfo,
@@ -361,7 +501,11 @@ pub(crate) fn handleregionalownership(
         .first(conn)
 }
 
-pub(crate) fn get_regional_multiplier(mut territory_count: f64, team_id: i32) -> f64 {
+pub(crate) fn get_regional_multiplier(
+    mut territory_count: f64,
+    team_id: i32,
+    region_bonus: f64,
+) -> f64 {
     if team_id == 0 {
         return 1.0;
     }
@@ -370,7 +514,7 @@ pub(crate) fn get_regional_multiplier(mut territory_count: f64, team_id: i32) ->
         territory_count -= 1.0;
     }
 
-    territory_count *= 0.5;
+    territory_count *= region_bonus;
 
     1.0 + territory_count
 }
@@ -379,6 +523,7 @@ pub(crate) fn handle_territory_info(
     c: &Claims,
     target: i32,
     latest: &TurnInfo,
+    rules: &Rules,
     conn: &PgConnection,
     aon: Option<bool>,
 ) -> Result<
@@ -400,8 +545,65 @@ pub(crate) fn handle_territory_info(
     String,
 > {
     //get user now_playing team
-    match users::table
-        .filter(users::id.eq(c.id))
+    let team_id = load_move_user(c.id, conn).map_err(|_| MoveError::NotAdjacent.to_string())?;
+    let multipliers = move_multipliers(team_id.0, target, latest, rules, conn)?;
+    let mut aon_multiplier: i32 = 1;
+    if aon == Some(true) && multipliers.aon_eligible {
+        let mut rng = thread_rng();
+        // Triple or nothing
+        aon_multiplier = 3 * rng.gen_range(0..2);
+    }
+    Ok((
+        team_id,
+        multipliers.multiplier() * f64::from(aon_multiplier),
+    ))
+}
+
+/// Previews a move on `target` by the user in `c`, running the same checks as
+/// `handle_territory_info` without rolling all-or-nothing.
+pub(crate) fn preview_move(
+    c: &Claims,
+    target: i32,
+    latest: &TurnInfo,
+    rules: &Rules,
+    conn: &PgConnection,
+) -> QueryResult<MovePreview> {
+    let user = load_move_user(c.id, conn)?;
+    let user_stats = Stats {
+        totalTurns: user.3.unwrap_or(0),
+        gameTurns: user.4.unwrap_or(0),
+        mvps: user.5.unwrap_or(0),
+        streak: user.6.unwrap_or(0),
+    };
+    let multipliers = move_multipliers(user.0, target, latest, rules, conn);
+    Ok(MovePreview::new(
+        target,
+        user.0,
+        user.7,
+        &user_stats,
+        multipliers,
+        rules,
+    ))
+}
+
+/// The user's team, counters and whether they are an alt, as a move needs them.
+fn load_move_user(
+    user_id: i32,
+    conn: &PgConnection,
+) -> QueryResult<(
+    i32,
+    i32,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    //Option<i32>,
+    i32,
+    bool,
+)> {
+    users::table
+        .filter(users::id.eq(user_id))
         .select((
             users::playing_for,
             users::id,
@@ -414,79 +616,48 @@ pub(crate) fn handle_territory_info(
             users::current_team,
             users::is_alt,
         ))
-        .first::<(
-            i32,
-            i32,
-            Option<i32>,
-            Option<i32>,
-            Option<i32>,
-            Option<i32>,
-            Option<i32>,
-            //Option<i32>,
-            i32,
-            bool,
-        )>(conn)
+        .first(conn)
+}
+
+/// The multipliers a move by `team` on `target` gets, or why it can't be made.
+pub(crate) fn move_multipliers(
+    team: i32,
+    target: i32,
+    latest: &TurnInfo,
+    rules: &Rules,
+    conn: &PgConnection,
+) -> Result<Multipliers, String> {
+    let adjacent_territory_owners = get_adjacent_territory_owners(target, latest, conn)
+        .map_err(|_| MoveError::NotAdjacent.to_string())?;
+    let home = check_move(team, target, &adjacent_territory_owners).map_err(|e| e.to_string())?;
+    // During the playoffs, teams only play the other teams in their bracket
+    if let Some(&(owner, _)) = adjacent_territory_owners
+        .iter()
+        .find(|&&(_, territory)| territory == target)
     {
-        Ok(team_id) => match get_adjacent_territory_owners(target, latest, conn) {
-            Ok(adjacent_territory_owners) => {
-                match adjacent_territory_owners
-                    .iter()
-                    .position(|&x| x.0 == team_id.0)
-                {
-                    Some(_tuple_of_territory) => {
-                        //dbg!(&adjacent_territory_owners);
-                        let pos = adjacent_territory_owners
-                            .iter()
-                            .position(|&x| x.1 == target);
-                        match adjacent_territory_owners
-                            .iter()
-                            .position(|&x| x.0 != team_id.0)
-                        {
-                            Some(_npos) => {
-                                if team_id.0 != 0 {
-                                    let regional_multiplier: f64 = get_regional_multiplier(
-                                        handleregionalownership(latest, team_id.0, conn)
-                                            .unwrap_or(0)
-                                            as f64,
-                                        team_id.0,
-                                    );
-
-                                    let mut aon_multiplier: i32 = 1;
-                                    if aon == Some(true)
-                                        && get_territory_number(team_id.0, latest, conn) == 1
-                                        && latest.allOrNothingEnabled == Some(true)
-                                    {
-                                        let mut rng = thread_rng();
-                                        // Triple or nothing
-                                        aon_multiplier = 3 * rng.gen_range(0..2);
-                                    }
-                                    if adjacent_territory_owners[pos.unwrap()].0 == team_id.0 {
-                                        Ok((
-                                            team_id,
-                                            1.5 * regional_multiplier * f64::from(aon_multiplier),
-                                        ))
-                                    } else {
-                                        Ok((
-                                            team_id,
-                                            1.0 * regional_multiplier * f64::from(aon_multiplier),
-                                        ))
-                                    }
-                                } else {
-                                    let mut rng = thread_rng();
-                                    let n: i32 = rng.gen_range(4..6);
-                                    Ok((team_id, f64::from(n / 4)))
-                                }
-                            }
-                            None => Err("You own all the surrounding territories".to_string()),
-                        }
-                    }
-                    None => Err("You don't own that territory or an adjacent one".to_string()),
-                }
-            }
-            Err(_er) => Err("You don't own that territory or an adjacent one".to_string()),
-        },
-        Err(_e) => Err("You don't own that territory or an adjacent one".to_string()),
+        let brackets =
+            get_playoff_brackets(latest, conn).map_err(|_| MoveError::NotAdjacent.to_string())?;
+        check_bracket(team, owner, &brackets).map_err(|e| e.to_string())?;
     }
+    if team == 0 {
+        return Ok(Multipliers {
+            home,
+            home_multiplier: 1.0,
+            regional_multiplier: 1.0,
+            aon_eligible: false,
+        });
+    }
+    Ok(Multipliers {
+        home,
+        home_multiplier: if home { rules.home_multiplier } else { 1.0 },
+        regional_multiplier: get_regional_multiplier(
+            handleregionalownership(latest, team, conn).unwrap_or(0) as f64,
+            team,
+            rules.region_bonus,
+        ),
+        aon_eligible: latest.allOrNothingEnabled == Some(true)
+            && get_territory_number(team, latest, conn) == 1,
+    })
 }
 
 pub(crate) fn get_adjacent_territory_owners(
@@ -510,6 +681,35 @@ pub(crate) fn get_adjacent_territory_owners(
         .load::<(i32, i32)>(conn)
 }
 
+/// The playoff brackets being played on `latest`, grouped by bracket, or none outside the
+/// playoffs.
+pub(crate) fn get_playoff_brackets(
+    latest: &TurnInfo,
+    conn: &PgConnection,
+) -> Result<Vec<Vec<i32>>, Error> {
+    let rows = playoff_brackets::table
+        .filter(playoff_brackets::season.eq(latest.season))
+        .filter(playoff_brackets::turn_id.le(latest.id))
+        .order_by((
+            playoff_brackets::turn_id.desc(),
+            playoff_brackets::bracket,
+            playoff_brackets::team,
+        ))
+        .select((
+            playoff_brackets::turn_id,
+            playoff_brackets::bracket,
+            playoff_brackets::team,
+        ))
+        .load::<(i32, i32, i32)>(conn)?;
+    let mut brackets: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+    if let Some(&(drawn, _, _)) = rows.first() {
+        for &(_, bracket, team) in rows.iter().filter(|row| row.0 == drawn) {
+            brackets.entry(bracket).or_default().push(team);
+        }
+    }
+    Ok(brackets.into_values().collect())
+}
+
 pub(crate) fn get_territory_number(team: i32, latest: &TurnInfo, conn: &PgConnection) -> i32 {
     use diesel::dsl::count;
     territory_ownership::table
@@ -552,6 +752,7 @@ pub(crate) fn insert_turn(
     ),
     user_ratings: Ratings,
     latest: &TurnInfo,
+    alt_score: i32,
     target: i32,
     multiplier: f64,
     user_weight: f64,
@@ -559,10 +760,6 @@ pub(crate) fn insert_turn(
     merc: bool,
     conn: &PgConnection,
 ) -> QueryResult<Vec<i32>> {
-    let alt_score: i32 = match user.8 {
-        true => 175,
-        false => 0,
-    };
     diesel::insert_into(turns::table)
         .values((
             turns::user_id.eq(user.1),
@@ -589,6 +786,103 @@ pub(crate) fn insert_turn(
         .get_results(conn)
 }
 
+/// Keeps `user`'s counters as they were before their first move on the turn `turn_id`.
+pub(crate) fn snapshot_user(
+    user: &(
+        i32,
+        i32,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        //Option<i32>,
+        i32,
+        bool,
+    ),
+    turn_id: i32,
+    conn: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::insert_into(move_snapshots::table)
+        .values((
+            move_snapshots::turn_id.eq(turn_id),
+            move_snapshots::user_id.eq(user.1),
+            move_snapshots::overall.eq(user.2),
+            move_snapshots::turns.eq(user.3),
+            move_snapshots::game_turns.eq(user.4),
+            move_snapshots::mvps.eq(user.5),
+            move_snapshots::streak.eq(user.6),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+/// Deletes `user_id`'s move on the turn `turn_id` and puts their counters back, returning the
+/// territory the move was on. The turn must still be taking moves.
+pub(crate) fn withdraw_move(
+    user_id: i32,
+    turn_id: i32,
+    conn: &PgConnection,
+) -> Result<i32, crate::Error> {
+    conn.transaction::<_, crate::Error, _>(|| {
+        // Holds off the ringmaster's lock until the move is gone, and refuses if it came first
+        turninfo::table
+            .select(turninfo::id)
+            .filter(turninfo::id.eq(turn_id))
+            .filter(turninfo::active.eq(true))
+            .for_update()
+            .first::<i32>(conn)
+            .optional()?
+            .ok_or(crate::Error::BadRequest {})?;
+        let territory = diesel::delete(
+            turns::table
+                .filter(turns::user_id.eq(user_id))
+                .filter(turns::turn_id.eq(turn_id)),
+        )
+        .returning(turns::territory)
+        .get_result::<i32>(conn)
+        .optional()?
+        .ok_or(crate::Error::NotFound {})?;
+        // Moves made before move_snapshots existed have none, and leave the counters alone
+        let snapshot = move_snapshots::table
+            .filter(move_snapshots::turn_id.eq(turn_id))
+            .filter(move_snapshots::user_id.eq(user_id))
+            .select((
+                move_snapshots::overall,
+                move_snapshots::turns,
+                move_snapshots::game_turns,
+                move_snapshots::mvps,
+                move_snapshots::streak,
+            ))
+            .first::<(
+                Option<i32>,
+                Option<i32>,
+                Option<i32>,
+                Option<i32>,
+                Option<i32>,
+            )>(conn)
+            .optional()?;
+        if let Some((overall, turns, game_turns, mvps, streak)) = snapshot {
+            diesel::update(users::table.filter(users::id.eq(user_id)))
+                .set((
+                    users::overall.eq(overall),
+                    users::turns.eq(turns),
+                    users::game_turns.eq(game_turns),
+                    users::mvps.eq(mvps),
+                    users::streak.eq(streak),
+                ))
+                .execute(conn)?;
+            diesel::delete(
+                move_snapshots::table
+                    .filter(move_snapshots::turn_id.eq(turn_id))
+                    .filter(move_snapshots::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+        }
+        Ok(territory)
+    })
+}
+
 pub(crate) fn update_user(
     new: bool,
     user: i32,
@@ -614,32 +908,32 @@ mod tests {
     #[test]
     fn test_get_regional_ncaa() {
         // Assumption: COUNT() never goes below 0.0
-        assert_eq!(get_regional_multiplier(1.0, 0), 1.0); // Neither should NCAA
-        assert_eq!(get_regional_multiplier(2.0, 0), 1.0); // Neither should NCAA
-        assert_eq!(get_regional_multiplier(100.0, 0), 1.0); // Neither should NCAA
-        assert_eq!(get_regional_multiplier(0.0, 0), 1.0); // Never go < 1.0
+        assert_eq!(get_regional_multiplier(1.0, 0, 0.5), 1.0); // Neither should NCAA
+        assert_eq!(get_regional_multiplier(2.0, 0, 0.5), 1.0); // Neither should NCAA
+        assert_eq!(get_regional_multiplier(100.0, 0, 0.5), 1.0); // Neither should NCAA
+        assert_eq!(get_regional_multiplier(0.0, 0, 0.5), 1.0); // Never go < 1.0
     }
 
     #[test]
     fn test_get_regional_chaos() {
         // Assumption: COUNT() never goes below 0.0
         if cfg!(feature = "chaos") {
-            assert_eq!(get_regional_multiplier(1.0, 131), 1.0); // Chaos should not get additional for regions
-            assert_eq!(get_regional_multiplier(2.0, 131), 1.5); // But Chaos should get some credit for > 1, unlike NCAA
-            assert_eq!(get_regional_multiplier(0.0, 131), 1.0); // Never go < 1.0
+            assert_eq!(get_regional_multiplier(1.0, 131, 0.5), 1.0); // Chaos should not get additional for regions
+            assert_eq!(get_regional_multiplier(2.0, 131, 0.5), 1.5); // But Chaos should get some credit for > 1, unlike NCAA
+            assert_eq!(get_regional_multiplier(0.0, 131, 0.5), 1.0); // Never go < 1.0
         } else {
-            assert_eq!(get_regional_multiplier(1.0, 131), 1.5); // Chaos behaves like a normal team
-            assert_eq!(get_regional_multiplier(2.0, 131), 2.0); // Chaos behaves like a normal team
-            assert_eq!(get_regional_multiplier(0.0, 131), 1.0); // Never go < 1.0
+            assert_eq!(get_regional_multiplier(1.0, 131, 0.5), 1.5); // Chaos behaves like a normal team
+            assert_eq!(get_regional_multiplier(2.0, 131, 0.5), 2.0); // Chaos behaves like a normal team
+            assert_eq!(get_regional_multiplier(0.0, 131, 0.5), 1.0); // Never go < 1.0
         }
     }
 
     #[test]
     fn test_get_regional_normal() {
         // Assumption: COUNT() never goes below 0.0
-        assert_eq!(get_regional_multiplier(0.0, 11), 1.0); // Never go < 1.0
-        assert_eq!(get_regional_multiplier(1.0, 11), 1.5); // Test `normal` case
-        assert_eq!(get_regional_multiplier(2.0, 11), 2.0); // Test `normal` case
-        assert_eq!(get_regional_multiplier(3.0, 11), 2.5); // Test `normal` case
+        assert_eq!(get_regional_multiplier(0.0, 11, 0.5), 1.0); // Never go < 1.0
+        assert_eq!(get_regional_multiplier(1.0, 11, 0.5), 1.5); // Test `normal` case
+        assert_eq!(get_regional_multiplier(2.0, 11, 0.5), 2.0); // Test `normal` case
+        assert_eq!(get_regional_multiplier(3.0, 11, 0.5), 2.5); // Test `normal` case
     }
 }
@@ -3,11 +3,11 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use crate::model::{Claims, DiscordUserInfo, UpsertableUser};
 use crate::{db::DbConn, model::User, sys::SysInfo};
-use chrono::prelude::*;
 use diesel_citext::types::CiString;
 use reqwest::header::{AUTHORIZATION, USER_AGENT};
 use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Json;
 use rocket::time::Duration;
 use rocket::State;
 use rocket_oauth2::{OAuth2, TokenResponse};
@@ -32,25 +32,7 @@ pub(crate) async fn callback(
     conn: DbConn,
     config: &State<SysInfo>,
 ) -> Result<Redirect, Status> {
-    let userinfo: Result<DiscordUserInfo, _> = match reqwest::Client::builder().build() {
-        Ok(rclient) => {
-            match rclient
-                .get("https://discord.com/api/users/@me")
-                .header(AUTHORIZATION, format!("Bearer {}", token.access_token()))
-                .header(USER_AGENT, "AggieRiskLocal - Dev Edition")
-                .send()
-                .await
-            {
-                Ok(text) => text.json().await,
-                Err(_) => {
-                    return std::result::Result::Err(Status::BadRequest);
-                }
-            }
-        }
-        Err(_) => {
-            return std::result::Result::Err(Status::BadRequest);
-        }
-    };
+    let userinfo = discord_me(token.access_token()).await;
     match userinfo {
         Ok(user_info) => {
             let new_user = UpsertableUser {
@@ -64,17 +46,14 @@ pub(crate) async fn callback(
                         .run(move |c| User::load(name, "discord".to_string(), c))
                         .await
                     {
+                        Ok(user) if user.banned => std::result::Result::Err(Status::Forbidden),
                         Ok(user) => {
-                            let datetime = Utc::now();
-                            let timestamp: usize = 2_529_000 + datetime.timestamp() as usize;
-                            dbg!(&token);
-                            let new_claims = Claims {
-                                id: user.id,
-                                user: user.uname.to_string(),
-                                token: Some(token.refresh_token().unwrap().to_string()),
-                                refresh_token: Some(token.access_token().to_string()),
-                                exp: timestamp,
-                            };
+                            let new_claims = Claims::new(
+                                user.id,
+                                user.uname.to_string(),
+                                token.access_token(),
+                                token.refresh_token(),
+                            );
                             cookies.add_private(
                                 Cookie::build("username", user_info.name())
                                     .same_site(SameSite::Lax)
@@ -104,6 +83,61 @@ pub(crate) async fn callback(
                 Err(_ex) => std::result::Result::Err(Status::BadRequest),
             }
         }
-        _ => std::result::Result::Err(Status::Gone),
+        Err(status) => std::result::Result::Err(status),
     }
 }
+
+/// # Refresh
+/// Renews the logged-in user's session before it expires. Discord is asked for a new access
+/// token, and the account must still exist and be the one the session is for. Returns when
+/// the renewed session expires, as a Unix timestamp.
+#[post("/discord/refresh")]
+pub(crate) async fn refresh(
+    oauth2: OAuth2<DiscordUserInfo>,
+    cookies: &CookieJar<'_>,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<usize>, Status> {
+    let (claims, _) =
+        Claims::from_private_cookie(cookies, config).map_err(|_| Status::Unauthorized)?;
+    let refresh_token = claims.refresh_token.clone().ok_or(Status::Unauthorized)?;
+    // Discord refuses if the user has deauthorized us or deleted their account
+    let token = oauth2
+        .refresh(&refresh_token)
+        .await
+        .map_err(|_| Status::Unauthorized)?;
+    let name = discord_me(token.access_token()).await?.name();
+    if name.to_lowercase() != claims.user.to_lowercase() {
+        return Err(Status::Unauthorized);
+    }
+    match conn
+        .run(move |c| User::load(name, "discord".to_string(), c))
+        .await
+    {
+        Ok(user) if user.banned => return Err(Status::Forbidden),
+        Ok(user) if user.id == claims.id => {}
+        _ => return Err(Status::Unauthorized),
+    }
+
+    let claims = claims.renewed(token.access_token(), token.refresh_token());
+    claims
+        .to_private_cookies(cookies, config)
+        .map_err(|_| Status::InternalServerError)?;
+    std::result::Result::Ok(Json(claims.exp))
+}
+
+/// Gets the user's information from Discord.
+async fn discord_me(access_token: &str) -> Result<DiscordUserInfo, Status> {
+    reqwest::Client::builder()
+        .build()
+        .map_err(|_| Status::BadRequest)?
+        .get("https://discord.com/api/users/@me")
+        .header(AUTHORIZATION, format!("Bearer {access_token}"))
+        .header(USER_AGENT, "AggieRiskLocal - Dev Edition")
+        .send()
+        .await
+        .map_err(|_| Status::BadRequest)?
+        .json()
+        .await
+        .map_err(|_| Status::Gone)
+}
@@ -2,6 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+pub(crate) mod admin;
 pub(crate) mod auth;
 pub(crate) mod discord;
 pub(crate) mod player;
@@ -12,6 +13,7 @@ pub(crate) mod stats;
 pub(crate) mod sys;
 pub(crate) mod team;
 pub(crate) mod territory;
+pub(crate) mod token;
 pub(crate) mod turn;
 pub(crate) mod user;
 pub(crate) use auth::*;
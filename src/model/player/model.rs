@@ -1,9 +1,11 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::game::DEFAULT_GAME;
 use crate::model::team::TeamWithColors;
 use crate::model::turn::{LastTurn, PastTurn};
 use crate::model::{Colors, Ratings, Stats, Team, Turn};
+use crate::rules::Rules;
 use crate::schema::{award_info, awards, moves, past_turns, teams, territories, turninfo, users};
 use diesel::prelude::*;
 use diesel::result::Error;
@@ -55,6 +57,7 @@ pub struct User {
     pub(crate) mvps: Option<i32>,
     pub(crate) streak: Option<i32>,
     pub(crate) is_alt: bool, //pub(crate) awards: Option<i32>, //    pub team: Option<String>
+    pub(crate) banned: bool,
 }
 
 #[derive(Queryable, Serialize, Deserialize, JsonSchema, Debug)]
@@ -214,6 +217,8 @@ impl PlayerWithTurns {
             ))
             .load::<(User, Team)>(conn)
             .expect("Error loading users");
+        // Players aren't tied to a game, so their ratings are shown by the default game's rules
+        let rules = Rules::load_current(DEFAULT_GAME, conn).unwrap_or_default();
         let mut out = Vec::new();
         for user in results {
             let stats = Stats {
@@ -249,7 +254,7 @@ impl PlayerWithTurns {
                     },
                 }),
                 platform: user.0.platform,
-                ratings: Ratings::load(&stats),
+                ratings: Ratings::load(&stats, &rules),
                 stats,
                 turns: users_turns,
                 is_alt: user.0.is_alt,
@@ -358,10 +363,19 @@ impl User {
                 users::mvps,
                 users::streak,
                 users::is_alt, //users::awards,
+                users::banned,
             ))
             .first::<User>(conn)
     }
 
+    /// Whether the user has been banned through `/admin`.
+    pub fn banned(id: i32, conn: &PgConnection) -> Result<bool, Error> {
+        users::table
+            .filter(users::id.eq(id))
+            .select(users::banned)
+            .first::<bool>(conn)
+    }
+
     pub fn search(s: String, limit: i32, conn: &PgConnection) -> Result<Vec<String>, Error> {
         users::table
             .filter(users::uname.like(CiString::from(s)))
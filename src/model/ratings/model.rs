@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use crate::model::stats::Stats;
+use crate::ratings;
+use crate::rules::Rules;
 use schemars::JsonSchema;
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub(crate) struct Ratings {
@@ -14,15 +16,12 @@ pub(crate) struct Ratings {
 }
 
 impl Ratings {
-    pub(crate) fn load(stat: &Stats) -> Ratings {
-        let totalTurns = Self::fromarr(stat.totalTurns, [0, 10, 25, 50, 100]);
-        let gameTurns = Self::fromarr(stat.gameTurns, [0, 5, 10, 25, 40]);
-        let mvps = Self::fromarr(stat.mvps, [0, 1, 5, 10, 25]);
-        let streak = Self::fromarr(stat.streak, [0, 3, 5, 10, 25]);
-        let mut numbers = vec![totalTurns, gameTurns, mvps, streak]; // awards
-        numbers.sort_unstable();
-        let mid = ((numbers[1] as f32 + numbers[2] as f32) / 2_f32).round() as i32;
-        let overall: i32 = mid;
+    pub(crate) fn load(stat: &Stats, rules: &Rules) -> Ratings {
+        let totalTurns = ratings::stars(stat.totalTurns, &rules.total_turns);
+        let gameTurns = ratings::stars(stat.gameTurns, &rules.game_turns);
+        let mvps = ratings::stars(stat.mvps, &rules.mvps);
+        let streak = ratings::stars(stat.streak, &rules.streak);
+        let overall = ratings::overall([totalTurns, gameTurns, mvps, streak]); // awards
         Ratings {
             overall,
             totalTurns,
@@ -31,17 +30,4 @@ impl Ratings {
             streak,
         }
     }
-
-    fn fromarr(num: i32, arr: [i32; 5]) -> i32 {
-        let mut r = 0;
-        for x in &arr {
-            if x <= &num {
-                r += 1;
-            } else {
-                r += 0;
-                break;
-            }
-        }
-        r
-    }
 }
@@ -2,14 +2,15 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use crate::model::{Claims, RedditUserInfo, UpsertableUser};
+use crate::schema::audit_log;
 use crate::{db::DbConn, model::User, sys::SysInfo};
-use chrono::Utc;
 use diesel_citext::types::CiString;
 use reqwest::header::{AUTHORIZATION, USER_AGENT};
 use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::outcome::Outcome;
 use rocket::request::{self, FromRequest, Request};
 use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Json;
 use rocket::time::Duration;
 use rocket::State;
 use rocket_oauth2::{OAuth2, TokenResponse};
@@ -39,33 +40,8 @@ pub(crate) async fn callback(
     config: &State<SysInfo>,
 ) -> Result<Redirect, Status> {
     // Get user's information from Reddit
-    let user_info: serde_json::Value = reqwest::Client::builder()
-        .build()
-        .map_err(|_| Status::BadRequest)?
-        .get("https://oauth.reddit.com/api/v1/me")
-        .header(AUTHORIZATION, format!("Bearer {}", token.access_token()))
-        .header(USER_AGENT, "AggieRiskLocal - Dev Edition")
-        .send()
-        .await
-        .map_err(|e| {
-            dbg!(e);
-            Status::InternalServerError
-        })?
-        .json()
-        .await
-        .map_err(|e| {
-            dbg!(e);
-            Status::InternalServerError
-        })?;
-
-    // This is a rather gross way of extracting the user's name
-    let uname: String = String::from(
-        user_info
-            .get("name")
-            .ok_or(Status::BadRequest)?
-            .as_str()
-            .ok_or(Status::InternalServerError)?,
-    );
+    let user_info = reddit_me(token.access_token()).await?;
+    let uname = reddit_name(&user_info)?;
 
     // Build the `UpsertableUser` for querying the DB
     let new_user = UpsertableUser {
@@ -91,19 +67,13 @@ pub(crate) async fn callback(
     // i.e. is the user banned from the platform?
     check_login(&user, &user_info, &cip.0, &conn).await?;
 
-    // Cookie is valid for 30 Days
-    // TODO: Pull this from Rocket settings...
-
-    let datetime = Utc::now();
-    let timestamp: usize = 2_592_000 + datetime.timestamp() as usize;
-
-    let new_claims = Claims {
-        id: user.id,
-        user: user.uname.to_string(),
-        token: Some(token.refresh_token().unwrap().to_string()),
-        refresh_token: Some(token.access_token().to_string()),
-        exp: timestamp,
-    };
+    // Cookie is valid for 30 Days, and can be renewed through /auth/reddit/refresh
+    let new_claims = Claims::new(
+        user.id,
+        user.uname.to_string(),
+        token.access_token(),
+        token.refresh_token(),
+    );
 
     // Now we build a private `Cookie` to return to the user
     // that contains the user's username (which is used in some low-sec processes)
@@ -134,6 +104,82 @@ pub(crate) async fn callback(
     }
 }
 
+/// # Refresh
+/// Renews the logged-in user's session before it expires. Reddit is asked for a new access
+/// token, and the account must still exist, not be suspended and still be the one the session
+/// is for. Returns when the renewed session expires, as a Unix timestamp.
+#[post("/reddit/refresh")]
+pub(crate) async fn refresh(
+    oauth2: OAuth2<RedditUserInfo>,
+    cookies: &CookieJar<'_>,
+    cip: Cip,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<usize>, Status> {
+    let (claims, _) =
+        Claims::from_private_cookie(cookies, config).map_err(|_| Status::Unauthorized)?;
+    let refresh_token = claims.refresh_token.clone().ok_or(Status::Unauthorized)?;
+    // Reddit refuses if the user has revoked our access or deleted their account
+    let token = oauth2
+        .refresh(&refresh_token)
+        .await
+        .map_err(|_| Status::Unauthorized)?;
+    let user_info = reddit_me(token.access_token()).await?;
+    let uname = reddit_name(&user_info)?;
+    if uname.to_lowercase() != claims.user.to_lowercase()
+        || user_info.get("is_suspended").and_then(Value::as_bool) == Some(true)
+    {
+        return Err(Status::Unauthorized);
+    }
+    let user = conn
+        .run(move |c| User::load(uname, "reddit".to_string(), c))
+        .await
+        .map_err(|_| Status::Unauthorized)?;
+    if user.id != claims.id {
+        return Err(Status::Unauthorized);
+    }
+    check_login(&user, &user_info, &cip.0, &conn).await?;
+
+    let claims = claims.renewed(token.access_token(), token.refresh_token());
+    claims
+        .to_private_cookies(cookies, config)
+        .map_err(|_| Status::InternalServerError)?;
+    std::result::Result::Ok(Json(claims.exp))
+}
+
+/// Gets the user's information from Reddit.
+async fn reddit_me(access_token: &str) -> Result<Value, Status> {
+    reqwest::Client::builder()
+        .build()
+        .map_err(|_| Status::BadRequest)?
+        .get("https://oauth.reddit.com/api/v1/me")
+        .header(AUTHORIZATION, format!("Bearer {access_token}"))
+        .header(USER_AGENT, "AggieRiskLocal - Dev Edition")
+        .send()
+        .await
+        .map_err(|e| {
+            dbg!(e);
+            Status::InternalServerError
+        })?
+        .json()
+        .await
+        .map_err(|e| {
+            dbg!(e);
+            Status::InternalServerError
+        })
+}
+
+// This is a rather gross way of extracting the user's name
+fn reddit_name(user_info: &Value) -> Result<String, Status> {
+    Ok(String::from(
+        user_info
+            .get("name")
+            .ok_or(Status::BadRequest)?
+            .as_str()
+            .ok_or(Status::InternalServerError)?,
+    ))
+}
+
 use diesel::prelude::*;
 pub(crate) async fn check_login(
     user_information: &User,
@@ -156,22 +202,14 @@ pub(crate) async fn check_login(
     })
     .await
     .map_err(|_| Status::InternalServerError)?;
-    // Return Ok() for now
-    Ok(())
-}
-
-table! {
-    audit_log (id) {
-        id -> Int4,
-        user_id -> Int4,
-        event -> Int4,
-        timestamp -> Timestamp,
-        data -> Nullable<Json>,
-        cip -> Nullable<Text>,
+    // The attempt is logged either way, but banned users don't get in
+    if user_information.banned {
+        return Err(Status::Forbidden);
     }
+    Ok(())
 }
 
-pub(crate) struct Cip(Option<String>);
+pub(crate) struct Cip(pub(crate) Option<String>);
 
 #[rocket::async_trait]
 impl<'a> FromRequest<'a> for Cip {
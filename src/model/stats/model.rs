@@ -1,11 +1,28 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
-use crate::schema::{heat_full, odds, statistics};
+use crate::game::Game;
+use crate::lottery;
+use crate::rules::Rules;
+use crate::schema::{
+    heat_full, odds, statistics, teams, territories, territory_ownership, turninfo, turns,
+};
 use diesel::prelude::*;
 use diesel::result::Error;
 use diesel_citext::types::CiString;
 use schemars::JsonSchema;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many times the forecast simulates the roll.
+const FORECAST_RUNS: u32 = 10_000;
+
+/// How many turns' forecasts are kept at once.
+const FORECAST_CACHE_TURNS: usize = 16;
+
+/// How many seconds apart a turn's forecasts are simulated when `risk.forecast.interval` is not set.
+pub(crate) const DEFAULT_FORECAST_INTERVAL: u64 = 30;
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub(crate) struct Stats {
@@ -88,6 +105,44 @@ pub(crate) struct Odds {
     pub(crate) chance: f64,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub(crate) struct Forecast {
+    pub(crate) season: i32,
+    pub(crate) day: i32,
+    pub(crate) runs: u32,
+    pub(crate) teams: Vec<TeamForecast>,
+    pub(crate) territories: Vec<TerritoryForecast>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub(crate) struct TeamForecast {
+    pub(crate) team: String,
+    pub(crate) expectedTerritories: f64,
+    pub(crate) percentiles: TerritoryPercentiles,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub(crate) struct TerritoryPercentiles {
+    pub(crate) p5: u32,
+    pub(crate) p25: u32,
+    pub(crate) p50: u32,
+    pub(crate) p75: u32,
+    pub(crate) p95: u32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub(crate) struct TerritoryForecast {
+    pub(crate) territory: String,
+    pub(crate) owner: String,
+    pub(crate) odds: Vec<TerritoryOdds>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub(crate) struct TerritoryOdds {
+    pub(crate) team: String,
+    pub(crate) chance: f64,
+}
+
 impl Heat {
     pub(crate) fn load(season: i32, day: i32, conn: &PgConnection) -> Vec<Heat> {
         heat_full::table
@@ -228,3 +283,200 @@ impl Odds {
             .load::<Odds>(conn)
     }
 }
+
+impl Forecast {
+    /// The id of the turn of `game` for `season`/`day`, and how many moves have been made on
+    /// it, which together say whether a cached forecast is still current.
+    pub(crate) fn turn(
+        game: &str,
+        season: i32,
+        day: i32,
+        conn: &PgConnection,
+    ) -> Result<(i32, i64), Error> {
+        let game = Game::load(game, conn)?;
+        let turn_id = turninfo::table
+            .filter(turninfo::game.eq(game.id))
+            .filter(turninfo::season.eq(season))
+            .filter(turninfo::day.eq(day))
+            .select(turninfo::id)
+            .first::<i32>(conn)?;
+        let moves = turns::table
+            .filter(turns::turn_id.eq(turn_id))
+            .count()
+            .get_result::<i64>(conn)?;
+        Ok((turn_id, moves))
+    }
+
+    /// Simulates the roll of turn `turn_id` (`season`/`day`) from the moves made so far, under
+    /// `strategy` and the season's `rules`.
+    pub(crate) fn load(
+        season: i32,
+        day: i32,
+        turn_id: i32,
+        strategy: lottery::Strategy,
+        rules: &Rules,
+        conn: &PgConnection,
+    ) -> Result<Forecast, Error> {
+        let owners = territory_ownership::table
+            .filter(territory_ownership::turn_id.eq(turn_id))
+            .select((
+                territory_ownership::territory_id,
+                territory_ownership::owner_id,
+            ))
+            .load::<(i32, i32)>(conn)?;
+        let moves = turns::table
+            .filter(turns::turn_id.eq(turn_id))
+            .select((
+                turns::territory,
+                turns::team,
+                turns::power,
+                turns::alt_score,
+            ))
+            .load::<(i32, i32, f64, i32)>(conn)?;
+        let team_names = teams::table
+            .select((teams::id, teams::tname))
+            .load::<(i32, CiString)>(conn)?
+            .into_iter()
+            .map(|(id, name)| (id, name.to_string()))
+            .collect::<BTreeMap<i32, String>>();
+        let territory_names = territories::table
+            .select((territories::id, territories::name))
+            .load::<(i32, CiString)>(conn)?
+            .into_iter()
+            .map(|(id, name)| (id, name.to_string()))
+            .collect::<BTreeMap<i32, String>>();
+
+        let contests = lottery::Contest::from_moves(&owners, &moves, rules.alt_cutoff);
+        let forecast = lottery::Forecast::simulate(
+            &contests,
+            strategy.victor_strategy().as_ref(),
+            FORECAST_RUNS,
+            &mut rand::thread_rng(),
+        );
+        let name = |names: &BTreeMap<i32, String>, id: &i32| {
+            names.get(id).cloned().unwrap_or_else(|| id.to_string())
+        };
+        Ok(Forecast {
+            season,
+            day,
+            runs: forecast.runs,
+            teams: forecast
+                .territory_counts
+                .keys()
+                .map(|team| TeamForecast {
+                    team: name(&team_names, team),
+                    expectedTerritories: forecast.expected(*team),
+                    percentiles: TerritoryPercentiles {
+                        p5: forecast.percentile(*team, 5),
+                        p25: forecast.percentile(*team, 25),
+                        p50: forecast.percentile(*team, 50),
+                        p75: forecast.percentile(*team, 75),
+                        p95: forecast.percentile(*team, 95),
+                    },
+                })
+                .collect(),
+            territories: contests
+                .iter()
+                .map(|contest| TerritoryForecast {
+                    territory: name(&territory_names, &contest.territory),
+                    owner: name(&team_names, &contest.owner),
+                    odds: forecast
+                        .wins
+                        .get(&contest.territory)
+                        .into_iter()
+                        .flatten()
+                        .map(|(team, wins)| TerritoryOdds {
+                            team: name(&team_names, team),
+                            chance: f64::from(*wins) / f64::from(forecast.runs),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Forecasts already simulated, so that each turn is only simulated again once more moves have
+/// been made on it, and then at most once every `interval`.
+pub(crate) struct ForecastCache {
+    interval: Duration,
+    /// Each turn's latest forecast, and how many moves it was simulated from
+    forecasts: Mutex<BTreeMap<i32, (i64, Forecast)>>,
+    /// When each turn's latest simulation started
+    last_runs: Mutex<BTreeMap<i32, Instant>>,
+}
+
+impl ForecastCache {
+    pub(crate) fn new(interval: Duration) -> ForecastCache {
+        ForecastCache {
+            interval,
+            forecasts: Mutex::new(BTreeMap::new()),
+            last_runs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The latest forecast of turn `turn_id`, and how many moves it was simulated from.
+    pub(crate) fn get(&self, turn_id: i32) -> Option<(i64, Forecast)> {
+        self.forecasts.lock().unwrap().get(&turn_id).cloned()
+    }
+
+    /// Claims a new simulation of turn `turn_id`, or returns false if its last one started less
+    /// than `interval` ago.
+    pub(crate) fn claim(&self, turn_id: i32) -> bool {
+        let now = Instant::now();
+        let mut last_runs = self.last_runs.lock().unwrap();
+        if matches!(last_runs.get(&turn_id), Some(last) if now.duration_since(*last) < self.interval)
+        {
+            return false;
+        }
+        last_runs.insert(turn_id, now);
+        while last_runs.len() > FORECAST_CACHE_TURNS {
+            last_runs.pop_first();
+        }
+        true
+    }
+
+    /// Keeps `forecast` as the latest of turn `turn_id`, dropping the oldest turns' forecasts
+    /// once there are too many.
+    pub(crate) fn insert(&self, turn_id: i32, moves: i64, forecast: Forecast) {
+        let mut forecasts = self.forecasts.lock().unwrap();
+        forecasts.insert(turn_id, (moves, forecast));
+        while forecasts.len() > FORECAST_CACHE_TURNS {
+            forecasts.pop_first();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forecast(day: i32) -> Forecast {
+        Forecast {
+            season: 1,
+            day,
+            runs: FORECAST_RUNS,
+            teams: Vec::new(),
+            territories: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_forecast_cache() {
+        let cache = ForecastCache::new(Duration::from_secs(3600));
+        assert!(cache.get(1).is_none());
+        assert!(cache.claim(1));
+        // Only one simulation of a turn per interval, which doesn't hold up other turns
+        assert!(!cache.claim(1));
+        assert!(cache.claim(2));
+        cache.insert(1, 4, forecast(1));
+        assert_eq!(Some((4, 1)), cache.get(1).map(|(moves, f)| (moves, f.day)));
+        for turn_id in 2..=(FORECAST_CACHE_TURNS as i32 + 1) {
+            cache.insert(turn_id, 0, forecast(turn_id));
+        }
+        // The oldest turn made way for the newest
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(FORECAST_CACHE_TURNS as i32 + 1).is_some());
+        assert!(ForecastCache::new(Duration::ZERO).claim(1));
+    }
+}
@@ -3,8 +3,13 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use crate::catchers::Status;
 use crate::db::DbConn;
-use crate::model::{CurrentStrength, Heat, Latest, Odds, StatHistory, StatLeaderboard};
+use crate::game::DEFAULT_GAME;
+use crate::model::{
+    CurrentStrength, Forecast, ForecastCache, Heat, Latest, Odds, StatHistory, StatLeaderboard,
+};
+use crate::rules::Rules;
 use rocket::serde::json::Json;
+use rocket::State;
 
 /// # Team Statistics
 /// Gives current team strength (from prior day's move).
@@ -22,12 +27,14 @@ pub(crate) async fn currentstrength(
 }
 
 /// # Leaderboard
-/// Provides team ranks on a given season/day, or on the prior day if season/day not provided.
+/// Provides team ranks on a given season/day, or on the game's prior day if season/day not
+/// provided.
 #[openapi(tag = "Stats", ignore = "conn")]
-#[get("/stats/leaderboard?<season>&<day>")]
+#[get("/stats/leaderboard?<season>&<day>&<game>")]
 pub(crate) async fn leaderboard(
     season: Option<i32>,
     day: Option<i32>,
+    game: Option<String>,
     conn: DbConn,
 ) -> Result<Json<Vec<StatLeaderboard>>, Status> {
     match (season, day) {
@@ -41,7 +48,8 @@ pub(crate) async fn leaderboard(
             }
         }
         _ => {
-            match conn.run(|c| Latest::latest(c)).await {
+            let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+            match conn.run(move |c| Latest::latest(&game, c)).await {
                 Ok(current) => {
                     //dbg!(&current.day - 1);
                     let leaderboard = conn
@@ -59,16 +67,18 @@ pub(crate) async fn leaderboard(
 }
 
 /// # Heat Map
-/// Information necessary to generate a heatmap of moves on a given day. Defaults to prior day if
-/// no season/day are specified.
+/// Information necessary to generate a heatmap of moves on a given day. Defaults to the game's
+/// prior day if no season/day are specified.
 #[openapi(tag = "Stats", ignore = "conn")]
-#[get("/heat?<season>&<day>")]
+#[get("/heat?<season>&<day>&<game>")]
 pub(crate) async fn heat(
     season: Option<i32>,
     day: Option<i32>,
+    game: Option<String>,
     conn: DbConn,
 ) -> Result<Json<Vec<Heat>>, Status> {
-    match conn.run(|c| Latest::latest(c)).await {
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    match conn.run(move |c| Latest::latest(&game, c)).await {
         Ok(current) => {
             let heat = conn
                 .run(move |c| {
@@ -130,3 +140,76 @@ pub(crate) async fn odds(
         }
     }
 }
+
+/// # Roll Forecast
+/// Simulates the roll for a given season/day many times over from the moves made so far, giving
+/// each team's expected territory count (with percentiles) and each territory's odds, under the
+/// season's territory resolution strategy.
+/// Defaults to the game's current day if season/day are not provided.
+///
+/// Forecasts are cached, and a turn is only simulated again once more moves have been made on
+/// it, and then at most once every `risk.forecast.interval` seconds; until then the last
+/// forecast of the turn stands. A turn's first forecast is always simulated.
+#[openapi(tag = "Stats", ignore = "conn")]
+#[get("/forecast?<season>&<day>&<game>")]
+pub(crate) async fn forecast(
+    season: Option<i32>,
+    day: Option<i32>,
+    game: Option<String>,
+    cache: &State<ForecastCache>,
+    conn: DbConn,
+) -> Result<Json<Forecast>, Status> {
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    let latest_game = game.clone();
+    match conn.run(move |c| Latest::latest(&latest_game, c)).await {
+        Ok(current) => {
+            let season = season.unwrap_or(current.season);
+            let day = day.unwrap_or(current.day);
+            let turn = conn
+                .run(move |c| Forecast::turn(&game, season, day, c))
+                .await;
+            let (turn_id, moves) = match turn {
+                Ok(turn) => turn,
+                _ => return std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
+            };
+            let cached = cache.get(turn_id);
+            match cached {
+                Some((seen, forecast)) if seen == moves => {
+                    return std::result::Result::Ok(Json(forecast))
+                }
+                Some((_, forecast)) if !cache.claim(turn_id) => {
+                    return std::result::Result::Ok(Json(forecast))
+                }
+                Some(_) => {}
+                // A turn's first forecast is simulated even while another request simulates it
+                None => {
+                    cache.claim(turn_id);
+                }
+            }
+            let rules = match conn.run(move |c| Rules::load(season, c)).await {
+                Ok(rules) => rules,
+                _ => return std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
+            };
+            let strategy = match rules.strategy() {
+                Ok(strategy) => strategy,
+                Err(e) => {
+                    eprintln!("Invalid strategy for season {season}: {e}");
+                    return std::result::Result::Err(Status(
+                        rocket::http::Status::InternalServerError,
+                    ));
+                }
+            };
+            let forecast = conn
+                .run(move |c| Forecast::load(season, day, turn_id, strategy, &rules, c))
+                .await;
+            match forecast {
+                Ok(forecast) => {
+                    cache.insert(turn_id, moves, forecast.clone());
+                    std::result::Result::Ok(Json(forecast))
+                }
+                _ => std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
+            }
+        }
+        _ => std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
+    }
+}
@@ -3,13 +3,14 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use crate::model::{PlayerInTurns, TeamInTurns};
 use crate::schema::{
-    regions, teams, territories, territory_ownership, territory_ownership_with_neighbors,
-    territory_ownership_without_neighbors, turninfo,
+    regions, teams, territories, territory_adjacency, territory_ownership,
+    territory_ownership_with_neighbors, territory_ownership_without_neighbors, turninfo,
 };
 use diesel::prelude::*;
 use diesel_citext::types::CiString;
 use schemars::JsonSchema;
 use serde_json::Value;
+use std::collections::BTreeMap;
 use std::result::Result;
 
 #[derive(Serialize, Queryable, Deserialize, JsonSchema)]
@@ -47,6 +48,18 @@ pub(crate) struct TerritoryTurn {
     pub(crate) players: Vec<PlayerInTurns>,
 }
 
+/// A chaos bridge open on a turn, added by the ringmaster between the chaos territory and
+/// another territory.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub(crate) struct ChaosBridge {
+    pub(crate) territoryId: i32,
+    pub(crate) territory: String,
+    pub(crate) adjacentId: i32,
+    pub(crate) adjacent: String,
+    /// How many turns after this one the bridge stays open for
+    pub(crate) turnsLeft: i32,
+}
+
 impl TerritoryWithNeighbors {
     pub(crate) fn load(
         season: i32,
@@ -175,3 +188,45 @@ impl TerritoryTurn {
         }
     }
 }
+
+impl ChaosBridge {
+    pub(crate) fn load(
+        season: i32,
+        day: i32,
+        conn: &mut PgConnection,
+    ) -> Result<Vec<ChaosBridge>, diesel::result::Error> {
+        let turn_id = turninfo::table
+            .select(turninfo::id)
+            .filter(turninfo::season.eq(season))
+            .filter(turninfo::day.eq(day))
+            .first::<i32>(conn)?;
+        let bridges = territory_adjacency::table
+            .select((
+                territory_adjacency::territory_id,
+                territory_adjacency::adjacent_id,
+                territory_adjacency::max_turn,
+            ))
+            .filter(territory_adjacency::note.eq("chaos_auto_managed"))
+            .filter(territory_adjacency::min_turn.lt(turn_id))
+            .filter(territory_adjacency::max_turn.ge(turn_id))
+            .order_by(territory_adjacency::id)
+            .load::<(i32, i32, i32)>(conn)?;
+        let names = territories::table
+            .select((territories::id, territories::name))
+            .load::<(i32, CiString)>(conn)?
+            .into_iter()
+            .map(|(id, name)| (id, name.to_string()))
+            .collect::<BTreeMap<i32, String>>();
+        let name = |id: i32| names.get(&id).cloned().unwrap_or_default();
+        Ok(bridges
+            .into_iter()
+            .map(|(territory, adjacent, max_turn)| ChaosBridge {
+                territoryId: territory,
+                territory: name(territory),
+                adjacentId: adjacent,
+                adjacent: name(adjacent),
+                turnsLeft: max_turn - turn_id,
+            })
+            .collect())
+    }
+}
@@ -4,19 +4,22 @@
 
 use crate::catchers::Status;
 use crate::db::DbConn;
-use crate::model::{Latest, TerritoryHistory, TerritoryTurn, TerritoryWithNeighbors};
+use crate::game::DEFAULT_GAME;
+use crate::model::{ChaosBridge, Latest, TerritoryHistory, TerritoryTurn, TerritoryWithNeighbors};
 use rocket::serde::json::Json;
 
 /// # Territory Ownership
-/// Gives territory ownership information
+/// Gives territory ownership information, defaulting to the game's current day
 #[openapi(tag = "Territories", ignore = "conn")]
-#[get("/territories?<day>&<season>")]
+#[get("/territories?<day>&<season>&<game>")]
 pub(crate) async fn territories(
     season: Option<i32>,
     day: Option<i32>,
+    game: Option<String>,
     conn: DbConn,
 ) -> Result<Json<Vec<TerritoryWithNeighbors>>, Status> {
-    match conn.run(move |c| Latest::latest(c)).await {
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    match conn.run(move |c| Latest::latest(&game, c)).await {
         Ok(current) => {
             let territories = conn
                 .run(move |c| {
@@ -74,3 +77,35 @@ pub(crate) async fn territory_turn(
         _ => std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
     }
 }
+
+/// # Chaos Bridges
+/// Lists the chaos bridges open on a given day, defaulting to the game's current day. Bridges
+/// that closed a while ago are cleaned up by the ringmaster, so older days may come back empty.
+#[openapi(tag = "Territories", ignore = "conn")]
+#[get("/chaos/bridges?<season>&<day>&<game>")]
+pub(crate) async fn chaos_bridges(
+    season: Option<i32>,
+    day: Option<i32>,
+    game: Option<String>,
+    conn: DbConn,
+) -> Result<Json<Vec<ChaosBridge>>, Status> {
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    match conn.run(move |c| Latest::latest(&game, c)).await {
+        Ok(current) => {
+            let bridges = conn
+                .run(move |c| {
+                    ChaosBridge::load(
+                        season.unwrap_or(current.season),
+                        day.unwrap_or(current.day),
+                        c,
+                    )
+                })
+                .await;
+            match bridges {
+                Ok(bridges) => std::result::Result::Ok(Json(bridges)),
+                _ => std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
+            }
+        }
+        _ => std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
+    }
+}
@@ -0,0 +1,248 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Personal API tokens, which let scripts and bots act for a user without the `jwt` cookie.
+//!
+//! A token is sent as `Authorization: Bearer <token>`. Only its SHA-256 hash is stored, so a
+//! token can only be seen when it is minted. Each token's scopes limit what it may be used
+//! for, and every request made with it is logged in `api_token_uses`.
+
+use crate::schema::{api_token_uses, api_tokens, users};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel_citext::types::CiString;
+use rand::Rng;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use sha2::{Digest, Sha256};
+
+/// Starts every token, so that leaked tokens are easy to spot.
+const TOKEN_PREFIX: &str = "rr_";
+
+/// What a token may be used for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Scope {
+    /// Reading the user's own information, such as `/auth/me`
+    Read,
+    /// Making moves, joining a team and answering polls. Includes `Read`.
+    Move,
+}
+
+/// A token as its owner sees it. The token itself is only handed out when it is minted.
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct ApiToken {
+    pub(crate) id: i32,
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) created_at: NaiveDateTime,
+    pub(crate) last_used_at: Option<NaiveDateTime>,
+}
+
+/// A newly minted token, including the token to send as `Authorization: Bearer <token>`.
+#[derive(Serialize, Debug)]
+pub(crate) struct MintedToken {
+    #[serde(flatten)]
+    pub(crate) info: ApiToken,
+    pub(crate) token: String,
+}
+
+/// The token sent with a request in the `Authorization: Bearer` header, if any.
+pub(crate) struct Bearer(pub(crate) Option<String>);
+
+#[rocket::async_trait]
+impl<'a> FromRequest<'a> for Bearer {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Bearer(
+            request
+                .headers()
+                .get_one("Authorization")
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .map(|token| token.trim().to_string()),
+        ))
+    }
+}
+
+impl Scope {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Move => "move",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Scope> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "move" => Some(Scope::Move),
+            _ => None,
+        }
+    }
+
+    /// Whether a token with `scopes` may be used for this.
+    pub(crate) fn allowed_by(self, scopes: &[Scope]) -> bool {
+        scopes.contains(&self) || (self == Scope::Read && scopes.contains(&Scope::Move))
+    }
+}
+
+impl ApiToken {
+    /// Mints a token called `name` for `user_id`.
+    pub(crate) fn mint(
+        user_id: i32,
+        name: String,
+        scopes: Vec<Scope>,
+        conn: &PgConnection,
+    ) -> QueryResult<MintedToken> {
+        let token = generate();
+        let (id, created_at) = diesel::insert_into(api_tokens::table)
+            .values((
+                api_tokens::user_id.eq(user_id),
+                api_tokens::name.eq(&name),
+                api_tokens::token_hash.eq(hash(&token)),
+                api_tokens::scopes.eq(scope_names(&scopes)),
+            ))
+            .returning((api_tokens::id, api_tokens::created_at))
+            .get_result::<(i32, NaiveDateTime)>(conn)?;
+        Ok(MintedToken {
+            info: ApiToken {
+                id,
+                name,
+                scopes,
+                created_at,
+                last_used_at: None,
+            },
+            token,
+        })
+    }
+
+    /// Lists the tokens `user_id` has minted and not revoked.
+    pub(crate) fn load(user_id: i32, conn: &PgConnection) -> QueryResult<Vec<ApiToken>> {
+        Ok(api_tokens::table
+            .filter(api_tokens::user_id.eq(user_id))
+            .filter(api_tokens::revoked_at.is_null())
+            .select((
+                api_tokens::id,
+                api_tokens::name,
+                api_tokens::scopes,
+                api_tokens::created_at,
+                api_tokens::last_used_at,
+            ))
+            .order_by(api_tokens::id)
+            .load::<(
+                i32,
+                String,
+                Vec<String>,
+                NaiveDateTime,
+                Option<NaiveDateTime>,
+            )>(conn)?
+            .into_iter()
+            .map(|(id, name, scopes, created_at, last_used_at)| ApiToken {
+                id,
+                name,
+                scopes: scopes.iter().filter_map(|s| Scope::parse(s)).collect(),
+                created_at,
+                last_used_at,
+            })
+            .collect())
+    }
+
+    /// Revokes the token `id`, if it belongs to `user_id`. Returns how many tokens were revoked.
+    pub(crate) fn revoke(user_id: i32, id: i32, conn: &PgConnection) -> QueryResult<usize> {
+        diesel::update(
+            api_tokens::table
+                .filter(api_tokens::id.eq(id))
+                .filter(api_tokens::user_id.eq(user_id))
+                .filter(api_tokens::revoked_at.is_null()),
+        )
+        .set(api_tokens::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+    }
+
+    /// Finds the user a request made with `token` is for, and logs the request under `route`.
+    /// The token must not have been revoked, and must allow `scope`. Returns the user's id
+    /// and name.
+    pub(crate) fn authenticate(
+        token: &str,
+        scope: Scope,
+        route: &str,
+        conn: &PgConnection,
+    ) -> Result<(i32, String), crate::Error> {
+        let (token_id, scopes, user_id, name) = api_tokens::table
+            .inner_join(users::table.on(users::id.eq(api_tokens::user_id)))
+            .filter(api_tokens::token_hash.eq(hash(token)))
+            .filter(api_tokens::revoked_at.is_null())
+            .select((api_tokens::id, api_tokens::scopes, users::id, users::uname))
+            .first::<(i32, Vec<String>, i32, CiString)>(conn)
+            .map_err(|_| crate::Error::Unauthorized {})?;
+        let scopes = scopes
+            .iter()
+            .filter_map(|s| Scope::parse(s))
+            .collect::<Vec<Scope>>();
+        if !scope.allowed_by(&scopes) {
+            return Err(crate::Error::Forbidden {});
+        }
+        diesel::insert_into(api_token_uses::table)
+            .values((
+                api_token_uses::token_id.eq(token_id),
+                api_token_uses::route.eq(route),
+            ))
+            .execute(conn)?;
+        diesel::update(api_tokens::table.filter(api_tokens::id.eq(token_id)))
+            .set(api_tokens::last_used_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+        Ok((user_id, name.to_string()))
+    }
+}
+
+/// A new token: the prefix followed by 32 random bytes in hex.
+fn generate() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    format!("{TOKEN_PREFIX}{}", to_hex(&bytes))
+}
+
+/// The SHA-256 hash of a token, as kept in `api_tokens.token_hash`.
+fn hash(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn scope_names(scopes: &[Scope]) -> Vec<String> {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes() {
+        assert!(Scope::Read.allowed_by(&[Scope::Read]));
+        assert!(Scope::Read.allowed_by(&[Scope::Move]));
+        assert!(!Scope::Move.allowed_by(&[Scope::Read]));
+        assert!(!Scope::Read.allowed_by(&[]));
+        for scope in [Scope::Read, Scope::Move] {
+            assert_eq!(Scope::parse(scope.as_str()), Some(scope));
+        }
+        assert_eq!(Scope::parse("admin"), None);
+    }
+
+    #[test]
+    fn test_tokens() {
+        let (a, b) = (generate(), generate());
+        assert_ne!(a, b);
+        assert!(a.starts_with(TOKEN_PREFIX));
+        assert_eq!(a.len(), TOKEN_PREFIX.len() + 64);
+        assert_eq!(hash(&a), hash(&a));
+        assert_ne!(hash(&a), hash(&b));
+        assert_eq!(hash(&a).len(), 64);
+    }
+}
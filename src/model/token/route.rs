@@ -0,0 +1,71 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::db::DbConn;
+use crate::model::token::{ApiToken, MintedToken, Scope};
+use crate::model::Claims;
+use crate::sys::SysInfo;
+use rocket::http::CookieJar;
+use rocket::serde::json::Json;
+use rocket::State;
+
+#[derive(Deserialize)]
+pub(crate) struct NewToken {
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<Scope>,
+}
+
+/// # Mint Token
+/// Mints a personal API token for the logged-in user. The token is only ever shown in this
+/// response. Tokens can't be used to mint more tokens.
+#[post("/tokens", format = "application/json", data = "<new>")]
+pub(crate) async fn mint_token(
+    new: Json<NewToken>,
+    cookies: &CookieJar<'_>,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<MintedToken>, crate::Error> {
+    let c = Claims::from_private_cookie(cookies, config)?;
+    let NewToken { name, scopes } = new.into_inner();
+    if name.trim().is_empty() || scopes.is_empty() {
+        return Err(crate::Error::BadRequest {});
+    }
+    conn.run(move |connection| ApiToken::mint(c.0.id, name, scopes, connection))
+        .await
+        .map(Json)
+        .map_err(|_| crate::Error::InternalServerError {})
+}
+
+/// # Tokens
+/// Lists the logged-in user's API tokens that haven't been revoked.
+#[get("/tokens")]
+pub(crate) async fn tokens(
+    cookies: &CookieJar<'_>,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<Vec<ApiToken>>, crate::Error> {
+    let c = Claims::from_private_cookie(cookies, config)?;
+    conn.run(move |connection| ApiToken::load(c.0.id, connection))
+        .await
+        .map(Json)
+        .map_err(|_| crate::Error::InternalServerError {})
+}
+
+/// # Revoke Token
+/// Revokes one of the logged-in user's API tokens.
+#[delete("/tokens/<id>")]
+pub(crate) async fn revoke_token(
+    id: i32,
+    cookies: &CookieJar<'_>,
+    conn: DbConn,
+    config: &State<SysInfo>,
+) -> Result<Json<String>, crate::Error> {
+    let c = Claims::from_private_cookie(cookies, config)?;
+    match conn
+        .run(move |connection| ApiToken::revoke(c.0.id, id, connection))
+        .await?
+    {
+        0 => Err(crate::Error::NotFound {}),
+        _ => std::result::Result::Ok(Json(String::from("Okay"))),
+    }
+}
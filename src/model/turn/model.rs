@@ -2,9 +2,11 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use crate::schema::{rollinfo, turninfo};
+use crate::game::Game;
+use crate::schema::{rollinfo, season_winners, teams, turninfo};
 use diesel::prelude::*;
 use diesel::result::Error;
+use diesel_citext::types::CiString;
 use schemars::JsonSchema;
 use serde_json::Value;
 
@@ -46,6 +48,8 @@ pub(crate) struct TurnInfo {
     pub(crate) rollTime: Option<crate::catchers::NaiveDateTime>,
     pub(crate) allOrNothingEnabled: Option<bool>,
     pub(crate) map: Option<String>,
+    pub(crate) seedCommitment: Option<String>,
+    pub(crate) seed: Option<String>,
 }
 
 #[derive(Queryable, Serialize, Deserialize, JsonSchema, Clone)]
@@ -56,17 +60,44 @@ pub struct Latest {
     pub(crate) id: i32,
 }
 
+/// A game hosted on this server, and its latest turn.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub(crate) struct GameInfo {
+    pub(crate) name: String,
+    pub(crate) description: Option<String>,
+    pub(crate) season: Option<i32>,
+    pub(crate) day: Option<i32>,
+}
+
 #[derive(Serialize, Queryable, Deserialize, JsonSchema)]
 pub(crate) struct Roll {
     pub(crate) startTime: String,
     pub(crate) endTime: String,
     pub(crate) chaosRerolls: i32,
     pub(crate) chaosWeight: i32,
+    pub(crate) seedCommitment: Option<String>,
+    pub(crate) seed: Option<String>,
     pub(crate) territoryRolls: Value,
 }
 
+/// A team that won a season. `reason` is `conquest` (the team owned the whole map),
+/// `elimination` (every other team was eliminated) or `final_day` (the team owned the most
+/// territories after the season's last day).
+#[derive(Queryable, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct SeasonWinner {
+    pub(crate) season: i32,
+    pub(crate) day: i32,
+    pub(crate) team: CiString,
+    pub(crate) territories: i32,
+    pub(crate) reason: String,
+}
+
 impl TurnInfo {
-    pub(crate) fn load(conn: &PgConnection) -> Vec<TurnInfo> {
+    pub(crate) fn load(game: &str, conn: &PgConnection) -> Vec<TurnInfo> {
+        let game = match Game::load(game, conn) {
+            Ok(game) => game,
+            Err(_) => return Vec::new(),
+        };
         turninfo::table
             .select((
                 turninfo::id,
@@ -78,14 +109,24 @@ impl TurnInfo {
                 turninfo::rollstarttime,
                 turninfo::allornothingenabled,
                 turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
             ))
+            .filter(turninfo::game.eq(game.id))
             .filter(turninfo::complete.eq(true).or(turninfo::active.eq(true)))
             .order_by(turninfo::id.desc()) // always desc so downstream know how to parse this consistently
             .load::<TurnInfo>(conn)
             .expect("Error loading TurnInfo")
+            .into_iter()
+            .map(TurnInfo::conceal_seed)
+            .collect()
     }
 
-    pub(crate) fn loadall(conn: &PgConnection) -> Vec<TurnInfo> {
+    pub(crate) fn loadall(game: &str, conn: &PgConnection) -> Vec<TurnInfo> {
+        let game = match Game::load(game, conn) {
+            Ok(game) => game,
+            Err(_) => return Vec::new(),
+        };
         turninfo::table
             .select((
                 turninfo::id,
@@ -97,13 +138,24 @@ impl TurnInfo {
                 turninfo::rollstarttime,
                 turninfo::allornothingenabled,
                 turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
             ))
+            .filter(turninfo::game.eq(game.id))
             .order_by(turninfo::id)
             .load::<TurnInfo>(conn)
             .expect("Error loading TurnInfo")
+            .into_iter()
+            .map(TurnInfo::conceal_seed)
+            .collect()
     }
 
-    pub(crate) fn latest(conn: &PgConnection) -> Result<TurnInfo, diesel::result::Error> {
+    /// The active turn of the game called `game`.
+    pub(crate) fn latest(
+        game: &str,
+        conn: &PgConnection,
+    ) -> Result<TurnInfo, diesel::result::Error> {
+        let game = Game::load(game, conn)?;
         turninfo::table
             .select((
                 turninfo::id,
@@ -115,22 +167,59 @@ impl TurnInfo {
                 turninfo::rollstarttime,
                 turninfo::allornothingenabled,
                 turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
             ))
             .filter(turninfo::active.eq(Some(true)))
+            .filter(turninfo::game.eq(game.id))
             .order_by(turninfo::id.desc())
             .first::<TurnInfo>(conn)
+            .map(TurnInfo::conceal_seed)
+    }
+
+    /// The seed is only revealed once the roll is complete; until then only the commitment is public.
+    fn conceal_seed(mut self) -> TurnInfo {
+        if self.complete != Some(true) {
+            self.seed = None;
+        }
+        self
     }
 }
 
 impl Latest {
-    pub(crate) fn latest(conn: &PgConnection) -> Result<Latest, diesel::result::Error> {
+    /// The latest turn of the game called `game`.
+    pub(crate) fn latest(game: &str, conn: &PgConnection) -> Result<Latest, diesel::result::Error> {
+        let game = Game::load(game, conn)?;
         turninfo::table
             .select((turninfo::season, turninfo::day, turninfo::id))
+            .filter(turninfo::game.eq(game.id))
             .order(turninfo::id.desc())
             .first::<Latest>(conn)
     }
 }
 
+impl GameInfo {
+    pub(crate) fn load(conn: &PgConnection) -> Result<Vec<GameInfo>, Error> {
+        Game::load_all(conn)?
+            .into_iter()
+            .map(|game| {
+                let latest = turninfo::table
+                    .select((turninfo::season, turninfo::day))
+                    .filter(turninfo::game.eq(game.id))
+                    .order(turninfo::id.desc())
+                    .first::<(i32, i32)>(conn)
+                    .optional()?;
+                Ok(GameInfo {
+                    name: game.name,
+                    description: game.description,
+                    season: latest.map(|(season, _)| season),
+                    day: latest.map(|(_, day)| day),
+                })
+            })
+            .collect()
+    }
+}
+
 impl Roll {
     pub(crate) fn load(season: i32, day: i32, conn: &PgConnection) -> Result<Roll, Error> {
         rollinfo::table
@@ -139,6 +228,8 @@ impl Roll {
                 rollinfo::rollendtime,
                 rollinfo::chaosrerolls,
                 rollinfo::chaosweight,
+                rollinfo::seed_commitment,
+                rollinfo::seed,
                 rollinfo::json_agg,
             ))
             .filter(rollinfo::day.eq(day))
@@ -146,3 +237,26 @@ impl Roll {
             .first::<Roll>(conn)
     }
 }
+
+impl SeasonWinner {
+    pub(crate) fn load(
+        season: Option<i32>,
+        conn: &PgConnection,
+    ) -> Result<Vec<SeasonWinner>, Error> {
+        let mut query = season_winners::table
+            .inner_join(teams::table)
+            .select((
+                season_winners::season,
+                season_winners::day,
+                teams::tname,
+                season_winners::territories,
+                season_winners::reason,
+            ))
+            .order_by((season_winners::season.desc(), teams::tname))
+            .into_boxed();
+        if let Some(season) = season {
+            query = query.filter(season_winners::season.eq(season));
+        }
+        query.load::<SeasonWinner>(conn)
+    }
+}
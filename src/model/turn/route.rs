@@ -4,14 +4,33 @@
 
 use crate::catchers::Status;
 use crate::db::DbConn;
-use crate::model::{Roll, TurnInfo};
+use crate::game::DEFAULT_GAME;
+use crate::model::{GameInfo, Roll, SeasonWinner, TurnInfo};
 use rocket::serde::json::Json;
+
+/// # List of Games
+/// Returns every game hosted on this server, with its latest season and day. Routes that default
+/// to the current turn take a `game`, and use the `default` game if none is given.
+#[openapi(tag = "Turns", ignore = "conn")]
+#[get("/games")]
+pub(crate) async fn games(conn: DbConn) -> Result<Json<Vec<GameInfo>>, Status> {
+    match conn.run(|c| GameInfo::load(c)).await {
+        Ok(games) => std::result::Result::Ok(Json(games)),
+        _ => std::result::Result::Err(Status(rocket::http::Status::InternalServerError)),
+    }
+}
+
 /// # List of Turns
-/// Returns information about all past and present. Eventually will allow filtering by season.
+/// Returns information about all past and present turns in a game. Eventually will allow
+/// filtering by season.
 #[openapi(tag = "Turns", ignore = "conn")]
-#[get("/turns")]
-pub(crate) async fn turns(conn: DbConn) -> Result<Json<Vec<TurnInfo>>, Status> {
-    let turns = conn.run(|c| TurnInfo::load(c)).await;
+#[get("/turns?<game>")]
+pub(crate) async fn turns(
+    game: Option<String>,
+    conn: DbConn,
+) -> Result<Json<Vec<TurnInfo>>, Status> {
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    let turns = conn.run(move |c| TurnInfo::load(&game, c)).await;
     if turns.len() as i32 >= 1 {
         std::result::Result::Ok(Json(turns))
     } else {
@@ -20,11 +39,15 @@ pub(crate) async fn turns(conn: DbConn) -> Result<Json<Vec<TurnInfo>>, Status> {
 }
 
 /// # List of Turns
-/// Returns information about all past, present, and upcoming turns.
+/// Returns information about all past, present, and upcoming turns in a game.
 #[openapi(tag = "Turns", ignore = "conn")]
-#[get("/turns/all")]
-pub(crate) async fn all_turns(conn: DbConn) -> Result<Json<Vec<TurnInfo>>, Status> {
-    let turns = conn.run(|c| TurnInfo::loadall(c)).await;
+#[get("/turns/all?<game>")]
+pub(crate) async fn all_turns(
+    game: Option<String>,
+    conn: DbConn,
+) -> Result<Json<Vec<TurnInfo>>, Status> {
+    let game = game.unwrap_or_else(|| String::from(DEFAULT_GAME));
+    let turns = conn.run(move |c| TurnInfo::loadall(&game, c)).await;
     if turns.len() as i32 >= 1 {
         std::result::Result::Ok(Json(turns))
     } else {
@@ -34,7 +57,8 @@ pub(crate) async fn all_turns(conn: DbConn) -> Result<Json<Vec<TurnInfo>>, Statu
 
 /// # Audit Log
 /// List of random numbers used to determine victors on a given day. Returns 502 error if no day
-/// specified.
+/// specified. Also reveals the day's seed, which must hash (SHA-256) to the published
+/// `seedCommitment` and reproduces every random number when used to seed ChaCha12.
 #[openapi(tag = "Turns", ignore = "conn")]
 #[get("/roll/log?<season>&<day>")]
 pub(crate) async fn rolllog(season: i32, day: i32, conn: DbConn) -> Result<Json<Roll>, Status> {
@@ -44,3 +68,20 @@ pub(crate) async fn rolllog(season: i32, day: i32, conn: DbConn) -> Result<Json<
         _ => std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
     }
 }
+
+/// # Season Winners
+/// Returns the winners of every finished season, or only of `season` if given. Teams tied for the
+/// most territories at the end of a season are all listed as winners.
+#[openapi(tag = "Turns", ignore = "conn")]
+#[get("/winners?<season>")]
+pub(crate) async fn winners(
+    season: Option<i32>,
+    conn: DbConn,
+) -> Result<Json<Vec<SeasonWinner>>, Status> {
+    let winners = conn.run(move |c| SeasonWinner::load(season, c)).await;
+    match winners {
+        Ok(winners) if !winners.is_empty() => std::result::Result::Ok(Json(winners)),
+        Ok(_) => std::result::Result::Err(Status(rocket::http::Status::NotFound)),
+        _ => std::result::Result::Err(Status(rocket::http::Status::BadRequest)),
+    }
+}
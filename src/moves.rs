@@ -0,0 +1,92 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Which moves a team may make, shared by the server (which checks each move as it is made)
+//! and the ringmaster's in-memory store (which lets the check be tested without a database).
+
+use std::fmt;
+
+/// Why a move was turned down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveError {
+    /// The team owns neither the target nor any territory next to it
+    NotAdjacent,
+    /// The team owns the target and every territory next to it, so there is nothing to defend
+    Surrounded,
+    /// During the playoffs, the target is held by a team outside the team's bracket
+    OtherBracket,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MoveError::NotAdjacent => "You don't own that territory or an adjacent one",
+            MoveError::Surrounded => "You own all the surrounding territories",
+            MoveError::OtherBracket => "That territory is held by a team in another bracket",
+        })
+    }
+}
+
+/// Checks a move by `team` on `target`, given the `(owner, territory)` of each territory
+/// adjacent to the target. Every territory is adjacent to itself, so the target is among them.
+///
+/// Returns whether the move defends a territory the team already owns.
+pub fn check_move(
+    team: i32,
+    target: i32,
+    adjacent_owners: &[(i32, i32)],
+) -> Result<bool, MoveError> {
+    if !adjacent_owners.iter().any(|&(owner, _)| owner == team) {
+        return Err(MoveError::NotAdjacent);
+    }
+    if adjacent_owners.iter().all(|&(owner, _)| owner == team) {
+        return Err(MoveError::Surrounded);
+    }
+    Ok(adjacent_owners
+        .iter()
+        .any(|&(owner, territory)| territory == target && owner == team))
+}
+
+/// Checks that a move by `team` on a territory held by `owner` stays within the team's playoff
+/// bracket. Outside of the playoffs there are no `brackets`, and any team may be attacked.
+pub fn check_bracket(team: i32, owner: i32, brackets: &[Vec<i32>]) -> Result<(), MoveError> {
+    if brackets.is_empty()
+        || brackets
+            .iter()
+            .any(|teams| teams.contains(&team) && teams.contains(&owner))
+    {
+        Ok(())
+    } else {
+        Err(MoveError::OtherBracket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_move() {
+        // Territory 1 borders 2 and 3
+        let adjacent = [(5, 1), (5, 2), (6, 3)];
+        assert_eq!(check_move(5, 1, &adjacent), Ok(true));
+        assert_eq!(check_move(6, 1, &adjacent), Ok(false));
+        assert_eq!(check_move(7, 1, &adjacent), Err(MoveError::NotAdjacent));
+        assert_eq!(
+            check_move(5, 1, &[(5, 1), (5, 2), (5, 3)]),
+            Err(MoveError::Surrounded)
+        );
+    }
+
+    #[test]
+    fn test_check_bracket() {
+        let brackets = [vec![1, 2], vec![3, 4]];
+        assert_eq!(check_bracket(1, 2, &brackets), Ok(()));
+        assert_eq!(check_bracket(1, 1, &brackets), Ok(()));
+        assert_eq!(check_bracket(1, 3, &brackets), Err(MoveError::OtherBracket));
+        // Teams knocked out of the playoffs have no bracket left to play in
+        assert_eq!(check_bracket(5, 1, &brackets), Err(MoveError::OtherBracket));
+        assert_eq!(check_bracket(1, 3, &[]), Ok(()));
+    }
+}
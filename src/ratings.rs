@@ -0,0 +1,20 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Player ratings, shared by the server (which reports ratings) and the ringmaster
+//! (which stores each user's `overall` after a roll). The thresholds for each star are
+//! part of each season's [`Rules`](crate::rules::Rules).
+
+/// The number of stars `num` earns against `thresholds`.
+#[must_use]
+pub fn stars(num: i32, thresholds: &[i32]) -> i32 {
+    thresholds.iter().take_while(|&&x| x <= num).count() as i32
+}
+
+/// The overall rating: the median of the individual ratings, rounded.
+#[must_use]
+pub fn overall(mut ratings: [i32; 4]) -> i32 {
+    ratings.sort_unstable();
+    ((ratings[1] as f32 + ratings[2] as f32) / 2_f32).round() as i32
+}
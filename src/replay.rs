@@ -0,0 +1,189 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Replays a completed roll from its revealed seed and compares the result with
+//! what was written to `territory_ownership`, `territory_stats` and `stats`.
+//!
+//! The moves in `turns` and the previous day's owners are the roll's only inputs
+//! besides the seed, so a faithful roll replays to exactly the same rows.
+
+use crate::rules::Rules;
+use crate::seed;
+use crate::structs::{
+    PlayerMoves, PlayoffBracket, Stats, TerritoryOwners, TerritoryOwnersInsert, TerritoryStats,
+    TurnInfo,
+};
+use diesel::pg::PgConnection;
+use rocket::figment::Figment;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+/// Replays the roll of `game` for `season`/`day`, returning a description of every
+/// difference between the replay and the recorded roll.
+pub fn replay(
+    game: i32,
+    season: i32,
+    day: i32,
+    settings: &Figment,
+    conn: &PgConnection,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let turninfoblock = TurnInfo::get(game, season, day, conn)?;
+    if turninfoblock.complete != Some(true) {
+        return Err(format!("Season {season} day {day} has not been rolled").into());
+    }
+    if turninfoblock.playoffs == Some(true) {
+        return Err(format!(
+            "Season {season} day {day} drew up playoff brackets instead of rolling"
+        )
+        .into());
+    }
+    let seed = turninfoblock.seed.as_deref().ok_or_else(|| {
+        format!("Season {season} day {day} was rolled before seeds were recorded")
+    })?;
+    let mut discrepancies = Vec::new();
+    match &turninfoblock.seed_commitment {
+        Some(commitment) if !seed::verify(seed, commitment) => discrepancies.push(format!(
+            "seed {seed} does not match the commitment {commitment}"
+        )),
+        _ => {}
+    }
+
+    let territories = TerritoryOwners::load(&turninfoblock.id, conn)?;
+    let players = crate::bracket_moves(
+        PlayerMoves::load(&turninfoblock.id, conn)?,
+        &territories,
+        &PlayoffBracket::load(season, turninfoblock.id, conn)?,
+    );
+    let recorded_mvps = players
+        .iter()
+        .filter(|player| player.mvp)
+        .map(|player| player.id)
+        .collect::<Vec<i32>>();
+    // Rolls are replayed with the rules they were made with
+    let rules = Rules::load(season, conn)?;
+    let strategy = rules.strategy()?.victor_strategy();
+    // The new owners belong to the day the roll created, which another game's turns may
+    // have been created ahead of
+    let next = turninfoblock
+        .get_next(conn)
+        .map_err(|e| format!("Could not load the day after season {season} day {day}: {e}"))?;
+    let mut rng = seed::rng(seed)?;
+    let (mut owners, mvps, mut stats, territory_stats) = crate::process_territories(
+        territories,
+        players,
+        next.id,
+        &mut rng,
+        strategy.as_ref(),
+        rules.alt_cutoff,
+        false,
+    );
+    // Who had committed players at the time of the roll isn't kept, so the teams that
+    // respawned come from the recorded stats, and only their territories are drawn again.
+    let recorded_stats = Stats::load(turninfoblock.id, conn)?;
+    let respawned = recorded_stats
+        .iter()
+        .filter(|stat| stat.respawned)
+        .map(|stat| (stat.team, 0))
+        .collect::<BTreeMap<i32, i32>>();
+    crate::respawn(
+        &mut owners,
+        &mut stats,
+        &respawned,
+        0,
+        &crate::neutral_teams(settings),
+        &mut rng,
+    );
+
+    discrepancies.extend(diff(
+        "territory_ownership",
+        owners,
+        TerritoryOwnersInsert::load(next.id, conn)?,
+        |owner| owner.territory_id,
+    ));
+    discrepancies.extend(diff(
+        "territory_stats",
+        territory_stats,
+        TerritoryStats::load(turninfoblock.id, conn)?,
+        |stat| (stat.territory, stat.team),
+    ));
+    discrepancies.extend(diff(
+        "stats",
+        Stats::rank(&stats, turninfoblock.id),
+        recorded_stats,
+        |stat| stat.team,
+    ));
+    discrepancies.extend(diff(
+        "turns.mvp",
+        mvps.iter().map(|mvp| mvp.id).collect(),
+        recorded_mvps,
+        |id| *id,
+    ));
+    Ok(discrepancies)
+}
+
+/// Pairs up `replayed` and `recorded` rows by `key`, describing each row that
+/// differs or is only present on one side. Rows sharing a key would hide each other, so
+/// differing row counts are reported too.
+fn diff<T: PartialEq + Debug, K: Ord + Debug>(
+    table: &str,
+    replayed: Vec<T>,
+    recorded: Vec<T>,
+    key: impl Fn(&T) -> K,
+) -> Vec<String> {
+    let mut discrepancies = Vec::new();
+    if replayed.len() != recorded.len() {
+        discrepancies.push(format!(
+            "{table}: replayed {} rows, recorded {}",
+            replayed.len(),
+            recorded.len()
+        ));
+    }
+    let mut rows: BTreeMap<K, (Option<T>, Option<T>)> = BTreeMap::new();
+    for row in replayed {
+        let row_key = key(&row);
+        rows.entry(row_key).or_default().0 = Some(row);
+    }
+    for row in recorded {
+        let row_key = key(&row);
+        rows.entry(row_key).or_default().1 = Some(row);
+    }
+    discrepancies.extend(rows.into_iter().filter_map(|(key, rows)| match rows {
+        (Some(replayed), Some(recorded)) if replayed == recorded => None,
+        (Some(replayed), Some(recorded)) => Some(format!(
+            "{table} {key:?}: replayed {replayed:?}, recorded {recorded:?}"
+        )),
+        (Some(replayed), None) => Some(format!(
+            "{table} {key:?}: replayed {replayed:?}, but nothing was recorded"
+        )),
+        (None, Some(recorded)) => Some(format!(
+            "{table} {key:?}: recorded {recorded:?}, but the replay did not produce it"
+        )),
+        (None, None) => None,
+    }));
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let key = |row: &(i32, &str)| row.0;
+        assert!(diff("t", vec![(1, "a"), (2, "b")], vec![(2, "b"), (1, "a")], key).is_empty());
+        assert_eq!(
+            vec![
+                String::from("t 1: replayed (1, \"a\"), recorded (1, \"x\")"),
+                String::from("t 2: replayed (2, \"b\"), but nothing was recorded"),
+                String::from("t 3: recorded (3, \"c\"), but the replay did not produce it"),
+            ],
+            diff("t", vec![(1, "a"), (2, "b")], vec![(1, "x"), (3, "c")], key)
+        );
+        // A duplicated row shares its key with the original
+        assert_eq!(
+            vec![String::from("t: replayed 1 rows, recorded 2")],
+            diff("t", vec![(1, "a")], vec![(1, "a"), (1, "a")], key)
+        );
+    }
+}
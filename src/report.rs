@@ -0,0 +1,238 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A machine-readable account of each roll, written once the roll has been committed.
+//!
+//! Reports are written to `risk.report.dir` as `season-<season>-day-<day>.json`, and printed
+//! to STDOUT as well when `risk.report.stdout` is true. Neither is written unless configured.
+
+use crate::structs::{Respawn, Stats, TerritoryOwnersInsert, TerritoryStats, TurnInfo};
+use chrono::NaiveDateTime;
+use rocket::figment::Figment;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Everything that happened in one roll.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RollReport {
+    pub turn_id: i32,
+    pub season: i32,
+    pub day: i32,
+    /// The revealed seed, from which the roll can be replayed
+    pub seed: Option<String>,
+    /// Whether this roll ended the season
+    pub finale: bool,
+    pub started: Option<NaiveDateTime>,
+    pub finished: Option<NaiveDateTime>,
+    pub elapsed_ms: u64,
+    pub territories: Vec<TerritoryReport>,
+    /// Eliminated teams put back on the map after the lottery
+    pub respawns: Vec<Respawn>,
+    /// Each team's totals for the day, in rank order
+    pub teams: Vec<Stats>,
+}
+
+/// How one territory was decided.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TerritoryReport {
+    pub territory: i32,
+    pub previous_owner: i32,
+    pub winner: i32,
+    /// The number drawn to pick the winner; 0 when the territory was not contested
+    pub lottery: f64,
+    /// The user id of the territory's MVP
+    pub mvp: Option<i32>,
+    /// Every team with a stake in the territory, including a previous owner that left it
+    pub contenders: Vec<Contender>,
+}
+
+/// One team's stake in a territory.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Contender {
+    pub team: i32,
+    pub power: f64,
+    pub chance: f64,
+    pub players: i32,
+}
+
+impl RollReport {
+    /// Gathers up the report from the rows the roll wrote. The timing is filled in by
+    /// [`RollReport::finish`] once the roll has been committed.
+    pub fn new(
+        turninfoblock: &TurnInfo,
+        owners: &[TerritoryOwnersInsert],
+        stats: &BTreeMap<i32, Stats>,
+        territory_stats: &[TerritoryStats],
+        respawns: Vec<Respawn>,
+    ) -> RollReport {
+        let mut contenders: BTreeMap<i32, Vec<Contender>> = BTreeMap::new();
+        for stat in territory_stats {
+            contenders
+                .entry(stat.territory)
+                .or_default()
+                .push(Contender {
+                    team: stat.team,
+                    power: stat.teampower,
+                    chance: stat.chance,
+                    players: stat.ones + stat.twos + stat.threes + stat.fours + stat.fives,
+                });
+        }
+        let territories = owners
+            .iter()
+            .map(|owner| {
+                let mut contenders = contenders.remove(&owner.territory_id).unwrap_or_default();
+                contenders.sort_by_key(|contender| contender.team);
+                TerritoryReport {
+                    territory: owner.territory_id,
+                    previous_owner: owner.previous_owner_id,
+                    winner: owner.owner_id,
+                    lottery: owner.random_number,
+                    mvp: owner.mvp,
+                    contenders,
+                }
+            })
+            .collect();
+        RollReport {
+            turn_id: turninfoblock.id,
+            season: turninfoblock.season,
+            day: turninfoblock.day,
+            seed: turninfoblock.seed.clone(),
+            finale: false,
+            started: None,
+            finished: None,
+            elapsed_ms: 0,
+            territories,
+            respawns,
+            teams: Stats::rank(stats, turninfoblock.id),
+        }
+    }
+
+    /// Records how the roll ended, from the turn as it was committed.
+    pub fn finish(&mut self, turninfoblock: &TurnInfo, elapsed: Duration) -> &mut Self {
+        self.finale = turninfoblock.finale == Some(true);
+        self.started = turninfoblock.rollstarttime;
+        self.finished = turninfoblock.rollendtime;
+        self.elapsed_ms = elapsed.as_millis() as u64;
+        self
+    }
+
+    /// Writes the report wherever `risk.report` says to.
+    pub fn write(&self, settings: &Figment) -> Result<(), Box<dyn std::error::Error>> {
+        if let Ok(dir) = settings.extract_inner::<PathBuf>("risk.report.dir") {
+            std::fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("season-{}-day-{}.json", self.season, self.day));
+            serde_json::to_writer_pretty(std::fs::File::create(&path)?, self)?;
+            println!("Roll report written to {}", path.display());
+        }
+        if settings
+            .extract_inner::<bool>("risk.report.stdout")
+            .unwrap_or(false)
+        {
+            println!("{}", serde_json::to_string_pretty(self)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::RollState;
+
+    #[test]
+    fn test_report() {
+        let turninfoblock = TurnInfo {
+            id: 10,
+            season: 2,
+            day: 5,
+            complete: Some(false),
+            active: Some(false),
+            finale: Some(false),
+            chaosweight: None,
+            rollendtime: None,
+            rollstarttime: None,
+            allornothingenabled: Some(false),
+            map: None,
+            seed_commitment: None,
+            seed: Some(String::from("00")),
+            playoffs: Some(false),
+            roll_state: RollState::Rolling,
+            game: 1,
+        };
+        let owner =
+            |territory_id, owner_id, previous_owner_id, random_number, mvp| TerritoryOwnersInsert {
+                territory_id,
+                owner_id,
+                turn_id: 11,
+                previous_owner_id,
+                random_number,
+                mvp,
+            };
+        let stat = |territory, team, teampower, chance, ones| TerritoryStats {
+            team,
+            turn_id: 10,
+            ones,
+            teampower,
+            chance,
+            territory,
+            ..TerritoryStats::default()
+        };
+        let mut stats = BTreeMap::new();
+        stats.insert(1, Stats::new(11, 1));
+        let report = RollReport::new(
+            &turninfoblock,
+            &[owner(1, 1, 1, 0.0, None), owner(2, 2, 1, 7.5, Some(40))],
+            &stats,
+            &[
+                stat(2, 2, 10.0, 0.8, 3),
+                stat(1, 1, 0.0, 0.0, 0),
+                stat(2, 1, 2.5, 0.2, 1),
+            ],
+            Vec::new(),
+        );
+        assert_eq!((10, 2, 5), (report.turn_id, report.season, report.day));
+        assert_eq!(Some(String::from("00")), report.seed);
+        assert_eq!(1, report.teams.len());
+        assert_eq!(
+            vec![
+                TerritoryReport {
+                    territory: 1,
+                    previous_owner: 1,
+                    winner: 1,
+                    lottery: 0.0,
+                    mvp: None,
+                    contenders: vec![Contender {
+                        team: 1,
+                        power: 0.0,
+                        chance: 0.0,
+                        players: 0,
+                    }],
+                },
+                TerritoryReport {
+                    territory: 2,
+                    previous_owner: 1,
+                    winner: 2,
+                    lottery: 7.5,
+                    mvp: Some(40),
+                    contenders: vec![
+                        Contender {
+                            team: 1,
+                            power: 2.5,
+                            chance: 0.2,
+                            players: 1,
+                        },
+                        Contender {
+                            team: 2,
+                            power: 10.0,
+                            chance: 0.8,
+                            players: 3,
+                        },
+                    ],
+                },
+            ],
+            report.territories
+        );
+    }
+}
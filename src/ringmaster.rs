@@ -9,27 +9,194 @@ extern crate diesel;
 extern crate serde_derive;
 extern crate rand;
 extern crate rand_chacha;
+pub mod daemon;
+pub mod game;
+pub mod lock;
+pub mod lottery;
+pub mod map;
+pub mod moves;
 pub mod optional;
+pub mod ratings;
+pub mod replay;
+pub mod report;
+pub mod rules;
 pub mod schema;
+pub mod seed;
+pub mod store;
 pub mod structs;
+pub mod undo;
 
 use chrono::{DateTime, Datelike, Duration, NaiveDateTime, NaiveTime, Timelike, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::sql_query;
+use game::{Game, DEFAULT_GAME};
+use lock::AdvisoryLock;
+use lottery::VictorStrategy;
+use map::Map;
+use moves::check_bracket;
 use rand::prelude::*;
 use rand_chacha::ChaCha12Rng;
+use report::RollReport;
+use rules::Rules;
 use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use store::{NewTurn, PgStore, Store};
 
-const ALT_CUTOFF: i32 = 75;
-const AON_END: i32 = 48;
-const AON_START: i32 = 4;
+/// What a roll hands back once it has written everything: the new owners and its report.
+type Rolled = (Vec<TerritoryOwnersInsert>, RollReport);
 
+/// Marks the `territory_adjacency` rows that the ringmaster adds and removes for chaos
+#[cfg(feature = "chaos")]
+const CHAOS_NOTE: &str = "chaos_auto_managed";
+
+#[cfg(feature = "chaos")]
+use structs::Adjacency;
 use structs::{
-    Bar, PlayerMoves, Stats, TerritoryOwners, TerritoryOwnersInsert, TerritoryStats, TurnInfo,
-    Victor,
+    PlayerMoves, Respawn, RollPreview, RollState, SeasonWinner, Stats, TerritoryOwners,
+    TerritoryOwnersInsert, TerritoryStats, TurnInfo, UserRatings, UserTurns, Victor,
 };
 
+/// What the ringmaster was asked to do.
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    /// Roll the active turn
+    Roll(RollOptions),
+    /// Replay an earlier roll and compare it with what was recorded
+    Replay {
+        season: i32,
+        day: i32,
+        game: Option<String>,
+    },
+    /// Check a map file without touching the database
+    CheckMap { path: PathBuf },
+    /// Load a map file as day 1 of a new season
+    LoadMap {
+        path: PathBuf,
+        season: i32,
+        game: Option<String>,
+    },
+    /// Undo the last roll of a game, recording who undid it
+    Undo { by: String, game: Option<String> },
+}
+
+impl Command {
+    /// Reads `replay <season> <day> [--game <name>]`, `map check <file>`, `map load <file> <season>
+    /// [--game <name>]`, `undo --by <name> [--game <name>]`, or else the roll options, from
+    /// the arguments (excluding the program name).
+    fn parse(args: impl Iterator<Item = String>) -> Result<Command, String> {
+        let mut args = args.peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("replay") => "replay",
+            Some("map") => "map",
+            Some("undo") => "undo",
+            _ => return RollOptions::parse(args).map(Command::Roll),
+        };
+        args.next();
+        let command = match command {
+            "replay" => {
+                let season = number(&mut args, "replay", "season")?;
+                let day = number(&mut args, "replay", "day")?;
+                let game = game_option(&mut args)?;
+                Command::Replay { season, day, game }
+            }
+            "undo" => {
+                let (mut by, mut game) = (None, None);
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--by" => by = Some(args.next().ok_or("--by requires a name")?),
+                        "--game" => game = Some(args.next().ok_or("--game requires a name")?),
+                        _ => return Err(format!("Unknown argument: {arg}")),
+                    }
+                }
+                let by = by.ok_or("undo requires --by <name>")?;
+                Command::Undo { by, game }
+            }
+            _ => {
+                let action = args.next().ok_or("map requires check or load")?;
+                let path = PathBuf::from(args.next().ok_or("map requires a file path")?);
+                match action.as_str() {
+                    "check" => Command::CheckMap { path },
+                    "load" => {
+                        let season = number(&mut args, "map load", "season")?;
+                        let game = game_option(&mut args)?;
+                        Command::LoadMap { path, season, game }
+                    }
+                    _ => return Err(format!("Unknown map command: {action}")),
+                }
+            }
+        };
+        match args.next() {
+            Some(arg) => Err(format!("Unknown argument: {arg}")),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Reads an optional trailing `--game <name>`.
+fn game_option(
+    args: &mut std::iter::Peekable<impl Iterator<Item = String>>,
+) -> Result<Option<String>, String> {
+    match args.peek().map(String::as_str) {
+        Some("--game") => {
+            args.next();
+            Ok(Some(args.next().ok_or("--game requires a name")?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reads the next argument of `command` as the number called `name`.
+fn number(
+    args: &mut impl Iterator<Item = String>,
+    command: &str,
+    name: &str,
+) -> Result<i32, String> {
+    args.next()
+        .ok_or(format!("{command} requires a {name}"))?
+        .parse::<i32>()
+        .map_err(|e| format!("Invalid {name}: {e}"))
+}
+
+/// Command line options for a single run of the ringmaster.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct RollOptions {
+    /// Run the roll without writing anything to the database
+    dry_run: bool,
+    /// Where to write the dry run results; `None` prints them to STDOUT
+    output: Option<PathBuf>,
+    /// Keep running, rolling each turn when its `rollstarttime` comes around
+    daemon: bool,
+    /// The game to roll; `None` rolls the default game, or every game when running as a daemon
+    game: Option<String>,
+}
+
+impl RollOptions {
+    /// Reads `--dry-run`, `--output <file>`, `--daemon` and `--game <name>` from the arguments
+    /// (excluding the program name).
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<RollOptions, String> {
+        let mut options = RollOptions::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dry-run" => options.dry_run = true,
+                "--daemon" => options.daemon = true,
+                "--output" => {
+                    let path = args.next().ok_or("--output requires a file path")?;
+                    options.output = Some(PathBuf::from(path));
+                }
+                "--game" => options.game = Some(args.next().ok_or("--game requires a name")?),
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+        if options.output.is_some() && !options.dry_run {
+            return Err(String::from("--output can only be used with --dry-run"));
+        }
+        if options.daemon && options.dry_run {
+            return Err(String::from("--daemon cannot be used with --dry-run"));
+        }
+        Ok(options)
+    }
+}
+
 #[must_use]
 pub fn establish_connection() -> PgConnection {
     // Rocket figment gives us the information we need, then we discard it
@@ -41,10 +208,10 @@ pub fn establish_connection() -> PgConnection {
         .unwrap_or_else(|_| panic!("Error connecting to {database_url}"))
 }
 
-fn get_teams(territory_players: Vec<PlayerMoves>) -> Vec<i32> {
+fn get_teams(territory_players: Vec<PlayerMoves>, alt_cutoff: i32) -> Vec<i32> {
     let mut teams = territory_players
         .iter()
-        .filter(|mover| mover.alt_score < ALT_CUTOFF)
+        .filter(|mover| mover.alt_score < alt_cutoff)
         .map(|x| x.team)
         .collect::<Vec<i32>>();
     teams.sort_unstable();
@@ -52,34 +219,14 @@ fn get_teams(territory_players: Vec<PlayerMoves>) -> Vec<i32> {
     teams
 }
 
-/// Based on random number `lottery`, return the ID of the MVP user
-fn determine_victor(lottery: f64, map: BTreeMap<i32, Victor>) -> i32 {
-    // This is the incrementing value that we will add each team's power to
-    let mut victorsum = 0_f64;
-    // This will be what we return, the id of the victorious team.
-    let mut victor = 0;
-    for (key, val) in &map {
-        // Add to the sum
-        victorsum += val.power;
-
-        // Is the sum greater than the random number?
-        if lottery - victorsum < 0_f64 {
-            // If so, we have our winner! Go!
-            victor = *key;
-            break;
-        }
-    }
-    victor
-}
-
 // Returns MVP by selecting at random from the team that won
-fn get_mvp(mut territory_players: Vec<PlayerMoves>, test: bool) -> Option<PlayerMoves> {
-    territory_players.retain(|x| x.alt_score < ALT_CUTOFF && x.power > 0.0);
-    let mut seed = if test {
-        ChaCha12Rng::seed_from_u64(55)
-    } else {
-        ChaCha12Rng::from_entropy()
-    };
+// The roll's seeded RNG is used so that MVPs can be re-derived from the revealed seed.
+fn get_mvp(
+    mut territory_players: Vec<PlayerMoves>,
+    alt_cutoff: i32,
+    seed: &mut ChaCha12Rng,
+) -> Option<PlayerMoves> {
+    territory_players.retain(|x| x.alt_score < alt_cutoff && x.power > 0.0);
     let rng = match territory_players.len() {
         // We eliminated everyone :(
         0 => return None,
@@ -102,6 +249,7 @@ fn get_mvp(mut territory_players: Vec<PlayerMoves>, test: bool) -> Option<Player
 /// - players: Vec<PlayerMoves>: These are the Moves made by the players today that we need to process.
 ///     e.g. user's id, turn id, territory id, whether they're mvp (it comes in as false but we later tell the DB to set it to true if they're the MVP)
 ///     the power, multiplier, and weight of the user, by the relationship power = multiplier * weight where weight is a function of starcount (see /src/model/auth/route.rs).
+/// - next_turn: i32: The id of tomorrow's turn, which the new ownership belongs to.
 /// Outputs:
 /// - Vec<TerritoryOwnersInsert>: The new territory ownership for tomorrow.
 /// - Vec<PlayerMoves>: The moves, with MVPs populated
@@ -110,7 +258,10 @@ fn get_mvp(mut territory_players: Vec<PlayerMoves>, test: bool) -> Option<Player
 fn process_territories(
     territories: Vec<TerritoryOwners>,
     mut players: Vec<PlayerMoves>,
+    next_turn: i32,
     seed: &mut ChaCha12Rng,
+    strategy: &dyn VictorStrategy,
+    alt_cutoff: i32,
     test: bool,
 ) -> (
     Vec<TerritoryOwnersInsert>,
@@ -118,10 +269,6 @@ fn process_territories(
     BTreeMap<i32, Stats>,
     Vec<TerritoryStats>,
 ) {
-    // We log this to STDOUT so we can debug problems
-    dbg!("process_territories");
-    dbg!(territories.len());
-
     // We create empty arrays for the outputs
     let mut new_owners: Vec<TerritoryOwnersInsert> = Vec::new();
     let mut mvps: Vec<PlayerMoves> = Vec::new();
@@ -132,22 +279,16 @@ fn process_territories(
     // If a territory wasn't 'owned' yesterday, we won't see it.
     // But given proper starting DB conditions that's not an issue.
     for territory in territories {
-        // Again, for debugging
-        dbg!(&territory.territory_id);
-
         // We collect all the players that placed a move on this territory
         let territory_players = players
             .drain_filter(|player| {
-                player.territory == territory.territory_id && player.alt_score < ALT_CUTOFF
+                player.territory == territory.territory_id && player.alt_score < alt_cutoff
             })
             .collect::<Vec<_>>();
 
-        // Again, for debugging
-        dbg!(&territory_players.len());
-
         // This function returns the teams that attacked/defended this territory
         // It does so by collecting the team id from all players and then removing dupes.
-        let teams = get_teams(territory_players.clone());
+        let teams = get_teams(territory_players.clone(), alt_cutoff);
 
         // Here we split into different logic depending on how many teams attacked a territory.
         // If nobody made a move on the territory (e.g. it's surrounded by territories also owned by the same owner)
@@ -159,11 +300,10 @@ fn process_territories(
         match teams.len() {
             // This is the "no teams attacked" case, so keep the owner the same.
             0 => {
-                dbg!("Zero Team");
-
                 // Push the new territory owner.
                 new_owners.push(TerritoryOwnersInsert::new(
                     &territory,
+                    next_turn,
                     territory.owner_id,
                     None,
                     None,
@@ -172,7 +312,7 @@ fn process_territories(
                 // add team territory count to stats (but no MVPs as nobody made a move here)
                 stats
                     .entry(territory.owner_id)
-                    .or_insert_with(|| Stats::new(territory.turn_id + 1, territory.owner_id))
+                    .or_insert_with(|| Stats::new(next_turn, territory.owner_id))
                     .territorycount += 1;
                 territory_stats.push(TerritoryStats {
                     team: territory.owner_id,
@@ -188,15 +328,15 @@ fn process_territories(
                 // So let's check if there's any power available from the team.
                 if territory_players
                     .iter()
-                    .filter(|mover| mover.alt_score < ALT_CUTOFF)
+                    .filter(|mover| mover.alt_score < alt_cutoff)
                     .map(|mover| mover.power)
                     .sum::<f64>()
                     == 0.0
                 {
                     // There is no power from this team, this is the same case as if there is no teams, next
-                    dbg!("Team has no power");
                     new_owners.push(TerritoryOwnersInsert::new(
                         &territory,
+                        next_turn,
                         territory.owner_id,
                         None,
                         None,
@@ -204,7 +344,7 @@ fn process_territories(
                     // add team territory count to stats
                     stats
                         .entry(territory.owner_id)
-                        .or_insert_with(|| Stats::new(territory.turn_id + 1, territory.owner_id))
+                        .or_insert_with(|| Stats::new(next_turn, territory.owner_id))
                         .territorycount += 1;
 
                     territory_stats.push(TerritoryStats {
@@ -214,7 +354,8 @@ fn process_territories(
                         ..TerritoryStats::default()
                     });
 
-                    let team_star_breakdown = get_team_star_breakdown(&territory_players);
+                    let team_star_breakdown =
+                        get_team_star_breakdown(&territory_players, alt_cutoff);
                     if team_star_breakdown.iter().sum::<i32>() > 0 {
                         territory_stats.push(TerritoryStats {
                             team: territory_players[0].team,
@@ -231,15 +372,13 @@ fn process_territories(
                         });
                     }
                     // We don't know if it was an alt or not so this helps us out
-                    handle_team_stats(&mut stats, territory_players);
+                    handle_team_stats(&mut stats, territory_players, next_turn, alt_cutoff);
                     continue; // next territory
                 }
 
                 // There was some power from the team! Let's give them the territory.
-                dbg!("One Team");
-
                 // We select an MVP
-                let mvp = get_mvp(territory_players.clone(), test);
+                let mvp = get_mvp(territory_players.clone(), alt_cutoff, seed);
                 // We push the mvps onto the MVP docket from earlier.
                 let mvp_id = match mvp {
                     None => None,
@@ -254,13 +393,13 @@ fn process_territories(
 
                 // We push the new territory owner into the owners docket.
                 new_owners.push(TerritoryOwnersInsert::new(
-                    &territory, teams[0], None, mvp_id,
+                    &territory, next_turn, teams[0], None, mvp_id,
                 ));
 
                 // And finally we alter the statistics for the team that won
                 stats
                     .entry(teams[0])
-                    .or_insert_with(|| Stats::new(territory.turn_id + 1, teams[0]))
+                    .or_insert_with(|| Stats::new(next_turn, teams[0]))
                     .territorycount += 1;
 
                 // Imagine a team quit the game, we want to show their stats on the leaderboard.
@@ -268,13 +407,13 @@ fn process_territories(
                 // territory so we'll just add 0 territories while we're at it.
                 stats
                     .entry(territory.owner_id)
-                    .or_insert_with(|| Stats::new(territory.turn_id + 1, territory.owner_id))
+                    .or_insert_with(|| Stats::new(next_turn, territory.owner_id))
                     .territorycount += 0;
 
                 // You may have noticed we only updated the territory count previously.
                 // But team stats includes mvps and power, et al. We calculate that now.
                 // add team stats
-                handle_team_stats(&mut stats, territory_players.clone());
+                handle_team_stats(&mut stats, territory_players.clone(), next_turn, alt_cutoff);
                 // This team might be dead/abandoned this territory, so we push to the odds table
                 if teams[0] != territory.owner_id {
                     territory_stats.push(TerritoryStats {
@@ -283,7 +422,7 @@ fn process_territories(
                         territory: territory.territory_id,
                         territory_power: territory_players
                             .iter()
-                            .filter(|mover| mover.alt_score < ALT_CUTOFF)
+                            .filter(|mover| mover.alt_score < alt_cutoff)
                             .map(|mover| mover.power)
                             .sum::<f64>(),
                         chance: 0.00,
@@ -291,7 +430,7 @@ fn process_territories(
                     });
                 }
 
-                let team_star_breakdown = get_team_star_breakdown(&territory_players);
+                let team_star_breakdown = get_team_star_breakdown(&territory_players, alt_cutoff);
 
                 // Finally we push the statistics for the territory
                 territory_stats.push(TerritoryStats {
@@ -304,14 +443,14 @@ fn process_territories(
                     fives: team_star_breakdown[4],
                     teampower: territory_players
                         .iter()
-                        .filter(|player| player.alt_score < ALT_CUTOFF)
+                        .filter(|player| player.alt_score < alt_cutoff)
                         .map(|mover| mover.power)
                         .sum::<f64>(),
                     chance: 1.00,
                     territory: territory.territory_id,
                     territory_power: territory_players
                         .iter()
-                        .filter(|player| player.alt_score < ALT_CUTOFF)
+                        .filter(|player| player.alt_score < alt_cutoff)
                         .map(|mover| mover.power)
                         .sum::<f64>(),
                 });
@@ -320,24 +459,21 @@ fn process_territories(
             // In Rust, `_` is a catchall for everything not in the match already.
             // In this case, it means ALL territories which have > 1 team.
             _ => {
-                // Again, for debugging.
-                dbg!(&teams);
-
                 // Due to All-or-nothing and alts, we don't get to just assume that this team gets it
                 // So let's check if there's any power available from the team.
                 if territory_players
                     .iter()
-                    .filter(|player| player.alt_score < ALT_CUTOFF)
+                    .filter(|player| player.alt_score < alt_cutoff)
                     .map(|mover| mover.power)
                     .sum::<f64>()
                     == 0.0
                 {
                     // No team has power, this is the same case as if there is no teams, next
-                    dbg!("Teams have no power");
 
                     // Push the same owner as previously
                     new_owners.push(TerritoryOwnersInsert::new(
                         &territory,
+                        next_turn,
                         territory.owner_id,
                         None,
                         None,
@@ -346,7 +482,7 @@ fn process_territories(
                     // add team territory count to stats
                     stats
                         .entry(territory.owner_id)
-                        .or_insert_with(|| Stats::new(territory.turn_id + 1, territory.owner_id))
+                        .or_insert_with(|| Stats::new(next_turn, territory.owner_id))
                         .territorycount += 1;
 
                     // and finally push territory stats
@@ -366,6 +502,7 @@ fn process_territories(
                                 .filter(|p| p.team == team)
                                 .cloned()
                                 .collect(),
+                            alt_cutoff,
                         );
                         if team_star_breakdown.iter().sum::<i32>() > 0 {
                             territory_stats.push(TerritoryStats {
@@ -390,6 +527,8 @@ fn process_territories(
                                 .filter(|p| p.team == team)
                                 .cloned()
                                 .collect(),
+                            next_turn,
+                            alt_cutoff,
                         );
                     }
 
@@ -402,7 +541,7 @@ fn process_territories(
                 // We create a stats entry for the owner of the territory, just in case they abandoned it
                 stats
                     .entry(territory.owner_id)
-                    .or_insert_with(|| Stats::new(territory.turn_id + 1, territory.owner_id))
+                    .or_insert_with(|| Stats::new(next_turn, territory.owner_id))
                     .territorycount += 0;
 
                 // For each team, insert the team into the BTreeMap we made earlier
@@ -413,8 +552,7 @@ fn process_territories(
 
                 // Now we populate the map with the power
                 for player in &territory_players {
-                    //dbg!(player.id, player.team);
-                    if player.alt_score >= ALT_CUTOFF {
+                    if player.alt_score >= alt_cutoff {
                         continue;
                     }
 
@@ -429,23 +567,23 @@ fn process_territories(
                 // We now calculate the total power that was expended by ALL teams on the territory.
                 let totalpower: f64 = map.values().map(|x| (x.power)).sum();
 
-                // We now generate the random number from 0 to the total power on the territory.
-                // We pass this in so we can run tests
-                let lottery = seed.gen_range(0_f64..totalpower);
-
-                // We determine the victor, this function goes team by team until the sum of the power of teams
-                // it has reviewed is greater than `lottery` the random number we created earlier.
-                // It returns the id of the team that wins.
-                let victor = determine_victor(lottery, map.clone());
+                // We determine the victor using the season's strategy. The classic lottery generates
+                // a random number from 0 to the total power on the territory, then goes team by team
+                // until the sum of the power of teams it has reviewed is greater than that number.
+                // It returns the id of the team that wins, and the random number it drew.
+                let (victor, lottery) = strategy.victor(
+                    &map.iter().map(|(team, val)| (*team, val.power)).collect(),
+                    seed,
+                );
 
                 // We collect the players that are on the winning team for MVPing.
                 let territory_victors = territory_players
                     .clone()
-                    .drain_filter(|player| player.team == victor && player.alt_score < ALT_CUTOFF)
+                    .drain_filter(|player| player.team == victor && player.alt_score < alt_cutoff)
                     .collect::<Vec<_>>();
 
                 // We now determine the MVP from the players on the winning team.
-                let mvp = get_mvp(territory_victors, test);
+                let mvp = get_mvp(territory_victors, alt_cutoff, seed);
                 let mvp_id = match mvp {
                     None => None,
                     Some(mvp_i) => {
@@ -460,6 +598,7 @@ fn process_territories(
                 // We push the new owner
                 new_owners.push(TerritoryOwnersInsert::new(
                     &territory,
+                    next_turn,
                     victor,
                     Some(lottery),
                     mvp_id,
@@ -468,13 +607,15 @@ fn process_territories(
                 // We generate the team statistics for the victor
                 stats
                     .entry(victor)
-                    .or_insert_with(|| Stats::new(territory.turn_id + 1, victor))
+                    .or_insert_with(|| Stats::new(next_turn, victor))
                     .territorycount += 1;
 
                 // We now calculate total power for the territory for territory statistics..
-                handle_team_stats(&mut stats, territory_players);
+                handle_team_stats(&mut stats, territory_players, next_turn, alt_cutoff);
 
                 // And we then push the territory statistics.
+                let chances =
+                    strategy.chances(&map.iter().map(|(team, val)| (*team, val.power)).collect());
                 for (key, val) in &map {
                     territory_stats.push(TerritoryStats {
                         team: *key,
@@ -485,7 +626,7 @@ fn process_territories(
                         fours: val.fours,
                         fives: val.fives,
                         teampower: val.power,
-                        chance: val.power / totalpower,
+                        chance: chances[key],
                         territory: territory.territory_id,
                         territory_power: totalpower,
                     });
@@ -518,9 +659,14 @@ fn process_territories(
     (new_owners, mvps, stats, territory_stats)
 }
 
-fn handle_team_stats(stats: &mut BTreeMap<i32, Stats>, territory_players: Vec<PlayerMoves>) {
+fn handle_team_stats(
+    stats: &mut BTreeMap<i32, Stats>,
+    territory_players: Vec<PlayerMoves>,
+    next_turn: i32,
+    alt_cutoff: i32,
+) {
     for i in territory_players {
-        if i.alt_score >= ALT_CUTOFF {
+        if i.alt_score >= alt_cutoff {
             continue;
         }
         let starpower = if i.power == 0.0 {
@@ -530,7 +676,7 @@ fn handle_team_stats(stats: &mut BTreeMap<i32, Stats>, territory_players: Vec<Pl
         };
         stats
             .entry(i.team)
-            .or_insert_with(|| Stats::new(i.turn_id + 1, i.team))
+            .or_insert_with(|| Stats::new(next_turn, i.team))
             .starpower(starpower)
             .effectivepower(i.power.round())
             .add_player_or_merc(i.merc)
@@ -538,11 +684,11 @@ fn handle_team_stats(stats: &mut BTreeMap<i32, Stats>, territory_players: Vec<Pl
     }
 }
 
-fn get_team_star_breakdown(territory_player: &Vec<PlayerMoves>) -> [i32; 5] {
+fn get_team_star_breakdown(territory_player: &Vec<PlayerMoves>, alt_cutoff: i32) -> [i32; 5] {
     let mut output = [0, 0, 0, 0, 0];
 
     for pm in territory_player {
-        if pm.alt_score >= ALT_CUTOFF {
+        if pm.alt_score >= alt_cutoff {
             continue;
         }
         match pm.stars {
@@ -558,45 +704,102 @@ fn get_team_star_breakdown(territory_player: &Vec<PlayerMoves>) -> [i32; 5] {
     output
 }
 
-/// Updates the statistics for all users after the roll
+/// Brings one user's counters up to date after a roll, from their turn history
+/// (`None` if they have never played) and the teams that still own territory.
+fn update_user(
+    user: &UserRatings,
+    turns: Option<&UserTurns>,
+    teams: &[i32],
+    rules: &Rules,
+) -> UserRatings {
+    let mut updated = user.clone();
+    // Played today? Keep the streak going, otherwise it's broken.
+    updated.streak = match turns {
+        Some(turns) if turns.played => Some(user.streak.unwrap_or(0) + 1),
+        _ => Some(0),
+    };
+    if let Some(turns) = turns {
+        updated.turns = Some(turns.turns);
+        updated.mvps = Some(turns.mvps);
+        // Users who haven't played this season keep their last season's count
+        if turns.game_turns > 0 {
+            updated.game_turns = Some(turns.game_turns);
+        }
+    }
+    updated.overall = Some(ratings::overall([
+        ratings::stars(updated.turns.unwrap_or(0), &rules.total_turns),
+        ratings::stars(updated.game_turns.unwrap_or(0), &rules.game_turns),
+        ratings::stars(updated.mvps.unwrap_or(0), &rules.mvps),
+        ratings::stars(updated.streak.unwrap_or(0), &rules.streak),
+    ]));
+    // Players whose team has been eliminated are no longer playing for it
+    if !teams.contains(&user.playing_for) {
+        updated.playing_for = -1;
+    }
+    updated
+}
+
+/// Updates each user's streak, turn counts, MVPs and overall rating after the roll of
+/// `turninfoblock`, which gave the territories of `previous_teams` to `owners`. Returns how many
+/// users changed.
+///
+/// Only the users taking part in the turn's game are updated: those who have played in it, and
+/// those playing for a team that held territory in it before the roll. Everyone else is left for
+/// the rolls of their own games.
 fn user_update(
     turninfoblock: &TurnInfo,
-    conn: &PgConnection,
-) -> Result<Vec<Bar>, diesel::result::Error> {
-    let query = format!(
-        "SELECT do_user_update({},{})",
-        &turninfoblock.id.to_string(),
-        &turninfoblock.season.to_string(),
-    );
-    sql_query(query).load(conn)
+    previous_teams: &[i32],
+    owners: &[TerritoryOwnersInsert],
+    rules: &Rules,
+    store: &dyn Store,
+) -> Result<usize, diesel::result::Error> {
+    let turns = store
+        .user_turns(turninfoblock.id, turninfoblock.season, turninfoblock.game)?
+        .into_iter()
+        .map(|turns| (turns.user_id, turns))
+        .collect::<BTreeMap<i32, UserTurns>>();
+    let mut teams = owners.iter().map(|x| x.owner_id).collect::<Vec<i32>>();
+    teams.sort_unstable();
+    teams.dedup();
+    let mut updated = 0;
+    for user in store.user_ratings()? {
+        let in_game = turns.get(&user.id).is_some_and(|turns| turns.in_game);
+        if !in_game && !previous_teams.contains(&user.playing_for) {
+            continue;
+        }
+        let new = update_user(&user, turns.get(&user.id), &teams, rules);
+        // Most users don't play on any given day, so only write the ones that changed. Each
+        // is kept as they were before, so that the roll can be undone.
+        if new != user {
+            store.snapshot_user(turninfoblock.id, &user)?;
+            store.update_user(&new)?;
+            updated += 1;
+        }
+    }
+    Ok(updated)
 }
 
-/// Updates chaos bridges randomly
+/// Adds the chaos bridges opened by the roll of `turn_id` for the new turn `next_turn`, and
+/// clears out expired ones.
+///
+/// Bridges run from `risk.chaos_territory_id` to territories drawn from those on the map, using
+/// the roll's RNG, so the same seed always draws the same bridges. Each bridge stays open for
+/// `risk.chaos_bridge_lifetime` turn ids from `next_turn`. Once closed, it is deleted after
+/// another `risk.chaos_bridge_history` turn ids.
 #[cfg(feature = "chaos")]
 fn chaos_update(
     territories: &[TerritoryOwnersInsert],
-    turn_id_n: i32,
+    turn_id: i32,
+    next_turn: i32,
+    seed: &mut ChaCha12Rng,
     settings: &rocket::figment::Figment,
-    conn: &PgConnection,
+    store: &dyn Store,
 ) -> Result<(), diesel::result::Error> {
-    use crate::schema::territory_adjacency;
-    // First, get the maximum and minimum territory numbers
-    let max_territory: i32 = territories
-        .iter()
-        .max_by(|a, b| a.territory_id.cmp(&b.territory_id))
-        .map(|k| k.territory_id)
-        .unwrap_or(-1);
-    let min_territory: i32 = territories
-        .iter()
-        .min_by(|a, b| a.territory_id.cmp(&b.territory_id))
-        .map(|k| k.territory_id)
-        .unwrap_or(-2);
-    // Decide how many bridges to add
     // First, read config for Max/Min:
-    let max_bridges: u32 = settings
+    let max_bridges: usize = settings
         .extract_inner("risk.max_chaos_bridges")
         .unwrap_or(5);
-    let min_bridges: u32 = settings
+    let min_bridges: usize = settings
         .extract_inner("risk.min_chaos_bridges")
         .unwrap_or(1);
     let chaos_territory_id: i32 = settings
@@ -605,69 +808,422 @@ fn chaos_update(
     let chaos_bridges_twoway: bool = settings
         .extract_inner("risk.chaos_bridges_twoway")
         .unwrap_or(false);
-    dbg!(
-        max_territory,
-        min_territory,
-        max_bridges,
-        min_bridges,
-        chaos_bridges_twoway,
-        chaos_territory_id
-    );
-    // Random!
-    // NOTE: THIS IS [low, high)
-    let num: u32 = rand::thread_rng().gen_range(min_bridges..max_bridges);
-    // Remove old bridges with note 'chaos_auto_managed'
-    //diesel::delete(territory_adjacency::table.filter(note.eq("chaos_auto_managed")))
-    //    .execute(conn)?;
+    let lifetime: i32 = settings
+        .extract_inner("risk.chaos_bridge_lifetime")
+        .unwrap_or(1);
+    let history: i32 = settings
+        .extract_inner("risk.chaos_bridge_history")
+        .unwrap_or(0);
+    // Remove bridges that closed more than `history` turns ago
+    let expired = store.delete_adjacency(CHAOS_NOTE, next_turn - history)?;
+    println!("Removed {expired} expired chaos bridges");
     // Add new bridges with note 'chaos_auto_managed'
-    // Goes 0, 1, 2, 3, num-1; excludes num just like normal languages
     let mut new_stuff = Vec::new();
-    #[derive(Insertable)]
-    #[table_name = "territory_adjacency"]
-    struct TerritoryAdjacent<'a> {
-        territory_id: i32,
-        adjacent_id: i32,
-        note: &'a str,
-        min_turn: i32,
-        max_turn: i32,
-    }
-    dbg!(num);
-    for _ in 0..num {
-        // Select random teritory id:
-        let territory: i32 = rand::thread_rng().gen_range(min_territory..(max_territory + 1));
-        new_stuff.push(TerritoryAdjacent {
+    for territory in chaos_targets(
+        territories,
+        chaos_territory_id,
+        min_bridges,
+        max_bridges,
+        seed,
+    ) {
+        new_stuff.push(Adjacency {
             territory_id: chaos_territory_id,
             adjacent_id: territory,
-            note: "chaos_auto_managed",
-            min_turn: turn_id_n - 1,
-            max_turn: turn_id_n,
+            note: String::from(CHAOS_NOTE),
+            min_turn: turn_id,
+            max_turn: next_turn + lifetime - 1,
         });
         if chaos_bridges_twoway {
-            new_stuff.push(TerritoryAdjacent {
+            new_stuff.push(Adjacency {
                 territory_id: territory,
                 adjacent_id: chaos_territory_id,
-                note: "chaos_auto_managed",
-                min_turn: turn_id_n - 1,
-                max_turn: turn_id_n,
+                note: String::from(CHAOS_NOTE),
+                min_turn: turn_id,
+                max_turn: next_turn + lifetime - 1,
             });
         }
     }
-    diesel::insert_into(territory_adjacency::table)
-        .values(&new_stuff)
-        .execute(conn)?;
+    store.insert_adjacency(&new_stuff)?;
     Ok(())
 }
 
-#[allow(dead_code)]
-fn do_playoffs() {
-    // If we have playoffs. then we need to cast off a new day
-    // Steps:
-    // 1. For each bracket, determine the winner
-    // 1a. If a bracket has a tie, then randomly declare a winner
-    // 2. Create new territory ownership by bracket
-    // 3. Create new statistics, giving each team equal territories and zero players
-    // 4. Pop on the new day
-    // Because we're not technically carrying out a new day, we don't add anything to any player account.
+/// Picks the territories to bridge the chaos territory to: between `min_bridges` and
+/// `max_bridges` (inclusive) different territories on the map, never the chaos territory itself.
+#[cfg(feature = "chaos")]
+fn chaos_targets(
+    territories: &[TerritoryOwnersInsert],
+    chaos_territory_id: i32,
+    min_bridges: usize,
+    max_bridges: usize,
+    seed: &mut ChaCha12Rng,
+) -> Vec<i32> {
+    let live = territories
+        .iter()
+        .map(|territory| territory.territory_id)
+        .filter(|&territory| territory != chaos_territory_id)
+        .collect::<Vec<i32>>();
+    let num = seed
+        .gen_range(min_bridges.min(max_bridges)..=max_bridges)
+        .min(live.len());
+    live.choose_multiple(seed, num).copied().collect()
+}
+
+/// The winner of each bracket: whichever team owns the most of the bracket's territories.
+/// A tie is broken at random between the tied teams.
+fn bracket_winners(
+    brackets: &[Vec<i32>],
+    territories: &[TerritoryOwners],
+    seed: &mut ChaCha12Rng,
+) -> Vec<i32> {
+    brackets
+        .iter()
+        .filter(|teams| !teams.is_empty())
+        .map(|teams| {
+            let count = |team: &i32| {
+                territories
+                    .iter()
+                    .filter(|territory| territory.owner_id == *team)
+                    .count()
+            };
+            let most = teams.iter().map(count).max().unwrap_or(0);
+            let leaders = teams
+                .iter()
+                .filter(|team| count(team) == most)
+                .collect::<Vec<&i32>>();
+            *leaders[seed.gen_range(0..leaders.len())]
+        })
+        .collect()
+}
+
+/// Pairs off the bracket winners into the next round's brackets. Once only one team is left, it is the champion.
+fn next_brackets(winners: &[i32]) -> Vec<Vec<i32>> {
+    winners.chunks(2).map(<[i32]>::to_vec).collect()
+}
+
+/// Drops the moves that leave the mover's playoff bracket, which the server turns down (see
+/// [`check_bracket`]), so that between playoff days each bracket only plays itself.
+fn bracket_moves(
+    players: Vec<PlayerMoves>,
+    territories: &[TerritoryOwners],
+    brackets: &[Vec<i32>],
+) -> Vec<PlayerMoves> {
+    players
+        .into_iter()
+        .filter(|player| {
+            !territories.iter().any(|territory| {
+                territory.territory_id == player.territory
+                    && check_bracket(player.team, territory.owner_id, brackets).is_err()
+            })
+        })
+        .collect()
+}
+
+/// Whether `day` is one of the `risk.playoffs.days`, which advance the playoffs instead of
+/// rolling.
+fn playoff_day(settings: &rocket::figment::Figment, day: i32) -> bool {
+    settings
+        .extract_inner::<Vec<i32>>("risk.playoffs.days")
+        .unwrap_or_default()
+        .contains(&day)
+}
+
+/// Splits the map evenly between the brackets, then evenly between the teams of each bracket.
+fn playoff_owners(
+    brackets: &[Vec<i32>],
+    territories: &[TerritoryOwners],
+    next_turn: i32,
+) -> Vec<TerritoryOwnersInsert> {
+    let mut owners = Vec::new();
+    for (bracket, teams) in brackets.iter().enumerate() {
+        let start = bracket * territories.len() / brackets.len();
+        let end = (bracket + 1) * territories.len() / brackets.len();
+        // Within a bracket, hand territories out to its teams in turn
+        for (i, territory) in territories[start..end].iter().enumerate() {
+            owners.push(TerritoryOwnersInsert::new(
+                territory,
+                next_turn,
+                teams[i % teams.len()],
+                None,
+                None,
+            ));
+        }
+    }
+    owners
+}
+
+/// Advances the playoffs on a turn flagged as a playoff day, in place of a normal roll.
+/// Steps:
+/// 1. For each bracket, determine the winner (a tie is randomly broken)
+/// 2. Create new territory ownership by bracket
+/// 3. Create new statistics, giving each team equal territories and zero players
+/// 4. Pop on the new day
+///
+/// On the first playoff day of a season, the brackets come from `risk.playoffs.brackets`
+/// instead of from the previous round.
+/// Because we're not technically carrying out a new day, we don't add anything to any player account.
+fn do_playoffs(
+    turninfoblock: &mut TurnInfo,
+    options: &RollOptions,
+    settings: &rocket::figment::Figment,
+    seed: &mut ChaCha12Rng,
+    store: &dyn Store,
+) -> Result<Option<Rolled>, Box<dyn std::error::Error>> {
+    let territories = store.owners(turninfoblock.id)?;
+    if territories.is_empty() {
+        return Err("No territories to divide between the playoff brackets".into());
+    }
+    let brackets = match store.playoff_brackets(turninfoblock.season, turninfoblock.id)? {
+        current if current.is_empty() => {
+            settings.extract_inner::<Vec<Vec<i32>>>("risk.playoffs.brackets")?
+        }
+        current => next_brackets(&bracket_winners(&current, &territories, seed)),
+    };
+    if brackets.is_empty() || brackets.iter().any(Vec::is_empty) {
+        return Err("Every playoff bracket needs at least one team".into());
+    }
+    let next_turn = create_next_turn(turninfoblock, options, store)?;
+    let owners = playoff_owners(&brackets, &territories, next_turn);
+    let mut stats: BTreeMap<i32, Stats> = BTreeMap::new();
+    for owner in &owners {
+        stats
+            .entry(owner.owner_id)
+            .or_insert_with(|| Stats::new(next_turn, owner.owner_id))
+            .territorycount += 1;
+    }
+    if options.dry_run {
+        write_dry_run(
+            turninfoblock,
+            &owners,
+            &[],
+            &stats,
+            &[],
+            options.output.as_deref(),
+        )?;
+        return Ok(None);
+    }
+    let report = RollReport::new(turninfoblock, &owners, &stats, &[], Vec::new());
+    store.insert_stats(stats, turninfoblock.id)?;
+    store.insert_owners(&owners)?;
+    store.insert_playoff_brackets(&brackets, turninfoblock.season, next_turn)?;
+    println!("Playoff brackets drawn up: {brackets:?}");
+
+    // A single bracket with a single team means we have a champion, who now owns the whole
+    // map, so the season ends like any other conquest.
+    finish_turn(
+        turninfoblock,
+        &owners,
+        false,
+        &store.rules(turninfoblock.season)?,
+        settings,
+        store,
+    )?;
+    Ok(Some((owners, report)))
+}
+
+/// The teams that hold territory without playing, from `risk.neutral_teams`.
+fn neutral_teams(settings: &rocket::figment::Figment) -> Vec<i32> {
+    settings
+        .extract_inner::<Vec<i32>>("risk.neutral_teams")
+        .unwrap_or_default()
+}
+
+/// Puts eliminated teams back on the map, returning the respawns.
+///
+/// A team respawns when it owns no territory after the roll but has at least `min_players`
+/// committed players (see [`Respawn::committed_players`]). Teams respawn in order of their id,
+/// each on a territory drawn with the roll's RNG from the unowned ones, held by team 0 or a
+/// `neutral` team. Once there are none of those left, the territory is drawn from those held by
+/// teams with more than one, so a respawn never eliminates another team.
+fn respawn(
+    owners: &mut [TerritoryOwnersInsert],
+    stats: &mut BTreeMap<i32, Stats>,
+    committed: &BTreeMap<i32, i32>,
+    min_players: i32,
+    neutral: &[i32],
+    seed: &mut ChaCha12Rng,
+) -> Vec<Respawn> {
+    let mut territories: BTreeMap<i32, i32> = BTreeMap::new();
+    for owner in owners.iter() {
+        *territories.entry(owner.owner_id).or_default() += 1;
+    }
+    let mut respawns = Vec::new();
+    for (&team, &players) in committed {
+        // Team 0 is the placeholder for unowned territory, and can't be joined
+        if team <= 0
+            || players < min_players
+            || neutral.contains(&team)
+            || territories.contains_key(&team)
+        {
+            continue;
+        }
+        let held_by = |spare: &dyn Fn(i32) -> bool| {
+            owners
+                .iter()
+                .enumerate()
+                .filter(|(_, owner)| spare(owner.owner_id))
+                .map(|(index, _)| index)
+                .collect::<Vec<usize>>()
+        };
+        let mut candidates = held_by(&|owner| owner == 0 || neutral.contains(&owner));
+        if candidates.is_empty() {
+            candidates = held_by(&|owner| territories.get(&owner).copied().unwrap_or(0) > 1);
+        }
+        let owner = match candidates.choose(seed) {
+            Some(&index) => &mut owners[index],
+            // Nobody has territory to spare
+            None => continue,
+        };
+        let previous_owner = owner.owner_id;
+        owner.owner_id = team;
+        *territories.entry(previous_owner).or_default() -= 1;
+        territories.insert(team, 1);
+        if let Some(stat) = stats.get_mut(&previous_owner) {
+            stat.territorycount -= 1;
+        }
+        let stat = stats
+            .entry(team)
+            .or_insert_with(|| Stats::new(owner.turn_id, team));
+        stat.territorycount += 1;
+        stat.respawned = true;
+        respawns.push(Respawn {
+            team,
+            territory: owner.territory_id,
+            previous_owner,
+            players,
+        });
+    }
+    respawns
+}
+
+/// Checks whether the roll of `season`/`day` ended the season, returning the winners if so.
+///
+/// The season is over once one team owns every territory, or once every team other than the
+/// `neutral` ones (which hold territory without playing) has been eliminated. Otherwise it
+/// runs until `final_day` has been rolled, and whoever owns the most territories then wins;
+/// teams tied for the most territories all win. Unowned territory (team 0) and neutral teams
+/// never win, so a map left with neither a playing team nor a winner has no result.
+fn season_winners(
+    season: i32,
+    day: i32,
+    owners: &[TerritoryOwnersInsert],
+    final_day: Option<i32>,
+    neutral: &[i32],
+) -> Option<Vec<SeasonWinner>> {
+    let mut territories: BTreeMap<i32, i32> = BTreeMap::new();
+    for owner in owners {
+        *territories.entry(owner.owner_id).or_default() += 1;
+    }
+    territories.retain(|&team, _| team != 0 && !neutral.contains(&team));
+    let winners = |reason: &str, teams: BTreeMap<i32, i32>| {
+        teams
+            .into_iter()
+            .map(|(team, territories)| SeasonWinner {
+                season,
+                day,
+                team,
+                territories,
+                reason: String::from(reason),
+            })
+            .collect()
+    };
+    if territories.is_empty() {
+        return None;
+    }
+    if territories.len() == 1 {
+        let conquest = territories
+            .values()
+            .all(|&count| count as usize == owners.len());
+        let reason = if conquest { "conquest" } else { "elimination" };
+        return Some(winners(reason, territories));
+    }
+    // Otherwise the season carries on until the final day
+    final_day.filter(|&final_day| day >= final_day)?;
+    let most = territories.values().max().copied().unwrap_or(0);
+    territories.retain(|_, count| *count == most);
+    Some(winners("final_day", territories))
+}
+
+/// Creates the day after `turninfoblock`, returning its id. The roll's new owners, brackets
+/// and chaos bridges belong to this turn, so it is created before any of them are written; it
+/// takes no moves until [`finish_turn`] opens it. A dry run writes nothing, so its new owners
+/// are shown on turn 0.
+fn create_next_turn(
+    turninfoblock: &TurnInfo,
+    options: &RollOptions,
+    store: &dyn Store,
+) -> Result<i32, diesel::result::Error> {
+    if options.dry_run {
+        return Ok(0);
+    }
+    store.insert_turn(&NewTurn {
+        game: turninfoblock.game,
+        season: turninfoblock.season,
+        day: turninfoblock.day + 1,
+        active: false,
+        finale: false,
+        playoffs: false,
+        map: turninfoblock.map.clone(),
+        allornothingenabled: false,
+        start_time: None,
+    })
+}
+
+/// Marks the rolled turn complete and opens the next day.
+///
+/// If the roll ended the season (see [`season_winners`]), the turn is flagged as the finale and
+/// the winners are recorded. The final map stays on the day [`create_next_turn`] made for it,
+/// which is never opened, so nothing is rolled after the finale.
+fn finish_turn(
+    turninfoblock: &mut TurnInfo,
+    owners: &[TerritoryOwnersInsert],
+    allornothingenabled: bool,
+    rules: &Rules,
+    settings: &rocket::figment::Figment,
+    store: &dyn Store,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let winners = season_winners(
+        turninfoblock.season,
+        turninfoblock.day,
+        owners,
+        rules.final_day,
+        &neutral_teams(settings),
+    );
+    turninfoblock.rollendtime = Some(Utc::now().naive_utc());
+    turninfoblock.complete = Some(true);
+    turninfoblock.active = Some(false);
+    turninfoblock.finale = Some(winners.is_some());
+    turninfoblock.roll_state = RollState::Complete;
+    store.update_turn(turninfoblock)?;
+    println!("Update turninfo success.");
+    match winners {
+        Some(winners) => {
+            store.insert_season_winners(&winners)?;
+            println!(
+                "Season {} is over: won by {:?}",
+                turninfoblock.season,
+                winners
+                    .iter()
+                    .map(|winner| winner.team)
+                    .collect::<Vec<i32>>()
+            );
+        }
+        None => {
+            // Reopens the day the roll's owners went to, keeping its id and seed
+            store.insert_turn(&NewTurn {
+                game: turninfoblock.game,
+                season: turninfoblock.season,
+                day: turninfoblock.day + 1,
+                active: true,
+                finale: false,
+                playoffs: playoff_day(settings, turninfoblock.day + 1),
+                map: turninfoblock.map.clone(),
+                allornothingenabled,
+                start_time: next_roll(settings),
+            })?;
+            println!("Open new turn succeeded");
+        }
+    }
+    Ok(())
 }
 
 fn next_roll(settings: &rocket::figment::Figment) -> Option<NaiveDateTime> {
@@ -704,98 +1260,337 @@ fn next_day_in_seq(
     )
 }
 
-fn runtime() -> Result<(), diesel::result::Error> {
-    let rocket = rocket::build();
-    let settings = rocket.figment();
-    // Connect to the Postgres DB
-    let conn: PgConnection = establish_connection();
-    // Get the active turn
-    // start_time_now then sets the start time to the current time.
-    let mut turninfoblock = TurnInfo::get_latest(&conn)?;
-    turninfoblock.start_time_now();
-    // Prevent new moves from being submitted
-    turninfoblock.lock(&conn)?;
+/// Prints (or writes to `output`) what the roll would have done, without touching the database.
+fn write_dry_run(
+    turninfoblock: &TurnInfo,
+    owners: &[TerritoryOwnersInsert],
+    mvps: &[PlayerMoves],
+    stats: &BTreeMap<i32, Stats>,
+    territory_stats: &[TerritoryStats],
+    output: Option<&std::path::Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let preview = RollPreview {
+        turn_id: turninfoblock.id,
+        season: turninfoblock.season,
+        day: turninfoblock.day,
+        owners,
+        mvps,
+        stats: Stats::rank(stats, turninfoblock.id),
+        territory_stats,
+    };
+    match output {
+        Some(path) => {
+            let file = std::fs::File::create(path)?;
+            serde_json::to_writer_pretty(file, &preview)?;
+            println!("Dry run written to {}", path.display());
+        }
+        None => println!("{}", serde_json::to_string_pretty(&preview)?),
+    }
+    Ok(())
+}
+
+/// Performs the roll for the locked turn `turninfoblock`, returning the new territory owners.
+/// Every write happens through `conn`, so when this runs inside a transaction a failure at any
+/// point leaves the database exactly as it was before the roll.
+fn roll(
+    turninfoblock: &mut TurnInfo,
+    options: &RollOptions,
+    settings: &rocket::figment::Figment,
+    store: &dyn Store,
+) -> Result<Option<Rolled>, Box<dyn std::error::Error>> {
     //dbg!(&turninfoblock.season, &turninfoblock.day);
-    // Now we go get all player moves for the current day
-    let players = PlayerMoves::load(&turninfoblock.id, &conn)?;
-    // And a list of all territories, and their current owners:
-    let territories = TerritoryOwners::load(&turninfoblock.id, &conn)?;
-    // If there are no moves to load, we'll exit as something's not right.
-    // TODO: Return Err, not Ok
-    if players.is_empty() {
-        return Ok(());
+    // The seed was committed to when the turn was created; turns created before
+    // seeds existed get one now. A dry run must not publish anything, so it uses a throwaway.
+    let seed = match (&turninfoblock.seed, options.dry_run) {
+        (Some(seed), _) => seed.clone(),
+        (None, false) => store.commit_seed(turninfoblock)?,
+        (None, true) => seed::generate().0,
+    };
+    if turninfoblock.playoffs == Some(true) {
+        return do_playoffs(
+            turninfoblock,
+            options,
+            settings,
+            &mut seed::rng(&seed)?,
+            store,
+        );
     }
+    // Now we go get a list of all territories, and their current owners:
+    let territories = store.owners(turninfoblock.id)?;
+    // And all player moves for the current day. During the playoffs, teams only play the
+    // other teams in their bracket.
+    let players = bracket_moves(
+        store.moves(turninfoblock.id)?,
+        &territories,
+        &store.playoff_brackets(turninfoblock.season, turninfoblock.id)?,
+    );
+    let mut previous_teams = territories
+        .iter()
+        .map(|territory| territory.owner_id)
+        .collect::<Vec<i32>>();
+    previous_teams.sort_unstable();
+    previous_teams.dedup();
+    // A day nobody moved on is rolled like any other: every territory stays with its owner.
     //let move_ids = players.iter().map(|x| x.id).collect::<Vec<i32>>();
-    // We pass in an entropy-driven randomy number, since we're not testing
-    let (owners, mvps, stats, territory_stats) = process_territories(
+    let rules = store.rules(turninfoblock.season)?;
+    let strategy = rules.strategy()?.victor_strategy();
+    let mut rng = seed::rng(&seed)?;
+    let next_turn = create_next_turn(turninfoblock, options, store)?;
+    let (mut owners, mvps, mut stats, territory_stats) = process_territories(
         territories,
         players,
-        &mut ChaCha12Rng::from_entropy(),
+        next_turn,
+        &mut rng,
+        strategy.as_ref(),
+        rules.alt_cutoff,
         false,
     );
-    TerritoryStats::insert(territory_stats, &conn)?;
-    Stats::insert(stats, turninfoblock.id, &conn)?;
-    let territory_insert = TerritoryOwnersInsert::insert(&owners, &conn)?;
-    dbg!(territory_insert);
-    let playermoves = PlayerMoves::mvps(mvps, &conn)?;
-    dbg!(playermoves);
-
-    // This calls an SQL function that updates each user's statistics
-    // Not ideal, TODO: we ought to implement this in Rust.
-    let userupdate = user_update(&turninfoblock, &conn);
-    match userupdate {
-        Ok(ok) => println!("Users updated successfully {}", ok[0].do_user_update),
-        Err(e) => println!("Failed to update users: {e:?}"),
+    let respawns = match rules.respawn_players {
+        Some(min_players) => respawn(
+            &mut owners,
+            &mut stats,
+            &store.committed_players()?,
+            min_players,
+            &neutral_teams(settings),
+            &mut rng,
+        ),
+        None => Vec::new(),
+    };
+    for respawned in &respawns {
+        println!(
+            "Team {} respawned on territory {}",
+            respawned.team, respawned.territory
+        );
     }
-    turninfoblock.rollendtime = Some(Utc::now().naive_utc());
-    turninfoblock.complete = Some(true);
-    turninfoblock.active = Some(false);
-    match TurnInfo::update_or_insert(&turninfoblock, &conn) {
-        Ok(_ok) => {
-            println!("Update turninfo success.")
+    if options.dry_run {
+        write_dry_run(
+            turninfoblock,
+            &owners,
+            &mvps,
+            &stats,
+            &territory_stats,
+            options.output.as_deref(),
+        )?;
+        return Ok(None);
+    }
+    let report = RollReport::new(turninfoblock, &owners, &stats, &territory_stats, respawns);
+    store.insert_territory_stats(territory_stats)?;
+    store.insert_stats(stats, turninfoblock.id)?;
+    let territory_insert = store.insert_owners(&owners)?;
+    println!("Territory owners inserted {territory_insert}");
+    let playermoves = store.set_mvps(&mvps)?;
+    println!("MVPs recorded {playermoves}");
+
+    // Now we update each user's statistics
+    let userupdate = user_update(turninfoblock, &previous_teams, &owners, &rules, store)?;
+    println!("Users updated successfully {userupdate}");
+    let aone = (turninfoblock.allornothingenabled == Some(true)
+        || (turninfoblock.day + 1) >= rules.aon_start)
+        && (turninfoblock.day + 1) < rules.aon_end;
+    finish_turn(turninfoblock, &owners, aone, &rules, settings, store)?;
+
+    #[cfg(feature = "chaos")]
+    {
+        chaos_update(
+            &owners,
+            turninfoblock.id,
+            next_turn,
+            &mut rng,
+            settings,
+            store,
+        )?;
+        println!("Chaos bridges updated.");
+    }
+    Ok(Some((owners, report)))
+}
+
+/// Rolls the active turn of the game called `game`.
+fn runtime(game: &str, options: &RollOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let rocket = rocket::build();
+    let settings = rocket.figment();
+    // Connect to the Postgres DB
+    let conn: PgConnection = establish_connection();
+    let store = PgStore::new(&conn);
+    let game = Game::load(game, &conn).map_err(|e| format!("Could not load game {game}: {e}"))?;
+    // Get the active turn, or a turn left locked by a ringmaster that stopped mid-roll
+    // start_time_now then sets the start time to the current time.
+    let mut turninfoblock = match store.rollable_turn(game.id) {
+        Ok(turninfoblock) => turninfoblock,
+        // Once a season's finale has been rolled, no turn is left active until the next season
+        Err(diesel::result::Error::NotFound) => {
+            println!("No active turn to roll in game {}.", game.name);
+            return Ok(());
         }
-        Err(_e) => {
-            println!("Error updating turninfo.")
+        Err(e) => return Err(e.into()),
+    };
+    turninfoblock.start_time_now();
+    // A dry run only reads, so there is nothing to lock or roll back
+    if options.dry_run {
+        roll(&mut turninfoblock, options, settings, &store)?;
+        return Ok(());
+    }
+    // Only one ringmaster may roll a turn at a time. Once we hold the turn's lock, check its
+    // state again: another ringmaster may have finished rolling it since we loaded it.
+    let _roll_lock = AdvisoryLock::roll(turninfoblock.id, &conn)?
+        .ok_or_else(|| format!("Turn {} is already being rolled", turninfoblock.id))?;
+    match TurnInfo::roll_state(turninfoblock.id, &conn)? {
+        RollState::Complete => {
+            return Err(format!("Turn {} has already been rolled", turninfoblock.id).into())
         }
+        // Nobody holds the lock, so the ringmaster that left the turn like this stopped
+        // before its transaction committed, and nothing was written.
+        state @ (RollState::Locked | RollState::Rolling) => eprintln!(
+            "An earlier roll of turn {} stopped while {state}; rolling it again",
+            turninfoblock.id
+        ),
+        RollState::Pending | RollState::Failed => {}
     }
-    let aone = (turninfoblock.allornothingenabled == Some(true)
-        || (turninfoblock.day + 1) >= AON_START)
-        && (turninfoblock.day + 1) < AON_END;
-    match TurnInfo::insert_new(
-        turninfoblock.season,
-        turninfoblock.day + 1,
-        true,
-        false,
-        turninfoblock.map,
-        aone,
-        next_roll(settings),
-        &conn,
-    ) {
-        Ok(_ok) => {
-            println!("Create new turn succeeded")
+    // Prevent new moves from being submitted. This is committed straight away (outside of
+    // the roll's transaction) so that the server stops taking moves while we roll.
+    let started = std::time::Instant::now();
+    turninfoblock.lock(&conn)?;
+    turninfoblock.begin_roll(&conn)?;
+    // The whole roll is one transaction: either every row is written, or none are.
+    // Only a dry run rolls without writing anything, so a roll that comes back empty is rolled
+    // back and reported like any other failure.
+    match conn.transaction::<_, Box<dyn std::error::Error>, _>(|| {
+        roll(&mut turninfoblock, options, settings, &store)?
+            .ok_or_else(|| format!("Roll of turn {} wrote nothing", turninfoblock.id).into())
+    }) {
+        Ok((_owners, mut report)) => {
+            // The roll is committed by now, so a report that can't be written is only logged
+            if let Err(e) = report
+                .finish(&turninfoblock, started.elapsed())
+                .write(settings)
+            {
+                eprintln!("Could not write the roll report: {e}");
+            }
+            #[cfg(feature = "risk_image")]
+            optional::image::make_image(&_owners, &conn);
+            Ok(())
+        }
+        Err(e) => {
+            // Nothing was written, so reopen the turn for moves as it was before the roll.
+            eprintln!(
+                "Roll failed and was rolled back; reopening turn {}",
+                turninfoblock.id
+            );
+            turninfoblock.unlock(&conn)?;
+            Err(e)
         }
-        Err(e) => println!("Failed to make new turn {e:?}"),
     }
+}
 
-    #[cfg(feature = "risk_image")]
-    optional::image::make_image(&owners, &conn);
+/// Replays the roll of the game called `game` for `season`/`day` and reports any differences
+/// from the recorded roll.
+fn replay_runtime(game: &str, season: i32, day: i32) -> Result<(), Box<dyn std::error::Error>> {
+    let conn: PgConnection = establish_connection();
+    let rocket = rocket::build();
+    let game = Game::load(game, &conn)?;
+    let discrepancies = replay::replay(game.id, season, day, rocket.figment(), &conn)?;
+    if discrepancies.is_empty() {
+        println!("Replay of season {season} day {day} matches the recorded roll.");
+        return Ok(());
+    }
+    for discrepancy in &discrepancies {
+        println!("{discrepancy}");
+    }
+    Err(format!(
+        "Replay of season {season} day {day} differs from the recorded roll in {} places",
+        discrepancies.len()
+    )
+    .into())
+}
 
-    #[cfg(feature = "chaos")]
-    {
-        match chaos_update(&owners, turninfoblock.id + 1, settings, &conn) {
-            Ok(_) => println!("Chaos bridges updated."),
-            Err(e) => println!("Chaos bridges couldn't update. \n Error: {:?}", e),
-        }
+/// Undoes the last roll of the game called `game` on behalf of `by`.
+fn undo_runtime(game: &str, by: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let rocket = rocket::build();
+    let conn: PgConnection = establish_connection();
+    let store = PgStore::new(&conn);
+    let game = Game::load(game, &conn).map_err(|e| format!("Could not load game {game}: {e}"))?;
+    // Hold the lock on the turn taking moves, so that it isn't rolled while its roll is undone
+    let _roll_lock = match store.latest_turn(game.id) {
+        Ok(active) => Some(
+            AdvisoryLock::roll(active.id, &conn)?
+                .ok_or_else(|| format!("Turn {} is being rolled", active.id))?,
+        ),
+        Err(diesel::result::Error::NotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let turn = conn.transaction::<_, Box<dyn std::error::Error>, _>(|| {
+        undo::undo(game.id, by, rocket.figment(), &store)
+    })?;
+    println!(
+        "Undid the roll of turn {} (season {} day {}) of game {}; it is open for moves again.",
+        turn.id, turn.season, turn.day, game.name
+    );
+    Ok(())
+}
+
+/// Reads and validates the map file at `path`, printing each problem found.
+fn check_map(path: &Path) -> Result<Map, Box<dyn std::error::Error>> {
+    let map = Map::read(path)?;
+    let errors = map.validate();
+    if errors.is_empty() {
+        println!(
+            "Map {} is valid: {} territories in {} regions.",
+            map.name,
+            map.territories.len(),
+            map.regions.len()
+        );
+        return Ok(map);
+    }
+    for error in &errors {
+        println!("{error}");
     }
+    Err(format!("Map {} has {} problems", map.name, errors.len()).into())
+}
+
+fn load_map(path: &Path, season: i32, game: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let map = check_map(path)?;
+    let rocket = rocket::build();
+    let conn: PgConnection = establish_connection();
+    let game = Game::load(game, &conn).map_err(|e| format!("Could not load game {game}: {e}"))?;
+    let turn = map.load(game.id, season, next_roll(rocket.figment()), &conn)?;
+    println!(
+        "Loaded map {} as turn {} (season {season} day 1) of game {}.",
+        map.name, turn.id, game.name
+    );
     Ok(())
 }
 
 /// Main function; runs RNG to determine new day parameters.
 fn main() {
     use std::time::Instant;
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {e}");
+            eprintln!("usage: rrringmaster [--game <name>] [--dry-run [--output <file>]]");
+            eprintln!("       rrringmaster [--game <name>] --daemon");
+            eprintln!("       rrringmaster replay <season> <day> [--game <name>]");
+            eprintln!("       rrringmaster map check <file>");
+            eprintln!("       rrringmaster map load <file> <season> [--game <name>]");
+            eprintln!("       rrringmaster undo --by <name> [--game <name>]");
+            std::process::exit(2);
+        }
+    };
     // Set up variables to know timing
     let now = Instant::now();
-    let state = runtime();
+    let state = match command {
+        Command::Roll(options) if options.daemon => daemon::run(&options),
+        Command::Roll(options) => {
+            runtime(options.game.as_deref().unwrap_or(DEFAULT_GAME), &options)
+        }
+        Command::Replay { season, day, game } => {
+            replay_runtime(game.as_deref().unwrap_or(DEFAULT_GAME), season, day)
+        }
+        Command::CheckMap { path } => check_map(&path).map(|_| ()),
+        Command::LoadMap { path, season, game } => {
+            load_map(&path, season, game.as_deref().unwrap_or(DEFAULT_GAME))
+        }
+        Command::Undo { by, game } => undo_runtime(game.as_deref().unwrap_or(DEFAULT_GAME), &by),
+    };
     let elapsed = now.elapsed();
     let end = Instant::now();
     println!("Elapsed: {elapsed:.2?}");
@@ -814,6 +1609,8 @@ fn main() {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::lottery::Lottery;
+    use crate::store::{MemoryStore, MemoryUser};
     use chrono::{NaiveDate, NaiveTime};
 
     #[test]
@@ -970,10 +1767,674 @@ mod tests {
         }
     }
 
+    fn playoff_territories(owners: &[i32]) -> Vec<TerritoryOwners> {
+        owners
+            .iter()
+            .zip(1..)
+            .map(|(&owner_id, territory_id)| TerritoryOwners {
+                id: territory_id,
+                territory_id,
+                owner_id,
+                turn_id: 10,
+                previous_owner_id: owner_id,
+                random_number: 0.0,
+                mvp: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bracket_winners() {
+        let territories = playoff_territories(&[1, 1, 2, 3, 4, 4]);
+        let brackets = vec![vec![1, 2], vec![3, 4]];
+        assert_eq!(
+            vec![1, 4],
+            bracket_winners(&brackets, &territories, &mut ChaCha12Rng::seed_from_u64(55))
+        );
+        // A tie goes to one of the tied teams, and the same seed always picks the same one
+        let tied = playoff_territories(&[1, 2, 3]);
+        let winner = bracket_winners(&[vec![1, 2, 3]], &tied, &mut ChaCha12Rng::seed_from_u64(55));
+        assert_eq!(
+            winner,
+            bracket_winners(&[vec![1, 2, 3]], &tied, &mut ChaCha12Rng::seed_from_u64(55))
+        );
+        assert!([1, 2, 3].contains(&winner[0]));
+    }
+
+    #[test]
+    fn test_next_brackets() {
+        assert_eq!(vec![vec![1, 4], vec![5]], next_brackets(&[1, 4, 5]));
+        assert_eq!(vec![vec![4]], next_brackets(&[4]));
+    }
+
+    #[test]
+    fn test_playoff_owners() {
+        let territories = playoff_territories(&[1, 1, 1, 2, 2, 3, 3, 4, 4]);
+        // Another game's turns may sit between this turn and the next
+        let owners = playoff_owners(&[vec![1, 2], vec![3, 4, 5]], &territories, 14);
+        assert_eq!(
+            vec![1, 2, 1, 2, 3, 4, 5, 3, 4],
+            owners.iter().map(|o| o.owner_id).collect::<Vec<i32>>()
+        );
+        assert!(owners.iter().all(|o| o.turn_id == 14 && o.mvp.is_none()));
+        assert_eq!(
+            vec![7; 9],
+            playoff_owners(&[vec![7]], &territories, 14)
+                .iter()
+                .map(|o| o.owner_id)
+                .collect::<Vec<i32>>()
+        );
+    }
+
+    #[test]
+    fn test_bracket_moves() {
+        let territories = playoff_territories(&[1, 2, 3, 4]);
+        let moves = vec![
+            memory_move(1, 1, 1, 2),
+            memory_move(2, 2, 1, 3),
+            memory_move(3, 3, 4, 3),
+            memory_move(4, 4, 3, 3),
+        ];
+        let kept = |brackets: &[Vec<i32>]| {
+            bracket_moves(moves.clone(), &territories, brackets)
+                .iter()
+                .map(|player| player.id)
+                .collect::<Vec<i32>>()
+        };
+        assert_eq!(vec![1, 3, 4], kept(&[vec![1, 2], vec![3, 4]]));
+        // Outside the playoffs, anyone may move anywhere
+        assert_eq!(vec![1, 2, 3, 4], kept(&[]));
+    }
+
+    fn new_owners(owners: &[i32]) -> Vec<TerritoryOwnersInsert> {
+        playoff_territories(owners)
+            .iter()
+            .map(|territory| {
+                TerritoryOwnersInsert::new(territory, 11, territory.owner_id, None, None)
+            })
+            .collect()
+    }
+
+    fn winners(winners: Option<Vec<SeasonWinner>>) -> Option<Vec<(i32, i32, String)>> {
+        winners.map(|winners| {
+            winners
+                .into_iter()
+                .map(|winner| (winner.team, winner.territories, winner.reason))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_season_winners_conquest() {
+        assert_eq!(
+            Some(vec![(3, 4, String::from("conquest"))]),
+            winners(season_winners(2, 30, &new_owners(&[3, 3, 3, 3]), None, &[]))
+        );
+        let winner = season_winners(2, 30, &new_owners(&[3]), Some(50), &[]).unwrap();
+        assert_eq!((2, 30), (winner[0].season, winner[0].day));
+        // Nobody conquers a map that is all unowned or all neutral
+        assert_eq!(
+            None,
+            winners(season_winners(2, 30, &new_owners(&[0, 0, 0]), None, &[]))
+        );
+        assert_eq!(
+            None,
+            winners(season_winners(2, 30, &new_owners(&[4, 4]), None, &[4]))
+        );
+    }
+
+    #[test]
+    fn test_season_winners_elimination() {
+        assert_eq!(
+            Some(vec![(3, 2, String::from("elimination"))]),
+            winners(season_winners(
+                2,
+                30,
+                &new_owners(&[3, 0, 3, 0]),
+                None,
+                &[0]
+            ))
+        );
+        // Unowned territory does not keep a season going
+        assert_eq!(
+            Some(vec![(3, 2, String::from("elimination"))]),
+            winners(season_winners(2, 30, &new_owners(&[3, 0, 3, 0]), None, &[]))
+        );
+        assert_eq!(
+            None,
+            winners(season_winners(2, 30, &new_owners(&[4, 0, 4]), None, &[4]))
+        );
+    }
+
+    #[test]
+    fn test_season_winners_final_day() {
+        let owners = new_owners(&[1, 2, 2, 3, 3, 0, 0, 0]);
+        assert_eq!(
+            None,
+            winners(season_winners(2, 49, &owners, Some(50), &[0]))
+        );
+        assert_eq!(
+            Some(vec![
+                (2, 2, String::from("final_day")),
+                (3, 2, String::from("final_day"))
+            ]),
+            winners(season_winners(2, 50, &owners, Some(50), &[0]))
+        );
+        // Team 0 holds the most territory, but it is unowned
+        assert_eq!(
+            Some(vec![
+                (2, 2, String::from("final_day")),
+                (3, 2, String::from("final_day"))
+            ]),
+            winners(season_winners(2, 51, &owners, Some(50), &[]))
+        );
+        assert_eq!(
+            None,
+            winners(season_winners(2, 50, &new_owners(&[0, 4]), Some(50), &[4]))
+        );
+    }
+
+    #[test]
+    fn test_respawn_unowned() {
+        let mut owners = new_owners(&[1, 0, 1, 2]);
+        let mut stats = BTreeMap::from([(1, Stats::new(5, 1)), (2, Stats::new(5, 2))]);
+        stats.get_mut(&1).unwrap().territorycount = 2;
+        // Team 3 has enough players, team 4 doesn't and team 2 was never eliminated
+        let committed = BTreeMap::from([(2, 9), (3, 5), (4, 4)]);
+        let respawns = respawn(
+            &mut owners,
+            &mut stats,
+            &committed,
+            5,
+            &[],
+            &mut ChaCha12Rng::seed_from_u64(55),
+        );
+        assert_eq!(
+            vec![Respawn {
+                team: 3,
+                territory: owners[1].territory_id,
+                previous_owner: 0,
+                players: 5,
+            }],
+            respawns
+        );
+        assert_eq!(3, owners[1].owner_id);
+        assert_eq!((1, true), (stats[&3].territorycount, stats[&3].respawned));
+        assert_eq!(2, stats[&1].territorycount);
+    }
+
+    #[test]
+    fn test_respawn_spare_territory() {
+        // Nothing is unowned, and team 2 can't spare its only territory
+        let mut owners = new_owners(&[1, 1, 2]);
+        let mut stats = BTreeMap::from([(1, Stats::new(5, 1))]);
+        stats.get_mut(&1).unwrap().territorycount = 2;
+        let committed = BTreeMap::from([(0, 20), (3, 1), (4, 1)]);
+        let respawns = respawn(
+            &mut owners,
+            &mut stats,
+            &committed,
+            1,
+            &[],
+            &mut ChaCha12Rng::seed_from_u64(55),
+        );
+        // Team 3 takes one of team 1's, after which nobody has a territory to spare for team 4
+        assert_eq!(
+            vec![(3, 1)],
+            respawns
+                .iter()
+                .map(|respawned| (respawned.team, respawned.previous_owner))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![1, 1, 1],
+            [1, 2, 3]
+                .iter()
+                .map(|team| owners.iter().filter(|o| o.owner_id == *team).count())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, stats[&1].territorycount);
+        // Team 4 is still eliminated, but no longer has enough players to respawn
+        let respawns = respawn(
+            &mut owners,
+            &mut stats,
+            &committed,
+            2,
+            &[],
+            &mut ChaCha12Rng::seed_from_u64(55),
+        );
+        assert!(respawns.is_empty());
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn test_chaos_targets() {
+        let owners = new_owners(&[1; 8]);
+        let draw = |seed: u64, min, max| {
+            chaos_targets(&owners, 3, min, max, &mut ChaCha12Rng::seed_from_u64(seed))
+        };
+        for seed in 0..20 {
+            let targets = draw(seed, 2, 4);
+            assert!((2..=4).contains(&targets.len()));
+            assert!(!targets.contains(&3));
+            assert!(targets.iter().all(|target| (1..=8).contains(target)));
+            let mut unique = targets.clone();
+            unique.sort_unstable();
+            unique.dedup();
+            assert_eq!(targets.len(), unique.len());
+            assert_eq!(targets, draw(seed, 2, 4));
+        }
+        // There are only seven territories other than the chaos territory to bridge to
+        assert_eq!(7, draw(0, 10, 10).len());
+        assert_eq!(5, draw(0, 5, 5).len());
+    }
+
+    fn user(streak: i32, turns: i32, game_turns: i32, mvps: i32) -> UserRatings {
+        UserRatings {
+            id: 7,
+            turns: Some(turns),
+            game_turns: Some(game_turns),
+            mvps: Some(mvps),
+            streak: Some(streak),
+            overall: Some(1),
+            playing_for: 2,
+        }
+    }
+
+    fn user_turns(turns: i32, game_turns: i32, mvps: i32, played: bool) -> UserTurns {
+        UserTurns {
+            user_id: 7,
+            turns,
+            game_turns,
+            mvps,
+            played,
+            in_game: true,
+        }
+    }
+
+    #[test]
+    fn test_update_user_played() {
+        let updated = update_user(
+            &user(9, 49, 24, 9),
+            Some(&user_turns(50, 25, 10, true)),
+            &[1, 2],
+            &Rules::default(),
+        );
+        // 4 stars each for 50 turns, 25 game turns, 10 mvps and a 10 day streak
+        assert_eq!(
+            UserRatings {
+                overall: Some(4),
+                ..user(10, 50, 25, 10)
+            },
+            updated
+        );
+    }
+
+    #[test]
+    fn test_update_user_missed_turn() {
+        let updated = update_user(
+            &user(30, 100, 40, 25),
+            Some(&user_turns(100, 40, 25, false)),
+            &[1, 2],
+            &Rules::default(),
+        );
+        // 5 stars for everything but the broken streak; the median is still 5
+        assert_eq!(Some(0), updated.streak);
+        assert_eq!(Some(5), updated.overall);
+    }
+
+    #[test]
+    fn test_update_user_new_season() {
+        // Nothing played this season yet, so last season's game turns stay put
+        let updated = update_user(
+            &user(0, 10, 12, 0),
+            Some(&user_turns(11, 0, 1, false)),
+            &[2],
+            &Rules::default(),
+        );
+        assert_eq!(Some(11), updated.turns);
+        assert_eq!(Some(12), updated.game_turns);
+        assert_eq!(Some(1), updated.mvps);
+        // stars: turns 2, game turns 3, mvps 2, streak 1 => median 2
+        assert_eq!(Some(2), updated.overall);
+        assert_eq!(2, updated.playing_for);
+    }
+
+    #[test]
+    fn test_update_user_never_played() {
+        let mut new = user(0, 0, 0, 0);
+        new.turns = None;
+        new.game_turns = None;
+        new.mvps = None;
+        new.streak = None;
+        new.overall = None;
+        let updated = update_user(&new, None, &[1], &Rules::default());
+        assert_eq!(None, updated.turns);
+        assert_eq!(Some(0), updated.streak);
+        assert_eq!(Some(1), updated.overall);
+        // Team 2 no longer owns any territory
+        assert_eq!(-1, updated.playing_for);
+    }
+
+    #[test]
+    fn test_ratings_thresholds() {
+        assert_eq!(0, ratings::stars(-1, &Rules::default().streak));
+        assert_eq!(1, ratings::stars(2, &Rules::default().streak));
+        assert_eq!(2, ratings::stars(3, &Rules::default().streak));
+        assert_eq!(5, ratings::stars(1000, &Rules::default().total_turns));
+        // The median of 1, 2, 3, 5 is 2.5, which rounds up
+        assert_eq!(3, ratings::overall([5, 1, 3, 2]));
+    }
+
+    fn memory_move(id: i32, user_id: i32, team: i32, territory: i32) -> PlayerMoves {
+        PlayerMoves {
+            id,
+            user_id,
+            turn_id: 1,
+            territory,
+            mvp: false,
+            power: 5.0,
+            multiplier: 1.0,
+            weight: 5.0,
+            stars: 1,
+            team,
+            alt_score: 0,
+            merc: false,
+        }
+    }
+
+    fn memory_user(id: i32, team: i32) -> MemoryUser {
+        MemoryUser {
+            ratings: UserRatings {
+                id,
+                turns: Some(0),
+                game_turns: Some(0),
+                mvps: Some(0),
+                streak: Some(2),
+                overall: Some(1),
+                playing_for: team,
+            },
+            current_team: team,
+            is_alt: false,
+        }
+    }
+
+    /// Sets up season 1 day 1 of game 1, where team 1 is unopposed on territory 2, so the roll
+    /// is the same whatever the seed.
+    fn memory_game() -> MemoryStore {
+        let store = MemoryStore::default();
+        store
+            .insert_turn(&NewTurn {
+                game: 1,
+                season: 1,
+                day: 1,
+                active: true,
+                finale: false,
+                playoffs: false,
+                map: None,
+                allornothingenabled: false,
+                start_time: None,
+            })
+            .unwrap();
+        let turn = store.latest_turn(1).unwrap();
+        store
+            .insert_owners(&[(1, 1), (2, 2), (3, 2)].map(|(territory_id, owner_id)| {
+                TerritoryOwnersInsert {
+                    territory_id,
+                    owner_id,
+                    turn_id: turn.id,
+                    previous_owner_id: owner_id,
+                    random_number: 0.0,
+                    mvp: None,
+                }
+            }))
+            .unwrap();
+        store
+            .moves
+            .borrow_mut()
+            .extend([memory_move(1, 10, 1, 2), memory_move(2, 11, 2, 3)]);
+        store.users.borrow_mut().extend([
+            memory_user(10, 1),
+            memory_user(11, 2),
+            memory_user(12, 2),
+        ]);
+        store
+    }
+
+    #[test]
+    fn test_roll_in_memory() {
+        let store = memory_game();
+        let mut turn = store.latest_turn(1).unwrap();
+        let settings = rocket::figment::Figment::new();
+        let (owners, _report) = roll(&mut turn, &RollOptions::default(), &settings, &store)
+            .unwrap()
+            .unwrap();
+        let next = store.latest_turn(1).unwrap();
+        assert_eq!((next.season, next.day), (1, 2));
+        assert_ne!(next.id, turn.id);
+        assert_eq!(
+            owners
+                .iter()
+                .map(|owner| (owner.territory_id, owner.owner_id, owner.turn_id))
+                .collect::<Vec<(i32, i32, i32)>>(),
+            vec![(1, 1, next.id), (2, 1, next.id), (3, 2, next.id)]
+        );
+        assert_eq!(store.owners(next.id).unwrap().len(), 3);
+        assert_eq!(store.stats.borrow().len(), 2);
+        assert!(store.moves(1).unwrap().iter().all(|player| player.mvp));
+        let rolled = store.turn(1, 1, 1).unwrap();
+        assert_eq!(rolled.roll_state, RollState::Complete);
+        assert_eq!(rolled.active, Some(false));
+        // Both players kept their streaks going; the one who sat the turn out lost theirs
+        let users = store.user_ratings().unwrap();
+        assert_eq!(
+            users
+                .iter()
+                .map(|user| (user.id, user.turns, user.mvps, user.streak))
+                .collect::<Vec<_>>(),
+            vec![
+                (10, Some(1), Some(1), Some(3)),
+                (11, Some(1), Some(1), Some(3)),
+                (12, Some(0), Some(0), Some(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_roll_in_memory_without_moves() {
+        let store = memory_game();
+        store.moves.borrow_mut().clear();
+        let mut turn = store.latest_turn(1).unwrap();
+        let settings = rocket::figment::Figment::new();
+        let (owners, _report) = roll(&mut turn, &RollOptions::default(), &settings, &store)
+            .unwrap()
+            .unwrap();
+        let next = store.latest_turn(1).unwrap();
+        assert_eq!((next.season, next.day), (1, 2));
+        assert_eq!(
+            owners
+                .iter()
+                .map(|owner| (owner.territory_id, owner.owner_id, owner.turn_id))
+                .collect::<Vec<(i32, i32, i32)>>(),
+            vec![(1, 1, next.id), (2, 2, next.id), (3, 2, next.id)]
+        );
+        assert_eq!(store.turn(1, 1, 1).unwrap().roll_state, RollState::Complete);
+    }
+
+    #[test]
+    fn test_finale_in_memory() {
+        let store = memory_game();
+        store.rules.borrow_mut().insert(
+            1,
+            Rules {
+                final_day: Some(1),
+                ..Rules::default()
+            },
+        );
+        let mut turn = store.latest_turn(1).unwrap();
+        let settings = rocket::figment::Figment::new();
+        let (owners, _report) = roll(&mut turn, &RollOptions::default(), &settings, &store)
+            .unwrap()
+            .unwrap();
+        assert_eq!(store.turn(1, 1, 1).unwrap().finale, Some(true));
+        assert_eq!(
+            store
+                .season_winners
+                .borrow()
+                .iter()
+                .map(|winner| (winner.team, winner.territories))
+                .collect::<Vec<(i32, i32)>>(),
+            vec![(1, 2)]
+        );
+        // The final map is kept on the day created for it, which never opens
+        assert!(store.latest_turn(1).is_err());
+        assert_eq!(store.turns.borrow().len(), 2);
+        let next = store.turn(1, 1, 2).unwrap();
+        assert_eq!(owners[0].turn_id, next.id);
+        assert_eq!(store.owners(next.id).unwrap().len(), 3);
+        assert_eq!((next.active, next.rollstarttime), (Some(false), None));
+    }
+
+    #[test]
+    fn test_undo_in_memory() {
+        let store = memory_game();
+        let users = store.user_ratings().unwrap();
+        let mut turn = store.latest_turn(1).unwrap();
+        let settings = rocket::figment::Figment::new();
+        roll(&mut turn, &RollOptions::default(), &settings, &store)
+            .unwrap()
+            .unwrap();
+        let undone = store.latest_turn(1).unwrap();
+        let reopened = undo::undo(1, "admin", &settings, &store).unwrap();
+        assert_eq!((reopened.id, reopened.season, reopened.day), (1, 1, 1));
+        assert_eq!(store.latest_turn(1).unwrap(), reopened);
+        assert_eq!(reopened.roll_state, RollState::Pending);
+        assert_eq!(reopened.complete, Some(false));
+        assert_ne!(reopened.seed, turn.seed);
+        assert!(store.turn(1, 1, 2).is_err());
+        assert!(store.owners(undone.id).unwrap().is_empty());
+        assert!(store.stats.borrow().is_empty());
+        assert!(store.territory_stats.borrow().is_empty());
+        assert!(store.moves(1).unwrap().iter().all(|player| !player.mvp));
+        assert_eq!(store.user_ratings().unwrap(), users);
+        assert!(store.user_snapshots.borrow().is_empty());
+        assert_eq!(store.undos.borrow()[0].undone_by, "admin");
+        // The turn can be rolled again, and only the one roll can be undone
+        let mut turn = store.latest_turn(1).unwrap();
+        roll(&mut turn, &RollOptions::default(), &settings, &store)
+            .unwrap()
+            .unwrap();
+        let next = store.latest_turn(1).unwrap();
+        assert_eq!((next.season, next.day), (1, 2));
+        assert_ne!(next.id, undone.id);
+        assert_eq!(store.owners(next.id).unwrap().len(), 3);
+        assert!(store.owners(undone.id).unwrap().is_empty());
+        store.moves.borrow_mut().push(PlayerMoves {
+            turn_id: next.id,
+            ..memory_move(3, 10, 1, 1)
+        });
+        assert!(undo::undo(1, "admin", &settings, &store).is_err());
+    }
+
+    #[test]
+    fn test_roll_options() {
+        let args = |a: &[&str]| {
+            a.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+        assert_eq!(Ok(RollOptions::default()), RollOptions::parse(args(&[])));
+        assert_eq!(
+            Ok(RollOptions {
+                dry_run: true,
+                output: Some(PathBuf::from("roll.json")),
+                ..RollOptions::default()
+            }),
+            RollOptions::parse(args(&["--dry-run", "--output", "roll.json"]))
+        );
+        assert!(RollOptions::parse(args(&["--output", "roll.json"])).is_err());
+        assert!(RollOptions::parse(args(&["--dry-run", "--output"])).is_err());
+        assert!(RollOptions::parse(args(&["--roll"])).is_err());
+        assert_eq!(
+            Ok(RollOptions {
+                daemon: true,
+                ..RollOptions::default()
+            }),
+            RollOptions::parse(args(&["--daemon"]))
+        );
+        assert!(RollOptions::parse(args(&["--daemon", "--dry-run"])).is_err());
+        assert_eq!(
+            Ok(RollOptions {
+                daemon: true,
+                game: Some(String::from("test")),
+                ..RollOptions::default()
+            }),
+            RollOptions::parse(args(&["--game", "test", "--daemon"]))
+        );
+        assert!(RollOptions::parse(args(&["--game"])).is_err());
+        assert_eq!(
+            Ok(Command::Replay {
+                season: 3,
+                day: 12,
+                game: None
+            }),
+            Command::parse(args(&["replay", "3", "12"]))
+        );
+        assert_eq!(
+            Ok(Command::Replay {
+                season: 3,
+                day: 12,
+                game: Some(String::from("test"))
+            }),
+            Command::parse(args(&["replay", "3", "12", "--game", "test"]))
+        );
+        assert!(Command::parse(args(&["replay", "3", "12", "--game"])).is_err());
+        assert_eq!(
+            Ok(Command::Roll(RollOptions::default())),
+            Command::parse(args(&[]))
+        );
+        assert!(Command::parse(args(&["replay", "3"])).is_err());
+        assert!(Command::parse(args(&["replay", "3", "x"])).is_err());
+        assert!(Command::parse(args(&["replay", "3", "12", "--dry-run"])).is_err());
+        assert_eq!(
+            Ok(Command::CheckMap {
+                path: PathBuf::from("ohio.toml")
+            }),
+            Command::parse(args(&["map", "check", "ohio.toml"]))
+        );
+        assert_eq!(
+            Ok(Command::LoadMap {
+                path: PathBuf::from("ohio.toml"),
+                season: 4,
+                game: Some(String::from("test"))
+            }),
+            Command::parse(args(&["map", "load", "ohio.toml", "4", "--game", "test"]))
+        );
+        assert!(Command::parse(args(&["map", "load", "ohio.toml"])).is_err());
+        assert!(Command::parse(args(&["map", "draw", "ohio.toml"])).is_err());
+        assert_eq!(
+            Ok(Command::Undo {
+                by: String::from("admin"),
+                game: Some(String::from("test")),
+            }),
+            Command::parse(args(&["undo", "--game", "test", "--by", "admin"]))
+        );
+        assert!(Command::parse(args(&["undo"])).is_err());
+        assert!(Command::parse(args(&["undo", "--by"])).is_err());
+    }
+
     #[test]
     fn test_get_mvp_empty() {
         let playermoves: Vec<PlayerMoves> = Vec::new();
-        assert_eq!(None, get_mvp(playermoves, false));
+        assert_eq!(
+            None,
+            get_mvp(
+                playermoves,
+                Rules::default().alt_cutoff,
+                &mut ChaCha12Rng::seed_from_u64(55)
+            )
+        );
     }
 
     #[test]
@@ -989,10 +2450,17 @@ mod tests {
             weight: 1.0,
             stars: 2,
             team: 20,
-            alt_score: ALT_CUTOFF + 1,
+            alt_score: Rules::default().alt_cutoff + 1,
             merc: false,
         }];
-        assert_eq!(None, get_mvp(playermoves, false));
+        assert_eq!(
+            None,
+            get_mvp(
+                playermoves,
+                Rules::default().alt_cutoff,
+                &mut ChaCha12Rng::seed_from_u64(55)
+            )
+        );
     }
 
     #[test]
@@ -1011,7 +2479,14 @@ mod tests {
             alt_score: 0,
             merc: false,
         }];
-        assert_eq!(None, get_mvp(playermoves, false));
+        assert_eq!(
+            None,
+            get_mvp(
+                playermoves,
+                Rules::default().alt_cutoff,
+                &mut ChaCha12Rng::seed_from_u64(55)
+            )
+        );
     }
 
     #[test]
@@ -1030,7 +2505,14 @@ mod tests {
             alt_score: 0,
             merc: false,
         }];
-        assert_eq!(Some(playermoves[0].clone()), get_mvp(playermoves, false));
+        assert_eq!(
+            Some(playermoves[0].clone()),
+            get_mvp(
+                playermoves,
+                Rules::default().alt_cutoff,
+                &mut ChaCha12Rng::seed_from_u64(55)
+            )
+        );
     }
 
     #[test]
@@ -1065,7 +2547,14 @@ mod tests {
                 merc: false,
             },
         ];
-        assert_eq!(Some(playermoves[1].clone()), get_mvp(playermoves, true));
+        assert_eq!(
+            Some(playermoves[1].clone()),
+            get_mvp(
+                playermoves,
+                Rules::default().alt_cutoff,
+                &mut ChaCha12Rng::seed_from_u64(55)
+            )
+        );
     }
 
     #[test]
@@ -1083,6 +2572,7 @@ mod tests {
         let playermoves: Vec<PlayerMoves> = Vec::new();
         let new_owners = vec![TerritoryOwnersInsert::new(
             &territories[0],
+            5,
             3,
             Some(0.0),
             None,
@@ -1107,7 +2597,10 @@ mod tests {
             process_territories(
                 territories,
                 playermoves,
+                5,
                 &mut ChaCha12Rng::seed_from_u64(45),
+                &Lottery,
+                Rules::default().alt_cutoff,
                 true
             )
         );
@@ -1141,6 +2634,7 @@ mod tests {
         }];
         let new_owners = vec![TerritoryOwnersInsert::new(
             &territories[0],
+            5,
             3,
             Some(0.0),
             Some(6),
@@ -1183,7 +2677,10 @@ mod tests {
             process_territories(
                 territories,
                 playermoves,
+                5,
                 &mut ChaCha12Rng::seed_from_u64(45),
+                &Lottery,
+                Rules::default().alt_cutoff,
                 true
             )
         );
@@ -1217,6 +2714,7 @@ mod tests {
         }];
         let new_owners = vec![TerritoryOwnersInsert::new(
             &territories[0],
+            5,
             3,
             Some(0.0),
             Some(6),
@@ -1275,7 +2773,10 @@ mod tests {
             process_territories(
                 territories,
                 playermoves,
+                5,
                 &mut ChaCha12Rng::seed_from_u64(45),
+                &Lottery,
+                Rules::default().alt_cutoff,
                 true
             )
         );
@@ -1309,6 +2810,7 @@ mod tests {
         }];
         let new_owners = vec![TerritoryOwnersInsert::new(
             &territories[0],
+            5,
             6,
             Some(0.0),
             None,
@@ -1366,7 +2868,10 @@ mod tests {
             process_territories(
                 territories,
                 playermoves,
+                5,
                 &mut ChaCha12Rng::seed_from_u64(45),
+                &Lottery,
+                Rules::default().alt_cutoff,
                 true
             )
         );
@@ -1400,6 +2905,7 @@ mod tests {
         }];
         let new_owners = vec![TerritoryOwnersInsert::new(
             &territories[0],
+            5,
             3,
             Some(0.0),
             None,
@@ -1424,7 +2930,10 @@ mod tests {
             process_territories(
                 territories,
                 playermoves,
+                5,
                 &mut ChaCha12Rng::seed_from_u64(45),
+                &Lottery,
+                Rules::default().alt_cutoff,
                 true
             )
         );
@@ -1474,6 +2983,7 @@ mod tests {
         ];
         let new_owners = vec![TerritoryOwnersInsert::new(
             &territories[0],
+            5,
             3,
             Some(5.254147072201107),
             Some(6),
@@ -1546,7 +3056,63 @@ mod tests {
             process_territories(
                 territories,
                 playermoves,
+                5,
                 &mut ChaCha12Rng::seed_from_u64(45),
+                &Lottery,
+                Rules::default().alt_cutoff,
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn test_process_territories_same_seed_same_roll() {
+        // Replaying a roll with its revealed seed must give the same owners, lottery values and MVPs.
+        let territories = || {
+            vec![TerritoryOwners {
+                id: 1,
+                territory_id: 2,
+                owner_id: 3,
+                turn_id: 4,
+                previous_owner_id: 5,
+                random_number: 0.0,
+                mvp: None,
+            }]
+        };
+        let playermoves: Vec<PlayerMoves> = (0..6)
+            .map(|i| PlayerMoves {
+                id: 45 + i,
+                user_id: 6 + i,
+                turn_id: 4,
+                territory: 2,
+                mvp: false,
+                power: 3.0,
+                multiplier: 1.0,
+                weight: 3.0,
+                stars: 3,
+                team: 2 + i % 2,
+                alt_score: 0,
+                merc: false,
+            })
+            .collect();
+        let (seed, _) = seed::generate();
+        assert_eq!(
+            process_territories(
+                territories(),
+                playermoves.clone(),
+                5,
+                &mut seed::rng(&seed).unwrap(),
+                &Lottery,
+                Rules::default().alt_cutoff,
+                true
+            ),
+            process_territories(
+                territories(),
+                playermoves,
+                5,
+                &mut seed::rng(&seed).unwrap(),
+                &Lottery,
+                Rules::default().alt_cutoff,
                 true
             )
         );
@@ -1652,6 +3218,7 @@ mod tests {
         ];
         let new_owners = vec![TerritoryOwnersInsert::new(
             &territories[0],
+            5,
             6,
             Some(0.0),
             None,
@@ -1714,7 +3281,10 @@ mod tests {
             process_territories(
                 territories,
                 playermoves,
+                5,
                 &mut ChaCha12Rng::seed_from_u64(45),
+                &Lottery,
+                Rules::default().alt_cutoff,
                 true
             )
         );
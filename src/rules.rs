@@ -0,0 +1,127 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Per-season game rules, shared by the server (which applies them to moves and ratings) and
+//! the ringmaster (which applies them to rolls).
+//!
+//! A season's rules come from its row in `season_rules`. Seasons without one play by
+//! [`Rules::default`], the rules every season used before they could be changed. A roll is
+//! only reproducible with the rules it was made with, so a season's row should not be edited
+//! once the season has started.
+
+use crate::game::Game;
+use crate::lottery::Strategy;
+use crate::schema::{season_rules, turninfo};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+
+/// The tunable numbers a season is played by.
+#[derive(Queryable, Debug, Clone, PartialEq)]
+pub struct Rules {
+    /// Moves with an alt score at or above this are left out of the roll
+    pub alt_cutoff: i32,
+    /// The points added to the alt score of moves made by accounts flagged as alts
+    pub alt_score: i32,
+    /// The first day on which all-or-nothing moves are allowed. Only the ringmaster reads
+    /// this, when it sets up each new turn.
+    #[allow(dead_code)]
+    pub aon_start: i32,
+    /// The day from which all-or-nothing moves are no longer allowed
+    #[allow(dead_code)]
+    pub aon_end: i32,
+    /// The multiplier for a move on a territory the player's team already owns
+    pub home_multiplier: f64,
+    /// The bonus added to a team's multiplier for each region it owns outright
+    pub region_bonus: f64,
+    /// The number of turns needed for each star of the total turns rating
+    pub total_turns: Vec<i32>,
+    /// The number of turns this season needed for each star of the game turns rating
+    pub game_turns: Vec<i32>,
+    /// The number of MVPs needed for each star of the MVP rating
+    pub mvps: Vec<i32>,
+    /// The number of consecutive turns needed for each star of the streak rating
+    pub streak: Vec<i32>,
+    /// The committed players an eliminated team needs to respawn, or `None` if eliminated
+    /// teams stay eliminated. Only the ringmaster reads this, when it rolls.
+    #[allow(dead_code)]
+    pub respawn_players: Option<i32>,
+    /// The last day of the season, after whose roll the team with the most territories wins,
+    /// or `None` if the season only ends by conquest or elimination. Only the ringmaster
+    /// reads this, when it rolls.
+    #[allow(dead_code)]
+    pub final_day: Option<i32>,
+    /// How a territory more than one team moved on is resolved; see [`Rules::strategy`]
+    pub strategy: String,
+    /// The highest chance any team may have under the `capped_underdog` strategy
+    pub strategy_cap: Option<f64>,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            alt_cutoff: 75,
+            alt_score: 175,
+            aon_start: 4,
+            aon_end: 48,
+            home_multiplier: 1.5,
+            region_bonus: 0.5,
+            total_turns: vec![0, 10, 25, 50, 100],
+            game_turns: vec![0, 5, 10, 25, 40],
+            mvps: vec![0, 1, 5, 10, 25],
+            streak: vec![0, 3, 5, 10, 25],
+            respawn_players: None,
+            final_day: None,
+            strategy: String::from("lottery"),
+            strategy_cap: None,
+        }
+    }
+}
+
+impl Rules {
+    /// Loads the rules for `season`, falling back to the defaults if it has none.
+    pub fn load(season: i32, conn: &PgConnection) -> QueryResult<Rules> {
+        Ok(season_rules::table
+            .select((
+                season_rules::alt_cutoff,
+                season_rules::alt_score,
+                season_rules::aon_start,
+                season_rules::aon_end,
+                season_rules::home_multiplier,
+                season_rules::region_bonus,
+                season_rules::total_turns,
+                season_rules::game_turns,
+                season_rules::mvps,
+                season_rules::streak,
+                season_rules::respawn_players,
+                season_rules::final_day,
+                season_rules::strategy,
+                season_rules::strategy_cap,
+            ))
+            .filter(season_rules::season.eq(season))
+            .first::<Rules>(conn)
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    /// The territory resolution strategy the season is played with. The ringmaster, replays
+    /// and the forecast all use it, so they resolve territories alike.
+    pub fn strategy(&self) -> Result<Strategy, String> {
+        Strategy::new(&self.strategy, self.strategy_cap)
+    }
+
+    /// Loads the rules for the season of the latest turn of the game called `game`.
+    pub fn load_current(game: &str, conn: &PgConnection) -> QueryResult<Rules> {
+        let game = Game::load(game, conn)?;
+        match turninfo::table
+            .select(turninfo::season)
+            .filter(turninfo::game.eq(game.id))
+            .order(turninfo::id.desc())
+            .first::<i32>(conn)
+            .optional()?
+        {
+            Some(season) => Rules::load(season, conn),
+            None => Ok(Rules::default()),
+        }
+    }
+}
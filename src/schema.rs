@@ -47,6 +47,7 @@ table! {
         role_id -> Nullable<Int4>,
         playing_for -> Int4,
         is_alt -> Bool,
+        banned -> Bool,
     }
 }
 
@@ -97,6 +98,67 @@ table! {
         rollstarttime -> Nullable<Timestamp>,
         map -> Nullable<Text>,
         allornothingenabled -> Nullable<Bool>,
+        seed_commitment -> Nullable<Text>,
+        seed -> Nullable<Text>,
+        playoffs -> Nullable<Bool>,
+        roll_state -> Text,
+        game -> Int4,
+    }
+}
+
+table! {
+    games (id) {
+        id -> Int4,
+        name -> Text,
+        description -> Nullable<Text>,
+    }
+}
+
+table! {
+    seasons (season) {
+        season -> Int4,
+        game -> Int4,
+    }
+}
+
+table! {
+    playoff_brackets (id) {
+        id -> Int4,
+        season -> Int4,
+        turn_id -> Int4,
+        bracket -> Int4,
+        team -> Int4,
+    }
+}
+
+table! {
+    season_rules (season) {
+        season -> Int4,
+        alt_cutoff -> Int4,
+        alt_score -> Int4,
+        aon_start -> Int4,
+        aon_end -> Int4,
+        home_multiplier -> Float8,
+        region_bonus -> Float8,
+        total_turns -> Array<Int4>,
+        game_turns -> Array<Int4>,
+        mvps -> Array<Int4>,
+        streak -> Array<Int4>,
+        respawn_players -> Nullable<Int4>,
+        final_day -> Nullable<Int4>,
+        strategy -> Text,
+        strategy_cap -> Nullable<Float8>,
+    }
+}
+
+table! {
+    season_winners (id) {
+        id -> Int4,
+        season -> Int4,
+        day -> Int4,
+        team -> Int4,
+        territories -> Int4,
+        reason -> Text,
     }
 }
 
@@ -147,6 +209,8 @@ table! {
         chaosweight -> Int4,
         rollstarttime -> Text,
         rollendtime -> Text,
+        seed_commitment -> Nullable<Text>,
+        seed -> Nullable<Text>,
         json_agg -> Json,
     }
 }
@@ -233,6 +297,7 @@ table! {
         threes -> Int4,
         fours -> Int4,
         fives -> Int4,
+        respawned -> Bool,
     }
 }
 
@@ -339,6 +404,85 @@ table! {
     }
 }
 
+table! {
+    audit_log (id) {
+        id -> Int4,
+        user_id -> Int4,
+        event -> Int4,
+        timestamp -> Timestamp,
+        data -> Nullable<Json>,
+        cip -> Nullable<Text>,
+    }
+}
+
+table! {
+    alt_scores (user_id, turn_id) {
+        user_id -> Int4,
+        turn_id -> Int4,
+        score -> Int4,
+        explanation -> Jsonb,
+        scored_at -> Timestamp,
+    }
+}
+
+table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    api_token_uses (id) {
+        id -> Int4,
+        token_id -> Int4,
+        route -> Text,
+        used_at -> Timestamp,
+    }
+}
+
+table! {
+    move_snapshots (turn_id, user_id) {
+        turn_id -> Int4,
+        user_id -> Int4,
+        overall -> Nullable<Int4>,
+        turns -> Nullable<Int4>,
+        game_turns -> Nullable<Int4>,
+        mvps -> Nullable<Int4>,
+        streak -> Nullable<Int4>,
+    }
+}
+
+table! {
+    roll_user_snapshots (turn_id, user_id) {
+        turn_id -> Int4,
+        user_id -> Int4,
+        turns -> Nullable<Int4>,
+        game_turns -> Nullable<Int4>,
+        mvps -> Nullable<Int4>,
+        streak -> Nullable<Int4>,
+        overall -> Nullable<Int4>,
+        playing_for -> Int4,
+    }
+}
+
+table! {
+    roll_undos (id) {
+        id -> Int4,
+        turn_id -> Int4,
+        season -> Int4,
+        day -> Int4,
+        undone_by -> Text,
+        undone_at -> Timestamp,
+    }
+}
+
 table! {
     award_info (id){
         id -> Int4,
@@ -377,3 +521,6 @@ allow_tables_to_appear_in_same_query!(regions, territories);
 allow_tables_to_appear_in_same_query!(turns, turninfo);
 allow_tables_to_appear_in_same_query!(continuation_polls, turninfo);
 allow_tables_to_appear_in_same_query!(statistics, turninfo);
+allow_tables_to_appear_in_same_query!(season_winners, teams);
+joinable!(season_winners -> teams (team));
+allow_tables_to_appear_in_same_query!(api_tokens, users);
@@ -0,0 +1,102 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Commit-and-reveal seeds for provably fair rolls.
+//!
+//! Each turn gets a 32 byte seed when it is created. Only the SHA-256 hash of the
+//! seed (the commitment) is published while the turn is open; the seed itself is
+//! revealed once the roll is complete. Anyone can then check that
+//! `sha256(seed) == commitment` and feed the seed to `ChaCha12Rng::from_seed`
+//! to re-derive every random number used in the roll.
+
+use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+use sha2::{Digest, Sha256};
+
+/// Generates a new seed, returning `(seed, commitment)` as lowercase hex strings.
+#[must_use]
+pub fn generate() -> (String, String) {
+    let seed: [u8; 32] = rand::thread_rng().gen();
+    (to_hex(&seed), commitment(&seed))
+}
+
+/// The SHA-256 hash of the seed's bytes, as a lowercase hex string.
+#[must_use]
+pub fn commitment(seed: &[u8]) -> String {
+    to_hex(&Sha256::digest(seed))
+}
+
+/// Builds the roll's RNG from a hex-encoded seed.
+pub fn rng(seed: &str) -> Result<ChaCha12Rng, String> {
+    let bytes: [u8; 32] = from_hex(seed)?
+        .try_into()
+        .map_err(|_| String::from("Seed must be 32 bytes"))?;
+    Ok(ChaCha12Rng::from_seed(bytes))
+}
+
+/// Checks that a revealed seed matches the commitment published before the roll.
+#[must_use]
+pub fn verify(seed: &str, commitment: &str) -> bool {
+    match from_hex(seed) {
+        Ok(bytes) => self::commitment(&bytes) == commitment.to_lowercase(),
+        Err(_) => false,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 != 0 {
+        return Err(String::from("Seed has an odd number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("Invalid hex in seed at {i}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_seed_verifies() {
+        let (seed, commitment) = generate();
+        assert_eq!(64, seed.len());
+        assert!(verify(&seed, &commitment));
+        assert!(!verify(&seed, &"0".repeat(64)));
+    }
+
+    #[test]
+    fn test_known_commitment() {
+        // sha256 of 32 zero bytes
+        let seed = "0".repeat(64);
+        assert_eq!(
+            "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925",
+            commitment(&from_hex(&seed).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_rng_is_reproducible() {
+        let (seed, _) = generate();
+        let a: f64 = rng(&seed).unwrap().gen_range(0_f64..17.0);
+        let b: f64 = rng(&seed).unwrap().gen_range(0_f64..17.0);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_bad_seeds() {
+        assert!(rng("abc").is_err());
+        assert!(rng("zz").is_err());
+        assert!(rng("00").is_err());
+        assert!(!verify("zz", "00"));
+    }
+}
@@ -19,18 +19,24 @@ extern crate diesel;
 #[macro_use]
 extern crate rocket_okapi;
 
+mod alts;
 mod catchers;
 pub mod db;
 //pub mod limits;
 mod error;
+mod game;
 mod hardcode;
+mod lottery;
 mod model;
+mod moves;
+mod ratings;
+mod rules;
 mod schema;
 
 use crate::db::DbConn;
 pub use error::Error;
 //use crate::limits::RateLimitGuard;
-use crate::model::{auth, player, region, stats, sys, team, territory, turn};
+use crate::model::{admin, auth, player, region, stats, sys, team, territory, token, turn};
 use rocket::fs::FileServer;
 //use rocket_governor::rocket_governor_catcher;
 use rocket_oauth2::OAuth2;
@@ -67,32 +73,51 @@ fn rocket() -> _ {
         player::route::players,
         player::route::player_multifetch,
         region::route::regions,
+        turn::route::games,
         turn::route::turns,
         turn::route::all_turns,
         turn::route::rolllog,
+        turn::route::winners,
         team::route::teams,
         team::route::teamplayersbymoves,
         team::route::team_territories_visited_by_season,
         territory::route::territories,
         territory::route::territoryhistory,
         territory::route::territory_turn,
+        territory::route::chaos_bridges,
         stats::route::heat,
         stats::route::stathistory,
         stats::route::currentstrength,
         stats::route::leaderboard,
         stats::route::odds,
+        stats::route::forecast,
         sys::route::sysinfo,
     ];
 
     // The paths on the /auth endpoint. Defined up here for cleanliness
     let auth_paths = routes![
         auth::route::make_move,
+        auth::route::cancel_move,
+        auth::route::preview,
         auth::route::my_move,
         auth::route::join_team,
         auth::route::view_response,
         auth::route::submit_poll,
         auth::route::get_polls,
         auth::route::me,
+        token::route::mint_token,
+        token::route::tokens,
+        token::route::revoke_token,
+    ];
+
+    // The paths on the /admin endpoint. Defined up here for cleanliness
+    let admin_paths = routes![
+        admin::route::user,
+        admin::route::ban,
+        admin::route::alt,
+        admin::route::playing_for,
+        admin::route::role,
+        admin::route::edit_turn,
     ];
 
     // Get Static Dir
@@ -118,6 +143,7 @@ fn rocket() -> _ {
         .mount("/", FileServer::from(static_dir).rank(2))
         .mount("/", root_paths)
         .mount("/auth", auth_paths)
+        .mount("/admin", admin_paths)
         .mount(
             "/docs/",
             make_swagger_ui(&SwaggerUIConfig {
@@ -132,13 +158,28 @@ fn rocket() -> _ {
         .expect("Cookie key not set; aborting!");
     saturn_v = saturn_v.manage(global_info_private);
 
+    // Moves are scored for alts with the weights the server started with
+    let alt_weights = alts::AltWeights::load(saturn_v.figment()).unwrap_or_else(|e| panic!("{e}"));
+    saturn_v = saturn_v.manage(alt_weights);
+
+    let forecast_interval = saturn_v
+        .figment()
+        .extract_inner("risk.forecast.interval")
+        .unwrap_or(stats::DEFAULT_FORECAST_INTERVAL);
+    saturn_v = saturn_v.manage(stats::ForecastCache::new(std::time::Duration::from_secs(
+        forecast_interval,
+    )));
+
     // Attach Discord routes
     #[cfg(feature = "risk_discord")]
     {
         use crate::model::discord;
         saturn_v = saturn_v.attach(OAuth2::<discord::DiscordUserInfo>::fairing("discord"));
         saturn_v = saturn_v.mount("/login", routes![discord::route::login]);
-        saturn_v = saturn_v.mount("/auth", routes![discord::route::callback]);
+        saturn_v = saturn_v.mount(
+            "/auth",
+            routes![discord::route::callback, discord::route::refresh],
+        );
     }
 
     // Attach Reddit routes
@@ -149,7 +190,11 @@ fn rocket() -> _ {
         saturn_v = saturn_v.mount("/login", routes![reddit::route::login]);
         saturn_v = saturn_v.mount(
             "/auth",
-            routes![reddit::route::callback, reddit::route::logout],
+            routes![
+                reddit::route::callback,
+                reddit::route::logout,
+                reddit::route::refresh
+            ],
         );
     }
 
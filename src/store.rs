@@ -0,0 +1,986 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Storage for the turns, ownership, adjacency, users and stats a roll reads and writes.
+//!
+//! Rolls go through [`Store`] rather than a `PgConnection`, so they can run against
+//! [`PgStore`] in production and [`MemoryStore`] in tests. Locking a turn and wrapping the
+//! roll in a transaction stay with the connection, as only Postgres has anything to lock.
+
+use crate::moves::{check_move, MoveError};
+use crate::rules::Rules;
+use crate::schema::{
+    playoff_brackets, roll_undos, roll_user_snapshots, season_winners, stats, territory_adjacency,
+    territory_ownership, territory_stats, turninfo, turns,
+};
+use crate::structs::{
+    season_taken, Adjacency, PlayerMoves, PlayoffBracket, Respawn, RollState, RollUndo,
+    SeasonWinner, Stats, TerritoryOwners, TerritoryOwnersInsert, TerritoryStats, TurnInfo,
+    UserRatings, UserSnapshot, UserTurns,
+};
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+/// A turn to create, or to reset if its game, season and day already exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewTurn {
+    pub game: i32,
+    pub season: i32,
+    pub day: i32,
+    pub active: bool,
+    pub finale: bool,
+    /// Whether the turn is a playoff day, which advances the playoffs instead of rolling
+    pub playoffs: bool,
+    pub map: Option<String>,
+    pub allornothingenabled: bool,
+    pub start_time: Option<NaiveDateTime>,
+}
+
+/// Everything a roll reads from and writes to the database.
+pub trait Store {
+    /// Loads the active turn of `game`.
+    fn latest_turn(&self, game: i32) -> QueryResult<TurnInfo>;
+    /// Loads the turn of `game` to roll next, as in [`TurnInfo::get_rollable`].
+    fn rollable_turn(&self, game: i32) -> QueryResult<TurnInfo>;
+    /// Loads the most recently rolled turn of `game`.
+    fn last_rolled_turn(&self, game: i32) -> QueryResult<TurnInfo>;
+    fn turn(&self, game: i32, season: i32, day: i32) -> QueryResult<TurnInfo>;
+    /// Loads the day after `turn` in the same game. Turn ids are shared between games, so
+    /// this is the only way to find it.
+    fn next_turn(&self, turn: &TurnInfo) -> QueryResult<TurnInfo>;
+    /// Creates `turn`, or resets it if it already exists, returning its id. Fails if another
+    /// game has claimed the turn's season (see [`season_taken`]).
+    fn insert_turn(&self, turn: &NewTurn) -> QueryResult<i32>;
+    fn update_turn(&self, turn: &TurnInfo) -> QueryResult<usize>;
+    fn delete_turn(&self, turn_id: i32) -> QueryResult<usize>;
+    /// Commits to a new seed for a turn, returning the seed. Used for turns created before
+    /// seeds existed, and for turns whose seed was revealed by a roll that has been undone.
+    fn commit_seed(&self, turn: &mut TurnInfo) -> QueryResult<String>;
+    fn rules(&self, season: i32) -> QueryResult<Rules>;
+
+    fn moves(&self, turn_id: i32) -> QueryResult<Vec<PlayerMoves>>;
+    fn set_mvps(&self, mvps: &[PlayerMoves]) -> QueryResult<usize>;
+    fn clear_mvps(&self, turn_id: i32) -> QueryResult<usize>;
+
+    fn owners(&self, turn_id: i32) -> QueryResult<Vec<TerritoryOwners>>;
+    fn insert_owners(&self, owners: &[TerritoryOwnersInsert]) -> QueryResult<usize>;
+    fn delete_owners(&self, turn_id: i32) -> QueryResult<usize>;
+
+    /// The `(owner, territory)` of each territory adjacent to `territory` on turn `turn_id`.
+    fn adjacent_owners(&self, territory: i32, turn_id: i32) -> QueryResult<Vec<(i32, i32)>>;
+    fn insert_adjacency(&self, adjacency: &[Adjacency]) -> QueryResult<usize>;
+    /// Deletes the adjacency rows marked with `note` that closed before turn `turn_id`.
+    fn delete_adjacency(&self, note: &str, turn_id: i32) -> QueryResult<usize>;
+    /// Deletes the adjacency rows marked with `note` that were opened by the roll of `turn_id`.
+    fn delete_opened_adjacency(&self, note: &str, turn_id: i32) -> QueryResult<usize>;
+
+    fn user_turns(&self, turn_id: i32, season: i32, game: i32) -> QueryResult<Vec<UserTurns>>;
+    fn user_ratings(&self) -> QueryResult<Vec<UserRatings>>;
+    fn update_user(&self, user: &UserRatings) -> QueryResult<usize>;
+    /// Keeps `user` as they were before the roll of `turn_id`, so the roll can be undone.
+    fn snapshot_user(&self, turn_id: i32, user: &UserRatings) -> QueryResult<usize>;
+    /// Loads the users kept by [`Store::snapshot_user`] for the roll of `turn_id`.
+    fn user_snapshots(&self, turn_id: i32) -> QueryResult<Vec<UserRatings>>;
+    fn delete_user_snapshots(&self, turn_id: i32) -> QueryResult<usize>;
+    /// Counts each team's committed players, as in [`Respawn::committed_players`].
+    fn committed_players(&self) -> QueryResult<BTreeMap<i32, i32>>;
+
+    fn insert_stats(&self, stats: BTreeMap<i32, Stats>, turn_id: i32) -> QueryResult<usize>;
+    fn delete_stats(&self, turn_id: i32) -> QueryResult<usize>;
+    fn insert_territory_stats(&self, stats: Vec<TerritoryStats>) -> QueryResult<usize>;
+    fn delete_territory_stats(&self, turn_id: i32) -> QueryResult<usize>;
+    /// The brackets of `season` being played on turn `turn_id`, as in [`PlayoffBracket::load`].
+    fn playoff_brackets(&self, season: i32, turn_id: i32) -> QueryResult<Vec<Vec<i32>>>;
+    fn insert_playoff_brackets(
+        &self,
+        brackets: &[Vec<i32>],
+        season: i32,
+        turn_id: i32,
+    ) -> QueryResult<usize>;
+    fn delete_playoff_brackets(&self, turn_id: i32) -> QueryResult<usize>;
+    fn insert_season_winners(&self, winners: &[SeasonWinner]) -> QueryResult<usize>;
+    /// Deletes the winners recorded by the roll of `season`/`day`.
+    fn delete_season_winners(&self, season: i32, day: i32) -> QueryResult<usize>;
+    fn insert_undo(&self, undo: &RollUndo) -> QueryResult<usize>;
+
+    /// Checks a move by `team` on `target` during turn `turn_id`, as the server does. Returns
+    /// whether the move defends a territory the team already owns.
+    fn check_move(
+        &self,
+        team: i32,
+        target: i32,
+        turn_id: i32,
+    ) -> QueryResult<Result<bool, MoveError>> {
+        Ok(check_move(
+            team,
+            target,
+            &self.adjacent_owners(target, turn_id)?,
+        ))
+    }
+}
+
+/// The database.
+pub struct PgStore<'a> {
+    conn: &'a PgConnection,
+}
+
+impl<'a> PgStore<'a> {
+    pub fn new(conn: &'a PgConnection) -> Self {
+        PgStore { conn }
+    }
+}
+
+impl Store for PgStore<'_> {
+    fn latest_turn(&self, game: i32) -> QueryResult<TurnInfo> {
+        TurnInfo::get_latest(game, self.conn)
+    }
+
+    fn rollable_turn(&self, game: i32) -> QueryResult<TurnInfo> {
+        TurnInfo::get_rollable(game, self.conn)
+    }
+
+    fn last_rolled_turn(&self, game: i32) -> QueryResult<TurnInfo> {
+        TurnInfo::get_last_rolled(game, self.conn)
+    }
+
+    fn turn(&self, game: i32, season: i32, day: i32) -> QueryResult<TurnInfo> {
+        TurnInfo::get(game, season, day, self.conn)
+    }
+
+    fn next_turn(&self, turn: &TurnInfo) -> QueryResult<TurnInfo> {
+        turn.get_next(self.conn)
+    }
+
+    fn insert_turn(&self, turn: &NewTurn) -> QueryResult<i32> {
+        TurnInfo::insert_new(
+            turn.game,
+            turn.season,
+            turn.day,
+            turn.active,
+            turn.finale,
+            turn.playoffs,
+            turn.map.clone(),
+            turn.allornothingenabled,
+            turn.start_time,
+            self.conn,
+        )
+    }
+
+    fn update_turn(&self, turn: &TurnInfo) -> QueryResult<usize> {
+        TurnInfo::update_or_insert(turn, self.conn)
+    }
+
+    fn delete_turn(&self, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(turninfo::table.filter(turninfo::id.eq(turn_id))).execute(self.conn)
+    }
+
+    fn commit_seed(&self, turn: &mut TurnInfo) -> QueryResult<String> {
+        turn.commit_seed(self.conn)
+    }
+
+    fn rules(&self, season: i32) -> QueryResult<Rules> {
+        Rules::load(season, self.conn)
+    }
+
+    fn moves(&self, turn_id: i32) -> QueryResult<Vec<PlayerMoves>> {
+        PlayerMoves::load(&turn_id, self.conn)
+    }
+
+    fn set_mvps(&self, mvps: &[PlayerMoves]) -> QueryResult<usize> {
+        PlayerMoves::mvps(mvps.to_vec(), self.conn)
+    }
+
+    fn clear_mvps(&self, turn_id: i32) -> QueryResult<usize> {
+        diesel::update(turns::table.filter(turns::turn_id.eq(turn_id)))
+            .set(turns::mvp.eq(false))
+            .execute(self.conn)
+    }
+
+    fn owners(&self, turn_id: i32) -> QueryResult<Vec<TerritoryOwners>> {
+        TerritoryOwners::load(&turn_id, self.conn)
+    }
+
+    fn insert_owners(&self, owners: &[TerritoryOwnersInsert]) -> QueryResult<usize> {
+        TerritoryOwnersInsert::insert(owners, self.conn)
+    }
+
+    fn delete_owners(&self, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(territory_ownership::table.filter(territory_ownership::turn_id.eq(turn_id)))
+            .execute(self.conn)
+    }
+
+    fn adjacent_owners(&self, territory: i32, turn_id: i32) -> QueryResult<Vec<(i32, i32)>> {
+        territory_adjacency::table
+            .filter(territory_adjacency::adjacent_id.eq(territory))
+            .filter(territory_adjacency::min_turn.lt(turn_id))
+            .filter(territory_adjacency::max_turn.ge(turn_id))
+            .filter(territory_ownership::turn_id.eq(turn_id))
+            .inner_join(
+                territory_ownership::table
+                    .on(territory_ownership::territory_id.eq(territory_adjacency::territory_id)),
+            )
+            .select((
+                territory_ownership::owner_id,
+                territory_ownership::territory_id,
+            ))
+            .load::<(i32, i32)>(self.conn)
+    }
+
+    fn insert_adjacency(&self, adjacency: &[Adjacency]) -> QueryResult<usize> {
+        diesel::insert_into(territory_adjacency::table)
+            .values(adjacency)
+            .execute(self.conn)
+    }
+
+    fn delete_adjacency(&self, note: &str, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(
+            territory_adjacency::table
+                .filter(territory_adjacency::note.eq(note))
+                .filter(territory_adjacency::max_turn.lt(turn_id)),
+        )
+        .execute(self.conn)
+    }
+
+    fn delete_opened_adjacency(&self, note: &str, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(
+            territory_adjacency::table
+                .filter(territory_adjacency::note.eq(note))
+                .filter(territory_adjacency::min_turn.eq(turn_id)),
+        )
+        .execute(self.conn)
+    }
+
+    fn user_turns(&self, turn_id: i32, season: i32, game: i32) -> QueryResult<Vec<UserTurns>> {
+        UserTurns::load(turn_id, season, game, self.conn)
+    }
+
+    fn user_ratings(&self) -> QueryResult<Vec<UserRatings>> {
+        UserRatings::load(self.conn)
+    }
+
+    fn update_user(&self, user: &UserRatings) -> QueryResult<usize> {
+        user.update(self.conn)
+    }
+
+    fn snapshot_user(&self, turn_id: i32, user: &UserRatings) -> QueryResult<usize> {
+        UserSnapshot::new(turn_id, user).insert(self.conn)
+    }
+
+    fn user_snapshots(&self, turn_id: i32) -> QueryResult<Vec<UserRatings>> {
+        UserSnapshot::load(turn_id, self.conn)
+    }
+
+    fn delete_user_snapshots(&self, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(roll_user_snapshots::table.filter(roll_user_snapshots::turn_id.eq(turn_id)))
+            .execute(self.conn)
+    }
+
+    fn committed_players(&self) -> QueryResult<BTreeMap<i32, i32>> {
+        Respawn::committed_players(self.conn)
+    }
+
+    fn insert_stats(&self, stats: BTreeMap<i32, Stats>, turn_id: i32) -> QueryResult<usize> {
+        Stats::insert(stats, turn_id, self.conn)
+    }
+
+    fn delete_stats(&self, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(stats::table.filter(stats::turn_id.eq(turn_id))).execute(self.conn)
+    }
+
+    fn insert_territory_stats(&self, stats: Vec<TerritoryStats>) -> QueryResult<usize> {
+        TerritoryStats::insert(stats, self.conn)
+    }
+
+    fn delete_territory_stats(&self, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(territory_stats::table.filter(territory_stats::turn_id.eq(turn_id)))
+            .execute(self.conn)
+    }
+
+    fn playoff_brackets(&self, season: i32, turn_id: i32) -> QueryResult<Vec<Vec<i32>>> {
+        PlayoffBracket::load(season, turn_id, self.conn)
+    }
+
+    fn insert_playoff_brackets(
+        &self,
+        brackets: &[Vec<i32>],
+        season: i32,
+        turn_id: i32,
+    ) -> QueryResult<usize> {
+        PlayoffBracket::insert(brackets, season, turn_id, self.conn)
+    }
+
+    fn delete_playoff_brackets(&self, turn_id: i32) -> QueryResult<usize> {
+        diesel::delete(playoff_brackets::table.filter(playoff_brackets::turn_id.eq(turn_id)))
+            .execute(self.conn)
+    }
+
+    fn insert_season_winners(&self, winners: &[SeasonWinner]) -> QueryResult<usize> {
+        SeasonWinner::insert(winners, self.conn)
+    }
+
+    fn delete_season_winners(&self, season: i32, day: i32) -> QueryResult<usize> {
+        diesel::delete(
+            season_winners::table
+                .filter(season_winners::season.eq(season))
+                .filter(season_winners::day.eq(day)),
+        )
+        .execute(self.conn)
+    }
+
+    fn insert_undo(&self, undo: &RollUndo) -> QueryResult<usize> {
+        diesel::insert_into(roll_undos::table)
+            .values(undo)
+            .execute(self.conn)
+    }
+}
+
+/// A user as the in-memory store keeps them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryUser {
+    pub ratings: UserRatings,
+    pub current_team: i32,
+    pub is_alt: bool,
+}
+
+/// Tables held in memory, for tests. Each field stands in for the table of the same name,
+/// except `moves`, which stands in for both `turns` and `past_turns`.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    pub turns: RefCell<Vec<TurnInfo>>,
+    /// Rules by season; seasons without any play by the defaults
+    pub rules: RefCell<BTreeMap<i32, Rules>>,
+    pub moves: RefCell<Vec<PlayerMoves>>,
+    pub owners: RefCell<Vec<TerritoryOwnersInsert>>,
+    pub adjacency: RefCell<Vec<Adjacency>>,
+    pub users: RefCell<Vec<MemoryUser>>,
+    pub stats: RefCell<Vec<Stats>>,
+    pub territory_stats: RefCell<Vec<TerritoryStats>>,
+    pub playoff_brackets: RefCell<Vec<PlayoffBracket>>,
+    pub season_winners: RefCell<Vec<SeasonWinner>>,
+    pub user_snapshots: RefCell<Vec<UserSnapshot>>,
+    pub undos: RefCell<Vec<RollUndo>>,
+    /// The game each season belongs to
+    pub seasons: RefCell<BTreeMap<i32, i32>>,
+    /// The last id handed to a new turn. Like a serial, ids are never reused, even once the
+    /// turn has been deleted.
+    pub last_turn_id: Cell<i32>,
+}
+
+impl MemoryStore {
+    /// Makes each pair of territories in `borders` adjacent, both ways and on every turn.
+    /// Every territory in `borders` is also made adjacent to itself.
+    pub fn border(&self, borders: &[(i32, i32)]) {
+        let mut adjacency = self.adjacency.borrow_mut();
+        let mut pairs = Vec::new();
+        for &(a, b) in borders {
+            pairs.extend([(a, b), (b, a), (a, a), (b, b)]);
+        }
+        pairs.sort_unstable();
+        pairs.dedup();
+        adjacency.extend(
+            pairs
+                .into_iter()
+                .map(|(territory_id, adjacent_id)| Adjacency {
+                    territory_id,
+                    adjacent_id,
+                    note: String::new(),
+                    min_turn: 0,
+                    max_turn: i32::MAX,
+                }),
+        );
+    }
+}
+
+impl Store for MemoryStore {
+    fn latest_turn(&self, game: i32) -> QueryResult<TurnInfo> {
+        self.turns
+            .borrow()
+            .iter()
+            .filter(|turn| turn.active == Some(true) && turn.game == game)
+            .max_by_key(|turn| (turn.season, turn.day))
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn rollable_turn(&self, game: i32) -> QueryResult<TurnInfo> {
+        self.turns
+            .borrow()
+            .iter()
+            .filter(|turn| {
+                turn.game == game
+                    && (turn.active == Some(true)
+                        || matches!(turn.roll_state, RollState::Locked | RollState::Rolling))
+            })
+            .max_by_key(|turn| (turn.season, turn.day))
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn last_rolled_turn(&self, game: i32) -> QueryResult<TurnInfo> {
+        self.turns
+            .borrow()
+            .iter()
+            .filter(|turn| turn.complete == Some(true) && turn.game == game)
+            .max_by_key(|turn| (turn.season, turn.day))
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn turn(&self, game: i32, season: i32, day: i32) -> QueryResult<TurnInfo> {
+        self.turns
+            .borrow()
+            .iter()
+            .find(|turn| turn.game == game && turn.season == season && turn.day == day)
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn next_turn(&self, turn: &TurnInfo) -> QueryResult<TurnInfo> {
+        self.turns
+            .borrow()
+            .iter()
+            .find(|next| {
+                next.game == turn.game && next.season == turn.season && next.day == turn.day + 1
+            })
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn insert_turn(&self, new: &NewTurn) -> QueryResult<i32> {
+        let owner = *self
+            .seasons
+            .borrow_mut()
+            .entry(new.season)
+            .or_insert(new.game);
+        if owner != new.game {
+            return Err(season_taken(new.season, owner));
+        }
+        let mut turns = self.turns.borrow_mut();
+        let index = match turns.iter().position(|turn| {
+            turn.game == new.game && turn.season == new.season && turn.day == new.day
+        }) {
+            Some(index) => index,
+            None => {
+                let (seed, seed_commitment) = crate::seed::generate();
+                self.last_turn_id.set(self.last_turn_id.get() + 1);
+                turns.push(TurnInfo {
+                    id: self.last_turn_id.get(),
+                    season: new.season,
+                    day: new.day,
+                    complete: None,
+                    active: None,
+                    finale: None,
+                    chaosweight: None,
+                    rollendtime: None,
+                    rollstarttime: None,
+                    allornothingenabled: None,
+                    map: None,
+                    seed_commitment: Some(seed_commitment),
+                    seed: Some(seed),
+                    playoffs: None,
+                    roll_state: RollState::Pending,
+                    game: new.game,
+                });
+                turns.len() - 1
+            }
+        };
+        let turn = &mut turns[index];
+        turn.active = Some(new.active);
+        turn.complete = Some(false);
+        turn.finale = Some(new.finale);
+        turn.playoffs = Some(new.playoffs);
+        turn.map = new.map.clone();
+        turn.rollstarttime = new.start_time;
+        turn.allornothingenabled = Some(new.allornothingenabled);
+        turn.roll_state = RollState::Pending;
+        Ok(turn.id)
+    }
+
+    fn update_turn(&self, new: &TurnInfo) -> QueryResult<usize> {
+        let mut turns = self.turns.borrow_mut();
+        match turns.iter_mut().find(|turn| turn.id == new.id) {
+            Some(turn) => *turn = new.clone(),
+            None => turns.push(new.clone()),
+        }
+        Ok(1)
+    }
+
+    fn delete_turn(&self, turn_id: i32) -> QueryResult<usize> {
+        let mut turns = self.turns.borrow_mut();
+        let before = turns.len();
+        turns.retain(|turn| turn.id != turn_id);
+        Ok(before - turns.len())
+    }
+
+    fn commit_seed(&self, turn: &mut TurnInfo) -> QueryResult<String> {
+        let (seed, seed_commitment) = crate::seed::generate();
+        turn.seed_commitment = Some(seed_commitment);
+        turn.seed = Some(seed.clone());
+        self.update_turn(turn)?;
+        Ok(seed)
+    }
+
+    fn rules(&self, season: i32) -> QueryResult<Rules> {
+        Ok(self
+            .rules
+            .borrow()
+            .get(&season)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn moves(&self, turn_id: i32) -> QueryResult<Vec<PlayerMoves>> {
+        let mut moves = self
+            .moves
+            .borrow()
+            .iter()
+            .filter(|player| player.turn_id == turn_id)
+            .cloned()
+            .collect::<Vec<PlayerMoves>>();
+        moves.sort_by_key(|player| (std::cmp::Reverse(player.territory), player.id));
+        Ok(moves)
+    }
+
+    fn set_mvps(&self, mvps: &[PlayerMoves]) -> QueryResult<usize> {
+        let mut updated = 0;
+        for player in self.moves.borrow_mut().iter_mut() {
+            if mvps.iter().any(|mvp| mvp.id == player.id) {
+                player.mvp = true;
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    }
+
+    fn clear_mvps(&self, turn_id: i32) -> QueryResult<usize> {
+        let mut updated = 0;
+        for player in self.moves.borrow_mut().iter_mut() {
+            if player.turn_id == turn_id {
+                player.mvp = false;
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    }
+
+    fn owners(&self, turn_id: i32) -> QueryResult<Vec<TerritoryOwners>> {
+        let mut owners = self
+            .owners
+            .borrow()
+            .iter()
+            .zip(1..)
+            .filter(|(owner, _)| owner.turn_id == turn_id)
+            .map(|(owner, id)| TerritoryOwners {
+                id,
+                territory_id: owner.territory_id,
+                owner_id: owner.owner_id,
+                turn_id: owner.turn_id,
+                previous_owner_id: owner.previous_owner_id,
+                random_number: owner.random_number,
+                mvp: owner.mvp,
+            })
+            .collect::<Vec<TerritoryOwners>>();
+        owners.sort_by_key(|owner| owner.territory_id);
+        Ok(owners)
+    }
+
+    fn insert_owners(&self, owners: &[TerritoryOwnersInsert]) -> QueryResult<usize> {
+        self.owners.borrow_mut().extend(owners.iter().cloned());
+        Ok(owners.len())
+    }
+
+    fn delete_owners(&self, turn_id: i32) -> QueryResult<usize> {
+        let mut owners = self.owners.borrow_mut();
+        let before = owners.len();
+        owners.retain(|owner| owner.turn_id != turn_id);
+        Ok(before - owners.len())
+    }
+
+    fn adjacent_owners(&self, territory: i32, turn_id: i32) -> QueryResult<Vec<(i32, i32)>> {
+        let owners = self.owners.borrow();
+        Ok(self
+            .adjacency
+            .borrow()
+            .iter()
+            .filter(|row| row.adjacent_id == territory)
+            .filter(|row| row.min_turn < turn_id && row.max_turn >= turn_id)
+            .flat_map(|row| {
+                owners
+                    .iter()
+                    .filter(move |owner| {
+                        owner.turn_id == turn_id && owner.territory_id == row.territory_id
+                    })
+                    .map(|owner| (owner.owner_id, owner.territory_id))
+            })
+            .collect())
+    }
+
+    fn insert_adjacency(&self, adjacency: &[Adjacency]) -> QueryResult<usize> {
+        self.adjacency
+            .borrow_mut()
+            .extend(adjacency.iter().cloned());
+        Ok(adjacency.len())
+    }
+
+    fn delete_adjacency(&self, note: &str, turn_id: i32) -> QueryResult<usize> {
+        let mut adjacency = self.adjacency.borrow_mut();
+        let before = adjacency.len();
+        adjacency.retain(|row| row.note != note || row.max_turn >= turn_id);
+        Ok(before - adjacency.len())
+    }
+
+    fn delete_opened_adjacency(&self, note: &str, turn_id: i32) -> QueryResult<usize> {
+        let mut adjacency = self.adjacency.borrow_mut();
+        let before = adjacency.len();
+        adjacency.retain(|row| row.note != note || row.min_turn != turn_id);
+        Ok(before - adjacency.len())
+    }
+
+    fn user_turns(&self, turn_id: i32, season: i32, game: i32) -> QueryResult<Vec<UserTurns>> {
+        let turns = self.turns.borrow();
+        let mut users: BTreeMap<i32, UserTurns> = BTreeMap::new();
+        for player in self.moves.borrow().iter() {
+            let Some(turn) = turns.iter().find(|turn| turn.id == player.turn_id) else {
+                continue;
+            };
+            let user = users.entry(player.user_id).or_insert(UserTurns {
+                user_id: player.user_id,
+                turns: 0,
+                game_turns: 0,
+                mvps: 0,
+                played: false,
+                in_game: false,
+            });
+            user.turns += 1;
+            user.game_turns += i32::from(turn.season == season);
+            user.mvps += i32::from(player.mvp);
+            user.played |= turn.id == turn_id;
+            user.in_game |= turn.game == game;
+        }
+        Ok(users.into_values().collect())
+    }
+
+    fn user_ratings(&self) -> QueryResult<Vec<UserRatings>> {
+        let mut users = self
+            .users
+            .borrow()
+            .iter()
+            .map(|user| user.ratings.clone())
+            .collect::<Vec<UserRatings>>();
+        users.sort_by_key(|user| user.id);
+        Ok(users)
+    }
+
+    fn update_user(&self, new: &UserRatings) -> QueryResult<usize> {
+        let mut users = self.users.borrow_mut();
+        Ok(
+            match users.iter_mut().find(|user| user.ratings.id == new.id) {
+                Some(user) => {
+                    user.ratings = new.clone();
+                    1
+                }
+                None => 0,
+            },
+        )
+    }
+
+    fn snapshot_user(&self, turn_id: i32, user: &UserRatings) -> QueryResult<usize> {
+        self.user_snapshots
+            .borrow_mut()
+            .push(UserSnapshot::new(turn_id, user));
+        Ok(1)
+    }
+
+    fn user_snapshots(&self, turn_id: i32) -> QueryResult<Vec<UserRatings>> {
+        let mut users = self
+            .user_snapshots
+            .borrow()
+            .iter()
+            .filter(|snapshot| snapshot.turn_id == turn_id)
+            .map(UserSnapshot::ratings)
+            .collect::<Vec<UserRatings>>();
+        users.sort_by_key(|user| user.id);
+        Ok(users)
+    }
+
+    fn delete_user_snapshots(&self, turn_id: i32) -> QueryResult<usize> {
+        let mut snapshots = self.user_snapshots.borrow_mut();
+        let before = snapshots.len();
+        snapshots.retain(|snapshot| snapshot.turn_id != turn_id);
+        Ok(before - snapshots.len())
+    }
+
+    fn committed_players(&self) -> QueryResult<BTreeMap<i32, i32>> {
+        let mut players = BTreeMap::new();
+        for user in self.users.borrow().iter().filter(|user| !user.is_alt) {
+            *players.entry(user.current_team).or_default() += 1;
+        }
+        Ok(players)
+    }
+
+    fn insert_stats(&self, stats: BTreeMap<i32, Stats>, turn_id: i32) -> QueryResult<usize> {
+        let ranked = Stats::rank(&stats, turn_id);
+        let inserted = ranked.len();
+        self.stats.borrow_mut().extend(ranked);
+        Ok(inserted)
+    }
+
+    fn delete_stats(&self, turn_id: i32) -> QueryResult<usize> {
+        let mut stats = self.stats.borrow_mut();
+        let before = stats.len();
+        stats.retain(|stat| stat.turn_id != turn_id);
+        Ok(before - stats.len())
+    }
+
+    fn insert_territory_stats(&self, stats: Vec<TerritoryStats>) -> QueryResult<usize> {
+        let inserted = stats.len();
+        self.territory_stats.borrow_mut().extend(stats);
+        Ok(inserted)
+    }
+
+    fn delete_territory_stats(&self, turn_id: i32) -> QueryResult<usize> {
+        let mut stats = self.territory_stats.borrow_mut();
+        let before = stats.len();
+        stats.retain(|stat| stat.turn_id != turn_id);
+        Ok(before - stats.len())
+    }
+
+    fn playoff_brackets(&self, season: i32, turn_id: i32) -> QueryResult<Vec<Vec<i32>>> {
+        let rows = self.playoff_brackets.borrow();
+        let latest = rows
+            .iter()
+            .filter(|row| row.season == season && row.turn_id <= turn_id)
+            .map(|row| row.turn_id)
+            .max();
+        let mut brackets: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+        for row in rows
+            .iter()
+            .filter(|row| row.season == season && Some(row.turn_id) == latest)
+        {
+            brackets.entry(row.bracket).or_default().push(row.team);
+        }
+        for teams in brackets.values_mut() {
+            teams.sort_unstable();
+        }
+        Ok(brackets.into_values().collect())
+    }
+
+    fn insert_playoff_brackets(
+        &self,
+        brackets: &[Vec<i32>],
+        season: i32,
+        turn_id: i32,
+    ) -> QueryResult<usize> {
+        let mut rows = self.playoff_brackets.borrow_mut();
+        let before = rows.len();
+        for (teams, bracket) in brackets.iter().zip(1..) {
+            rows.extend(teams.iter().map(|&team| PlayoffBracket {
+                season,
+                turn_id,
+                bracket,
+                team,
+            }));
+        }
+        Ok(rows.len() - before)
+    }
+
+    fn delete_playoff_brackets(&self, turn_id: i32) -> QueryResult<usize> {
+        let mut rows = self.playoff_brackets.borrow_mut();
+        let before = rows.len();
+        rows.retain(|row| row.turn_id != turn_id);
+        Ok(before - rows.len())
+    }
+
+    fn insert_season_winners(&self, winners: &[SeasonWinner]) -> QueryResult<usize> {
+        self.season_winners
+            .borrow_mut()
+            .extend(winners.iter().cloned());
+        Ok(winners.len())
+    }
+
+    fn delete_season_winners(&self, season: i32, day: i32) -> QueryResult<usize> {
+        let mut winners = self.season_winners.borrow_mut();
+        let before = winners.len();
+        winners.retain(|winner| winner.season != season || winner.day != day);
+        Ok(before - winners.len())
+    }
+
+    fn insert_undo(&self, undo: &RollUndo) -> QueryResult<usize> {
+        self.undos.borrow_mut().push(undo.clone());
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(territory_id: i32, owner_id: i32) -> TerritoryOwnersInsert {
+        TerritoryOwnersInsert {
+            territory_id,
+            owner_id,
+            turn_id: 1,
+            previous_owner_id: owner_id,
+            random_number: 0.0,
+            mvp: None,
+        }
+    }
+
+    #[test]
+    fn test_memory_check_move() {
+        // 1 - 2 - 3, with 3 bridged to 1 only on turn 2
+        let store = MemoryStore::default();
+        store.border(&[(1, 2), (2, 3)]);
+        store
+            .insert_adjacency(&[Adjacency {
+                territory_id: 1,
+                adjacent_id: 3,
+                note: String::from("bridge"),
+                min_turn: 1,
+                max_turn: 2,
+            }])
+            .unwrap();
+        store
+            .insert_owners(&[owner(1, 5), owner(2, 5), owner(3, 6)])
+            .unwrap();
+        assert_eq!(store.check_move(5, 2, 1).unwrap(), Ok(true));
+        assert_eq!(store.check_move(5, 3, 1).unwrap(), Ok(false));
+        assert_eq!(store.check_move(6, 2, 1).unwrap(), Ok(false));
+        assert_eq!(
+            store.check_move(6, 1, 1).unwrap(),
+            Err(MoveError::NotAdjacent)
+        );
+        assert_eq!(
+            store.check_move(5, 1, 1).unwrap(),
+            Err(MoveError::Surrounded)
+        );
+        // The bridge has closed by turn 3, and is deleted once the history runs out
+        assert_eq!(store.adjacent_owners(3, 1).unwrap().len(), 2);
+        assert_eq!(store.delete_adjacency("bridge", 2).unwrap(), 0);
+        assert_eq!(store.delete_adjacency("bridge", 3).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_memory_turns() {
+        let store = MemoryStore::default();
+        let mut new = NewTurn {
+            game: 1,
+            season: 2,
+            day: 1,
+            active: true,
+            finale: false,
+            playoffs: false,
+            map: None,
+            allornothingenabled: false,
+            start_time: None,
+        };
+        store.insert_turn(&new).unwrap();
+        new.game = 2;
+        new.season = 3;
+        store.insert_turn(&new).unwrap();
+        assert_eq!(store.latest_turn(1).unwrap().season, 2);
+        assert_eq!(store.latest_turn(2).unwrap().id, 2);
+        // Creating a turn that already exists resets it, and keeps its seed
+        let mut turn = store.turn(1, 2, 1).unwrap();
+        turn.active = Some(false);
+        store.update_turn(&turn).unwrap();
+        assert!(store.latest_turn(1).is_err());
+        store
+            .insert_turn(&NewTurn {
+                game: 1,
+                season: 2,
+                ..new
+            })
+            .unwrap();
+        let reset = store.latest_turn(1).unwrap();
+        assert_eq!((reset.id, &reset.seed), (turn.id, &turn.seed));
+        // Ids aren't reused once their turn is deleted
+        store.delete_turn(2).unwrap();
+        let id = store
+            .insert_turn(&NewTurn {
+                game: 2,
+                season: 3,
+                day: 1,
+                active: true,
+                finale: false,
+                playoffs: false,
+                map: None,
+                allornothingenabled: false,
+                start_time: None,
+            })
+            .unwrap();
+        assert_eq!(id, 3);
+    }
+
+    #[test]
+    fn test_memory_turns_shared_season() {
+        let store = MemoryStore::default();
+        let new = NewTurn {
+            game: 1,
+            season: 4,
+            day: 1,
+            active: true,
+            finale: false,
+            playoffs: false,
+            map: Some(String::from("first")),
+            allornothingenabled: false,
+            start_time: None,
+        };
+        let id = store.insert_turn(&new).unwrap();
+        // Another game can't create, or reset, a day of a season that is already played
+        let error = store
+            .insert_turn(&NewTurn {
+                game: 2,
+                active: false,
+                map: Some(String::from("second")),
+                ..new.clone()
+            })
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Season 4 belongs to game 1");
+        assert!(store
+            .insert_turn(&NewTurn {
+                game: 2,
+                day: 2,
+                ..new.clone()
+            })
+            .is_err());
+        let turn = store.turn(1, 4, 1).unwrap();
+        assert_eq!(
+            (turn.id, turn.active, turn.map.as_deref()),
+            (id, Some(true), Some("first"))
+        );
+        assert_eq!(store.turns.borrow().len(), 1);
+        assert!(store.turn(2, 4, 1).is_err());
+        assert!(store.latest_turn(2).is_err());
+    }
+
+    #[test]
+    fn test_memory_rollable_turn() {
+        let store = MemoryStore::default();
+        let new = NewTurn {
+            game: 1,
+            season: 1,
+            day: 1,
+            active: false,
+            finale: false,
+            playoffs: false,
+            map: None,
+            allornothingenabled: false,
+            start_time: None,
+        };
+        store.insert_turn(&new).unwrap();
+        assert!(store.rollable_turn(1).is_err());
+        // A roll that stopped after locking the turn leaves it inactive, but still to roll
+        let mut turn = store.turn(1, 1, 1).unwrap();
+        turn.roll_state = RollState::Rolling;
+        store.update_turn(&turn).unwrap();
+        assert!(store.latest_turn(1).is_err());
+        assert_eq!(store.rollable_turn(1).unwrap().id, turn.id);
+        assert!(store.rollable_turn(2).is_err());
+    }
+}
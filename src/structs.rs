@@ -2,22 +2,57 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use crate::schema::{stats, teams, territory_ownership, territory_stats, turninfo, turns};
+use crate::schema::{
+    playoff_brackets, roll_undos, roll_user_snapshots, season_winners, seasons, stats, teams,
+    territory_adjacency, territory_ownership, territory_stats, turninfo, turns, users,
+};
 use crate::Utc;
 use chrono::NaiveDateTime;
-use diesel::pg::PgConnection;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
 use diesel::result::Error;
-use diesel::sql_types::Bool;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_query;
+use diesel::sql_types::{Bool, Integer, Text};
 use diesel::{insert_into, update};
 use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
 
-#[derive(QueryableByName)]
-pub struct Bar {
+/// A user's turn history, as of the roll of one turn.
+#[derive(QueryableByName, Debug, PartialEq, Eq, Clone)]
+pub struct UserTurns {
+    #[sql_type = "Integer"]
+    pub user_id: i32,
+    /// Every turn the user has played
+    #[sql_type = "Integer"]
+    pub turns: i32,
+    /// The turns the user has played this season
+    #[sql_type = "Integer"]
+    pub game_turns: i32,
+    #[sql_type = "Integer"]
+    pub mvps: i32,
+    /// Whether the user played the turn being rolled
+    #[sql_type = "Bool"]
+    pub played: bool,
+    /// Whether the user has ever played in the game being rolled
     #[sql_type = "Bool"]
-    pub do_user_update: bool,
+    pub in_game: bool,
 }
-#[derive(Deserialize, Insertable, Queryable, Debug, PartialEq, Clone)]
+
+/// The counters on a user that are kept up to date by each roll.
+#[derive(Queryable, Debug, PartialEq, Eq, Clone)]
+pub struct UserRatings {
+    pub id: i32,
+    pub turns: Option<i32>,
+    pub game_turns: Option<i32>,
+    pub mvps: Option<i32>,
+    pub streak: Option<i32>,
+    pub overall: Option<i32>,
+    pub playing_for: i32,
+}
+#[derive(Serialize, Deserialize, Insertable, Queryable, Debug, PartialEq, Clone)]
 #[table_name = "turns"]
 pub struct PlayerMoves {
     pub id: i32,
@@ -34,7 +69,7 @@ pub struct PlayerMoves {
     pub merc: bool,
 }
 
-#[derive(Deserialize, Insertable, Queryable, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Insertable, Queryable, Debug, PartialEq, Clone)]
 #[table_name = "stats"]
 pub struct Stats {
     pub turn_id: i32,
@@ -51,6 +86,8 @@ pub struct Stats {
     pub threes: i32,
     pub fours: i32,
     pub fives: i32,
+    /// Whether the team respawned on this roll, having been eliminated
+    pub respawned: bool,
 }
 
 #[derive(Deserialize, Queryable)]
@@ -59,7 +96,7 @@ pub struct Team {
     pub color: String,
 }
 
-#[derive(Deserialize, Insertable, Queryable)]
+#[derive(Deserialize, Insertable, Queryable, Debug, Clone)]
 #[table_name = "territory_ownership"]
 pub struct TerritoryOwners {
     pub id: i32,
@@ -71,7 +108,7 @@ pub struct TerritoryOwners {
     pub mvp: Option<i32>,
 }
 
-#[derive(Deserialize, Insertable, Queryable, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Insertable, Queryable, Debug, PartialEq, Clone)]
 #[table_name = "territory_ownership"]
 pub struct TerritoryOwnersInsert {
     pub territory_id: i32,
@@ -82,7 +119,7 @@ pub struct TerritoryOwnersInsert {
     pub mvp: Option<i32>,
 }
 
-#[derive(Deserialize, Insertable, Queryable, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Insertable, Queryable, Debug, PartialEq, Clone)]
 #[table_name = "territory_stats"]
 pub struct TerritoryStats {
     pub team: i32,
@@ -112,6 +149,148 @@ pub struct TurnInfo {
     pub rollstarttime: Option<NaiveDateTime>,
     pub allornothingenabled: Option<bool>,
     pub map: Option<String>,
+    pub seed_commitment: Option<String>,
+    pub seed: Option<String>,
+    pub playoffs: Option<bool>,
+    pub roll_state: RollState,
+    /// The game the turn belongs to
+    pub game: i32,
+}
+
+/// A row of `territory_adjacency`: `territory_id` borders `adjacent_id` on the turns after
+/// `min_turn` up to and including `max_turn`.
+#[derive(Insertable, Queryable, Debug, PartialEq, Eq, Clone)]
+#[table_name = "territory_adjacency"]
+pub struct Adjacency {
+    pub territory_id: i32,
+    pub adjacent_id: i32,
+    pub note: String,
+    pub min_turn: i32,
+    pub max_turn: i32,
+}
+
+/// Where a turn is in its roll.
+///
+/// A turn is `Pending` while it takes moves. The ringmaster `Locked`s it to stop taking
+/// moves, marks it `Rolling` just before the roll's transaction starts, and marks it
+/// `Complete` inside that transaction. If the roll fails, the transaction is rolled back and
+/// the turn is reopened as `Failed`, from where it can be rolled again.
+#[derive(AsExpression, FromSqlRow, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[sql_type = "Text"]
+#[serde(rename_all = "snake_case")]
+pub enum RollState {
+    Pending,
+    Locked,
+    Rolling,
+    Complete,
+    Failed,
+}
+
+impl RollState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RollState::Pending => "pending",
+            RollState::Locked => "locked",
+            RollState::Rolling => "rolling",
+            RollState::Complete => "complete",
+            RollState::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for RollState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ToSql<Text, Pg> for RollState {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<Text, Pg>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for RollState {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"pending" => Ok(RollState::Pending),
+            b"locked" => Ok(RollState::Locked),
+            b"rolling" => Ok(RollState::Rolling),
+            b"complete" => Ok(RollState::Complete),
+            b"failed" => Ok(RollState::Failed),
+            _ => Err("Unrecognized roll state".into()),
+        }
+    }
+}
+
+/// One team's place in a playoff bracket, for the turn the bracket is played on.
+#[derive(Deserialize, Insertable, Queryable, Debug, PartialEq, Eq, Clone)]
+#[table_name = "playoff_brackets"]
+pub struct PlayoffBracket {
+    pub season: i32,
+    pub turn_id: i32,
+    pub bracket: i32,
+    pub team: i32,
+}
+
+/// A team that won a season, and how it won.
+#[derive(Deserialize, Insertable, Queryable, Debug, PartialEq, Eq, Clone)]
+#[table_name = "season_winners"]
+pub struct SeasonWinner {
+    pub season: i32,
+    /// The day whose roll ended the season
+    pub day: i32,
+    pub team: i32,
+    /// Territories owned by the team once the season ended
+    pub territories: i32,
+    pub reason: String,
+}
+
+/// A user's counters as they were before the roll of `turn_id` changed them.
+#[derive(Insertable, Debug, PartialEq, Eq, Clone)]
+#[table_name = "roll_user_snapshots"]
+pub struct UserSnapshot {
+    pub turn_id: i32,
+    pub user_id: i32,
+    pub turns: Option<i32>,
+    pub game_turns: Option<i32>,
+    pub mvps: Option<i32>,
+    pub streak: Option<i32>,
+    pub overall: Option<i32>,
+    pub playing_for: i32,
+}
+
+/// A roll that was undone, and who undid it.
+#[derive(Insertable, Debug, PartialEq, Eq, Clone)]
+#[table_name = "roll_undos"]
+pub struct RollUndo {
+    pub turn_id: i32,
+    pub season: i32,
+    pub day: i32,
+    pub undone_by: String,
+}
+
+/// An eliminated team put back on the map by a roll.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Respawn {
+    pub team: i32,
+    pub territory: i32,
+    /// The team that held the territory before the team respawned on it
+    pub previous_owner: i32,
+    /// The committed players that earned the team its respawn
+    pub players: i32,
+}
+
+/// Everything a roll would have written, gathered up by a dry run instead.
+#[derive(Serialize, Debug)]
+pub struct RollPreview<'a> {
+    pub turn_id: i32,
+    pub season: i32,
+    pub day: i32,
+    pub owners: &'a [TerritoryOwnersInsert],
+    pub mvps: &'a [PlayerMoves],
+    pub stats: Vec<Stats>,
+    pub territory_stats: &'a [TerritoryStats],
 }
 
 #[derive(Clone)]
@@ -166,7 +345,7 @@ impl PlayerMoves {
     pub fn load(turn_id: &i32, conn: &PgConnection) -> Result<Vec<PlayerMoves>, Error> {
         turns::table
             .filter(turns::turn_id.eq(turn_id))
-            .order_by(turns::territory.desc())
+            .order_by((turns::territory.desc(), turns::id))
             .load::<PlayerMoves>(conn)
     }
 
@@ -185,6 +364,22 @@ impl Stats {
         turn_id: i32,
         conn: &PgConnection,
     ) -> QueryResult<usize> {
+        diesel::insert_into(stats::table)
+            .values(Stats::rank(&stats, turn_id))
+            .execute(conn)
+    }
+
+    pub fn load(turn_id: i32, conn: &PgConnection) -> Result<Vec<Stats>, Error> {
+        stats::table
+            .filter(stats::turn_id.eq(turn_id))
+            .order_by(stats::team)
+            .load::<Stats>(conn)
+    }
+
+    /// Ranks the teams by territory count and fills in their efficiency, as
+    /// they will be stored for `turn_id`.
+    #[must_use]
+    pub fn rank(stats: &BTreeMap<i32, Stats>, turn_id: i32) -> Vec<Stats> {
         // calculate whichever has the highest number of territories and such
         let mut insertable_stats = stats.values().collect::<Vec<_>>();
         insertable_stats.sort_by_key(|a| a.territorycount);
@@ -224,11 +419,10 @@ impl Stats {
                 threes: i.threes,
                 fours: i.fours,
                 fives: i.fives,
+                respawned: i.respawned,
             });
         }
-        diesel::insert_into(stats::table)
-            .values(amended_stats)
-            .execute(conn)
+        amended_stats
     }
 
     #[must_use]
@@ -248,6 +442,7 @@ impl Stats {
             threes: 0,
             fours: 0,
             fives: 0,
+            respawned: false,
         }
     }
 
@@ -299,6 +494,26 @@ impl TerritoryStats {
             .values(stats)
             .execute(conn)
     }
+
+    pub fn load(turn_id: i32, conn: &PgConnection) -> Result<Vec<TerritoryStats>, Error> {
+        territory_stats::table
+            .select((
+                territory_stats::team,
+                territory_stats::turn_id,
+                territory_stats::ones,
+                territory_stats::twos,
+                territory_stats::threes,
+                territory_stats::fours,
+                territory_stats::fives,
+                territory_stats::teampower,
+                territory_stats::chance,
+                territory_stats::territory,
+                territory_stats::territory_power,
+            ))
+            .filter(territory_stats::turn_id.eq(turn_id))
+            .order_by((territory_stats::territory, territory_stats::team))
+            .load::<TerritoryStats>(conn)
+    }
 }
 
 impl Default for TerritoryStats {
@@ -323,13 +538,16 @@ impl TerritoryOwners {
     pub fn load(turn_id: &i32, conn: &PgConnection) -> Result<Vec<TerritoryOwners>, Error> {
         territory_ownership::table
             .filter(territory_ownership::turn_id.eq(turn_id))
+            .order_by(territory_ownership::territory_id)
             .load::<TerritoryOwners>(conn)
     }
 }
 
 impl TerritoryOwnersInsert {
+    /// The owner of `territory` on turn `turn_id`, the turn after the one being rolled.
     pub fn new(
         territory: &TerritoryOwners,
+        turn_id: i32,
         owner: i32,
         random_number: Option<f64>,
         mvp: Option<i32>,
@@ -337,7 +555,7 @@ impl TerritoryOwnersInsert {
         TerritoryOwnersInsert {
             territory_id: territory.territory_id,
             owner_id: owner,
-            turn_id: territory.turn_id + 1,
+            turn_id,
             previous_owner_id: territory.owner_id,
             random_number: random_number.unwrap_or(0_f64),
             mvp,
@@ -350,6 +568,211 @@ impl TerritoryOwnersInsert {
             .values(owners)
             .execute(conn)
     }
+
+    pub fn load(turn_id: i32, conn: &PgConnection) -> Result<Vec<TerritoryOwnersInsert>, Error> {
+        territory_ownership::table
+            .select((
+                territory_ownership::territory_id,
+                territory_ownership::owner_id,
+                territory_ownership::turn_id,
+                territory_ownership::previous_owner_id,
+                territory_ownership::random_number,
+                territory_ownership::mvp,
+            ))
+            .filter(territory_ownership::turn_id.eq(turn_id))
+            .order_by(territory_ownership::territory_id)
+            .load::<TerritoryOwnersInsert>(conn)
+    }
+}
+
+impl UserTurns {
+    /// Counts up every user's turns and MVPs across all games, and whether they played turn
+    /// `turn_id` and its `game`.
+    pub fn load(
+        turn_id: i32,
+        season: i32,
+        game: i32,
+        conn: &PgConnection,
+    ) -> Result<Vec<UserTurns>, Error> {
+        sql_query(
+            "SELECT past_turns.user_id, count(*)::int4 AS turns, \
+            (count(*) FILTER (WHERE turninfo.season = $1))::int4 AS game_turns, \
+            (count(*) FILTER (WHERE past_turns.mvp))::int4 AS mvps, \
+            bool_or(past_turns.turn_id = $2) AS played, \
+            bool_or(turninfo.game = $3) AS in_game \
+            FROM past_turns INNER JOIN turninfo ON turninfo.id = past_turns.turn_id \
+            GROUP BY past_turns.user_id",
+        )
+        .bind::<Integer, _>(season)
+        .bind::<Integer, _>(turn_id)
+        .bind::<Integer, _>(game)
+        .load(conn)
+    }
+}
+
+impl UserRatings {
+    pub fn load(conn: &PgConnection) -> Result<Vec<UserRatings>, Error> {
+        users::table
+            .select((
+                users::id,
+                users::turns,
+                users::game_turns,
+                users::mvps,
+                users::streak,
+                users::overall,
+                users::playing_for,
+            ))
+            .order_by(users::id)
+            .load::<UserRatings>(conn)
+    }
+
+    pub fn update(&self, conn: &PgConnection) -> QueryResult<usize> {
+        update(users::table.filter(users::id.eq(self.id)))
+            .set((
+                users::turns.eq(self.turns),
+                users::game_turns.eq(self.game_turns),
+                users::mvps.eq(self.mvps),
+                users::streak.eq(self.streak),
+                users::overall.eq(self.overall),
+                users::playing_for.eq(self.playing_for),
+            ))
+            .execute(conn)
+    }
+}
+
+impl UserSnapshot {
+    pub fn new(turn_id: i32, user: &UserRatings) -> UserSnapshot {
+        UserSnapshot {
+            turn_id,
+            user_id: user.id,
+            turns: user.turns,
+            game_turns: user.game_turns,
+            mvps: user.mvps,
+            streak: user.streak,
+            overall: user.overall,
+            playing_for: user.playing_for,
+        }
+    }
+
+    pub fn ratings(&self) -> UserRatings {
+        UserRatings {
+            id: self.user_id,
+            turns: self.turns,
+            game_turns: self.game_turns,
+            mvps: self.mvps,
+            streak: self.streak,
+            overall: self.overall,
+            playing_for: self.playing_for,
+        }
+    }
+
+    pub fn insert(&self, conn: &PgConnection) -> QueryResult<usize> {
+        insert_into(roll_user_snapshots::table)
+            .values(self)
+            .execute(conn)
+    }
+
+    /// Loads the counters of the users changed by the roll of `turn_id`, as they were before.
+    pub fn load(turn_id: i32, conn: &PgConnection) -> Result<Vec<UserRatings>, Error> {
+        roll_user_snapshots::table
+            .select((
+                roll_user_snapshots::user_id,
+                roll_user_snapshots::turns,
+                roll_user_snapshots::game_turns,
+                roll_user_snapshots::mvps,
+                roll_user_snapshots::streak,
+                roll_user_snapshots::overall,
+                roll_user_snapshots::playing_for,
+            ))
+            .filter(roll_user_snapshots::turn_id.eq(turn_id))
+            .order_by(roll_user_snapshots::user_id)
+            .load::<UserRatings>(conn)
+    }
+}
+
+impl Respawn {
+    /// Counts each team's committed players: the users, other than alts, who have made it
+    /// their team. Unlike `playing_for`, this sticks with a team once it has been eliminated.
+    pub fn committed_players(conn: &PgConnection) -> QueryResult<BTreeMap<i32, i32>> {
+        let mut players = BTreeMap::new();
+        for team in users::table
+            .filter(users::is_alt.eq(false))
+            .select(users::current_team)
+            .load::<i32>(conn)?
+        {
+            *players.entry(team).or_default() += 1;
+        }
+        Ok(players)
+    }
+}
+
+impl PlayoffBracket {
+    /// Loads the brackets of `season` being played on turn `turn_id`, grouped by bracket: the
+    /// ones most recently drawn up by then.
+    pub fn load(season: i32, turn_id: i32, conn: &PgConnection) -> Result<Vec<Vec<i32>>, Error> {
+        let rows = playoff_brackets::table
+            .select((
+                playoff_brackets::season,
+                playoff_brackets::turn_id,
+                playoff_brackets::bracket,
+                playoff_brackets::team,
+            ))
+            .filter(playoff_brackets::season.eq(season))
+            .filter(playoff_brackets::turn_id.le(turn_id))
+            .order_by((
+                playoff_brackets::turn_id.desc(),
+                playoff_brackets::bracket,
+                playoff_brackets::team,
+            ))
+            .load::<PlayoffBracket>(conn)?;
+        let mut brackets: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+        if let Some(latest) = rows.first().map(|row| row.turn_id) {
+            for row in rows.iter().filter(|row| row.turn_id == latest) {
+                brackets.entry(row.bracket).or_default().push(row.team);
+            }
+        }
+        Ok(brackets.into_values().collect())
+    }
+
+    pub fn insert(
+        brackets: &[Vec<i32>],
+        season: i32,
+        turn_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<usize> {
+        let rows = brackets
+            .iter()
+            .zip(1..)
+            .flat_map(|(teams, bracket)| {
+                teams.iter().map(move |&team| PlayoffBracket {
+                    season,
+                    turn_id,
+                    bracket,
+                    team,
+                })
+            })
+            .collect::<Vec<PlayoffBracket>>();
+        insert_into(playoff_brackets::table)
+            .values(rows)
+            .execute(conn)
+    }
+}
+
+impl SeasonWinner {
+    pub fn insert(winners: &[SeasonWinner], conn: &PgConnection) -> QueryResult<usize> {
+        insert_into(season_winners::table)
+            .values(winners)
+            .execute(conn)
+    }
+}
+
+/// The error for a turn created in `season`, which belongs to game `owner`. Seasons are numbered
+/// across games, so that a season and day name a single turn.
+pub fn season_taken(season: i32, owner: i32) -> Error {
+    Error::DatabaseError(
+        diesel::result::DatabaseErrorKind::ForeignKeyViolation,
+        Box::new(format!("Season {season} belongs to game {owner}")),
+    )
 }
 
 impl TurnInfo {
@@ -362,6 +785,8 @@ impl TurnInfo {
             .set((
                 turninfo::complete.eq(newturninfo.complete),
                 turninfo::active.eq(newturninfo.active),
+                turninfo::finale.eq(newturninfo.finale),
+                turninfo::roll_state.eq(newturninfo.roll_state),
                 turninfo::rollstarttime.eq(newturninfo.rollstarttime),
                 turninfo::rollendtime.eq(newturninfo.rollendtime),
                 turninfo::map.eq(&newturninfo.map),
@@ -370,28 +795,52 @@ impl TurnInfo {
             .execute(conn)
     }
 
+    /// Creates the turn of `game` for `season`/`day`, or reopens it if it exists. Fails if
+    /// another game has claimed `season` (see [`season_taken`]).
     #[allow(clippy::too_many_arguments)]
     pub fn insert_new(
+        game: i32,
         season: i32,
         day: i32,
         active: bool,
         finale: bool,
+        playoffs: bool,
         map: Option<String>,
         allornothingenabled: bool,
         start_time: Option<NaiveDateTime>,
         conn: &PgConnection,
-    ) -> QueryResult<usize> {
+    ) -> QueryResult<i32> {
         //use schema::turninfo::dsl::*;
+        // The seed for the new turn's roll is committed to now, and revealed after the roll.
+        // If the turn already exists we leave its seed alone so a published commitment never changes.
+        let (seed, seed_commitment) = crate::seed::generate();
+        // A season belongs to the game that first created a turn in it
+        diesel::insert_into(seasons::table)
+            .values((seasons::season.eq(season), seasons::game.eq(game)))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        let owner = seasons::table
+            .filter(seasons::season.eq(season))
+            .select(seasons::game)
+            .first::<i32>(conn)?;
+        if owner != game {
+            return Err(season_taken(season, owner));
+        }
         diesel::insert_into(turninfo::table)
             .values((
+                turninfo::game.eq(game),
                 turninfo::season.eq(season),
                 turninfo::day.eq(day),
                 turninfo::complete.eq(&Some(false)),
                 turninfo::active.eq(&Some(active)),
                 turninfo::finale.eq(&Some(finale)),
+                turninfo::playoffs.eq(&Some(playoffs)),
                 turninfo::map.eq(&map),
                 turninfo::rollstarttime.eq(&start_time),
                 turninfo::allornothingenabled.eq(&Some(allornothingenabled)),
+                turninfo::seed_commitment.eq(&Some(seed_commitment)),
+                turninfo::seed.eq(&Some(seed)),
+                turninfo::roll_state.eq(RollState::Pending),
             ))
             .on_conflict((turninfo::season, turninfo::day))
             .do_update()
@@ -399,14 +848,18 @@ impl TurnInfo {
                 turninfo::active.eq(&Some(active)),
                 turninfo::complete.eq(&Some(false)),
                 turninfo::finale.eq(&Some(finale)),
+                turninfo::playoffs.eq(&Some(playoffs)),
                 turninfo::map.eq(&map),
                 turninfo::rollstarttime.eq(&start_time),
                 turninfo::allornothingenabled.eq(&Some(allornothingenabled)),
+                turninfo::roll_state.eq(RollState::Pending),
             ))
-            .execute(conn)
+            .returning(turninfo::id)
+            .get_result(conn)
     }
 
-    pub fn get_latest(conn: &PgConnection) -> Result<TurnInfo, diesel::result::Error> {
+    /// Loads the active turn of `game`.
+    pub fn get_latest(game: i32, conn: &PgConnection) -> Result<TurnInfo, diesel::result::Error> {
         turninfo::table
             .select((
                 turninfo::id,
@@ -420,20 +873,189 @@ impl TurnInfo {
                 turninfo::rollstarttime,
                 turninfo::allornothingenabled,
                 turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
+                turninfo::playoffs,
+                turninfo::roll_state,
+                turninfo::game,
             ))
             .filter(turninfo::active.eq(true))
+            .filter(turninfo::game.eq(game))
+            .order((turninfo::season.desc(), turninfo::day.desc()))
+            .first::<TurnInfo>(conn)
+    }
+
+    /// Loads the turn of `game` to roll next: the active turn, or a turn whose roll stopped
+    /// after it was locked. Locking a turn deactivates it, so a ringmaster that crashed
+    /// mid-roll leaves no active turn behind.
+    pub fn get_rollable(game: i32, conn: &PgConnection) -> Result<TurnInfo, Error> {
+        turninfo::table
+            .select((
+                turninfo::id,
+                turninfo::season,
+                turninfo::day,
+                turninfo::complete,
+                turninfo::active,
+                turninfo::finale,
+                turninfo::chaosweight,
+                turninfo::rollendtime,
+                turninfo::rollstarttime,
+                turninfo::allornothingenabled,
+                turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
+                turninfo::playoffs,
+                turninfo::roll_state,
+                turninfo::game,
+            ))
+            .filter(
+                turninfo::active
+                    .eq(true)
+                    .or(turninfo::roll_state.eq(RollState::Locked))
+                    .or(turninfo::roll_state.eq(RollState::Rolling)),
+            )
+            .filter(turninfo::game.eq(game))
             .order((turninfo::season.desc(), turninfo::day.desc()))
             .first::<TurnInfo>(conn)
     }
 
+    /// Loads the most recently rolled turn of `game`.
+    pub fn get_last_rolled(game: i32, conn: &PgConnection) -> Result<TurnInfo, Error> {
+        turninfo::table
+            .select((
+                turninfo::id,
+                turninfo::season,
+                turninfo::day,
+                turninfo::complete,
+                turninfo::active,
+                turninfo::finale,
+                turninfo::chaosweight,
+                turninfo::rollendtime,
+                turninfo::rollstarttime,
+                turninfo::allornothingenabled,
+                turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
+                turninfo::playoffs,
+                turninfo::roll_state,
+                turninfo::game,
+            ))
+            .filter(turninfo::complete.eq(true))
+            .filter(turninfo::game.eq(game))
+            .order((turninfo::season.desc(), turninfo::day.desc()))
+            .first::<TurnInfo>(conn)
+    }
+
+    /// Loads the turn of `game` for `season`/`day`.
+    pub fn get(game: i32, season: i32, day: i32, conn: &PgConnection) -> Result<TurnInfo, Error> {
+        turninfo::table
+            .select((
+                turninfo::id,
+                turninfo::season,
+                turninfo::day,
+                turninfo::complete,
+                turninfo::active,
+                turninfo::finale,
+                turninfo::chaosweight,
+                turninfo::rollendtime,
+                turninfo::rollstarttime,
+                turninfo::allornothingenabled,
+                turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
+                turninfo::playoffs,
+                turninfo::roll_state,
+                turninfo::game,
+            ))
+            .filter(turninfo::game.eq(game))
+            .filter(turninfo::season.eq(season))
+            .filter(turninfo::day.eq(day))
+            .first::<TurnInfo>(conn)
+    }
+
+    /// Loads the day after this turn in the same game, whatever its id.
+    pub fn get_next(&self, conn: &PgConnection) -> Result<TurnInfo, Error> {
+        turninfo::table
+            .select((
+                turninfo::id,
+                turninfo::season,
+                turninfo::day,
+                turninfo::complete,
+                turninfo::active,
+                turninfo::finale,
+                turninfo::chaosweight,
+                turninfo::rollendtime,
+                turninfo::rollstarttime,
+                turninfo::allornothingenabled,
+                turninfo::map,
+                turninfo::seed_commitment,
+                turninfo::seed,
+                turninfo::playoffs,
+                turninfo::roll_state,
+                turninfo::game,
+            ))
+            .filter(turninfo::game.eq(self.game))
+            .filter(turninfo::season.eq(self.season))
+            .filter(turninfo::day.eq(self.day + 1))
+            .first::<TurnInfo>(conn)
+    }
+
     pub fn start_time_now(&mut self) -> &mut Self {
         self.rollstarttime = Some(Utc::now().naive_utc());
         self
     }
 
+    /// Commits to a seed for a turn that was created without one.
+    pub fn commit_seed(&mut self, conn: &PgConnection) -> Result<String, Error> {
+        let (seed, seed_commitment) = crate::seed::generate();
+        update(turninfo::table.filter(turninfo::id.eq(self.id)))
+            .set((
+                turninfo::seed_commitment.eq(&seed_commitment),
+                turninfo::seed.eq(&seed),
+            ))
+            .execute(conn)?;
+        self.seed_commitment = Some(seed_commitment);
+        self.seed = Some(seed.clone());
+        Ok(seed)
+    }
+
+    /// Reads the turn's roll state straight from the database, as another ringmaster may have
+    /// changed it since this turn was loaded.
+    pub fn roll_state(turn_id: i32, conn: &PgConnection) -> Result<RollState, Error> {
+        turninfo::table
+            .select(turninfo::roll_state)
+            .filter(turninfo::id.eq(turn_id))
+            .first::<RollState>(conn)
+    }
+
+    /// Stops the turn from taking moves ahead of its roll.
     pub fn lock(&mut self, conn: &PgConnection) -> Result<usize, Error> {
+        self.set_roll_state(false, RollState::Locked, conn)
+    }
+
+    /// Marks the turn as being rolled, just before the roll's transaction starts.
+    pub fn begin_roll(&mut self, conn: &PgConnection) -> Result<usize, Error> {
+        self.set_roll_state(false, RollState::Rolling, conn)
+    }
+
+    /// Reopens the turn for moves after its roll failed and was rolled back.
+    pub fn unlock(&mut self, conn: &PgConnection) -> Result<usize, Error> {
+        self.set_roll_state(true, RollState::Failed, conn)
+    }
+
+    fn set_roll_state(
+        &mut self,
+        active: bool,
+        roll_state: RollState,
+        conn: &PgConnection,
+    ) -> Result<usize, Error> {
+        self.active = Some(active);
+        self.roll_state = roll_state;
         update(turninfo::table.filter(turninfo::id.eq(self.id)))
-            .set(turninfo::active.eq(false))
+            .set((
+                turninfo::active.eq(active),
+                turninfo::roll_state.eq(roll_state),
+            ))
             .execute(conn)
     }
 }
@@ -0,0 +1,108 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Undoes the most recent roll of a game: the rows it wrote are deleted, the users it changed
+//! are put back as they were, and the rolled turn is reopened for moves with a new seed.
+//!
+//! Only the latest roll can be undone, and only until someone moves on the day it created.
+//! Chaos bridges that the roll deleted once their history ran out are gone for good.
+
+use crate::store::Store;
+use crate::structs::{RollState, RollUndo, TurnInfo};
+use diesel::result::Error;
+use rocket::figment::Figment;
+
+/// Undoes the last roll of `game` on behalf of `undone_by`, returning the reopened turn.
+pub fn undo(
+    game: i32,
+    undone_by: &str,
+    settings: &Figment,
+    store: &dyn Store,
+) -> Result<TurnInfo, Box<dyn std::error::Error>> {
+    let mut turninfoblock = match store.last_rolled_turn(game) {
+        Ok(turninfoblock) => turninfoblock,
+        Err(Error::NotFound) => return Err("No turn has been rolled yet".into()),
+        Err(e) => return Err(e.into()),
+    };
+    let (season, day) = (turninfoblock.season, turninfoblock.day);
+    let next = match store.next_turn(&turninfoblock) {
+        Ok(next) => Some(next),
+        Err(Error::NotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    // The turn the roll created must still be the one taking moves (or, after a finale, there
+    // must be none), and nobody may have moved on it yet.
+    match store.latest_turn(game) {
+        Ok(active) if next.as_ref().map(|next| next.id) != Some(active.id) => {
+            return Err(format!(
+                "Season {} day {} has started since season {season} day {day} was rolled",
+                active.season, active.day
+            )
+            .into())
+        }
+        Ok(_) | Err(Error::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(next) = &next {
+        if !matches!(next.roll_state, RollState::Pending | RollState::Failed) {
+            return Err(format!("Season {season} day {} is {}", day + 1, next.roll_state).into());
+        }
+        let moves = store.moves(next.id)?.len();
+        if moves > 0 {
+            return Err(format!(
+                "{moves} moves have been made on season {season} day {}",
+                day + 1
+            )
+            .into());
+        }
+    }
+
+    let turn_id = turninfoblock.id;
+    // The new owners and brackets belong to the day the roll created. Rolling the turn again
+    // creates that day afresh, so the roll's rows go along with the turn.
+    if let Some(next) = &next {
+        let owners = store.delete_owners(next.id)?;
+        println!("Territory owners deleted {owners}");
+        store.delete_playoff_brackets(next.id)?;
+    }
+    let stats = store.delete_stats(turn_id)? + store.delete_territory_stats(turn_id)?;
+    println!("Stats deleted {stats}");
+    store.clear_mvps(turn_id)?;
+    store.delete_season_winners(season, day)?;
+    #[cfg(feature = "chaos")]
+    {
+        let bridges = store.delete_opened_adjacency(crate::CHAOS_NOTE, turn_id)?;
+        println!("Chaos bridges deleted {bridges}");
+    }
+    // Users the roll didn't change have no snapshot, and are already as they were
+    let users = store.user_snapshots(turn_id)?;
+    for user in &users {
+        store.update_user(user)?;
+    }
+    store.delete_user_snapshots(turn_id)?;
+    println!("Users restored {}", users.len());
+    if let Some(next) = &next {
+        store.delete_turn(next.id)?;
+    }
+
+    // The turn rolls again when the day it created would have been rolled
+    turninfoblock.rollstarttime = next
+        .and_then(|next| next.rollstarttime)
+        .or_else(|| crate::next_roll(settings));
+    turninfoblock.rollendtime = None;
+    turninfoblock.complete = Some(false);
+    turninfoblock.active = Some(true);
+    turninfoblock.finale = Some(false);
+    turninfoblock.roll_state = RollState::Pending;
+    store.update_turn(&turninfoblock)?;
+    // The roll revealed the old seed, so moves made now could be planned around it
+    store.commit_seed(&mut turninfoblock)?;
+    store.insert_undo(&RollUndo {
+        turn_id,
+        season,
+        day,
+        undone_by: undone_by.to_string(),
+    })?;
+    Ok(turninfoblock)
+}